@@ -5,13 +5,13 @@ use anyhow::*;
 use futures_lite::FutureExt;
 
 use crate::account_transform::{
-    field_balance, field_public_key, field_stake, run_action, AccountTransform,
+    field_balance, field_public_key, field_stake, run_action, vote_prefix, AccountTransform,
 };
 use crate::blockdata::{
     AccountInit, Action, DataNode, MainBlock, QuorumNodeBody, QuorumNodeStats, RadixChildren,
     RadixHashNode,
 };
-use crate::crypto::{hash, Hash, HashCode};
+use crate::crypto::{hash, path_to_hash_code, Hash, HashCode};
 use crate::hashlookup::{HashLookup, HashPut};
 use crate::hex_path::{bytes_to_path, is_prefix, u4, HexPath};
 use crate::queries::{longest_prefix_length, lookup_account};
@@ -149,6 +149,124 @@ pub async fn insert_into_data_tree<'a, HL: HashLookup + HashPut>(
     insert_into_rh_tree(hl, node_count, path, replace, hash_tree).await
 }
 
+/// The result of deleting from a radix hash subtree; see `delete_from_rh_subtree`.
+type DeletedSubtree<N> = Option<(HexPath, Hash<N>)>;
+
+/// Deletes the value of the node at a given path in the subtree whose top is
+/// `hash_tree`.  Returns `None` if the subtree becomes empty, and otherwise
+/// the new top of the subtree along with the hex digits to append to the
+/// parent's suffix for it, which are non-empty if the old top node no longer
+/// has a value and was merged into its only child.  The top node of the
+/// whole tree (`is_top`) is never removed or merged.
+fn delete_from_rh_subtree<
+    'a,
+    HL: HashLookup + HashPut,
+    N: 'a + RadixHashNode,
+    CV: 'a + Send + FnOnce(N) -> Result<N, anyhow::Error>,
+>(
+    hl: &'a mut HL,
+    node_count: &'a mut usize,
+    path: &'a [u4],
+    clear_value: CV,
+    hash_tree: Hash<N>,
+    is_top: bool,
+) -> Pin<Box<dyn Future<Output = Result<DeletedSubtree<N>, anyhow::Error>> + Send + 'a>> {
+    async move {
+        let unchanged = Ok(Some((HexPath(Vec::new()), hash_tree)));
+        let tree = hl.lookup(hash_tree).await?;
+        let tree = if path.is_empty() {
+            if !tree.has_value() {
+                return unchanged;
+            }
+            clear_value(tree)?
+        } else {
+            let (suffix, child_hash) = match tree.get_children().0[path[0].0 as usize].clone() {
+                Some((suffix, child_hash)) if is_prefix(&suffix[..], &path[1..]) => {
+                    (suffix, child_hash)
+                }
+                // there is no value at the path
+                _ => return unchanged,
+            };
+            let new_child = delete_from_rh_subtree(
+                hl,
+                node_count,
+                &path[1 + suffix.len()..],
+                clear_value,
+                child_hash,
+                false,
+            )
+            .await?;
+            if new_child == Some((HexPath(Vec::new()), child_hash)) {
+                return unchanged;
+            }
+            let mut children = tree.get_children().to_owned();
+            children.0[path[0].0 as usize] = new_child
+                .map(|(extra, new_child)| (HexPath([&suffix[..], &extra[..]].concat()), new_child));
+            tree.replace_children(hl, children).await?
+        };
+        if !is_top && !tree.has_value() {
+            let mut entries = tree
+                .get_children()
+                .0
+                .iter()
+                .enumerate()
+                .filter_map(|(digit, entry)| entry.as_ref().map(|entry| (digit, entry)));
+            match (entries.next(), entries.next()) {
+                (None, _) => return Ok(None),
+                (Some((digit, (suffix, child))), None) => {
+                    let extra = [&[u4(digit as u8)], &suffix[..]].concat();
+                    return Ok(Some((HexPath(extra), *child)));
+                }
+                _ => {}
+            }
+        }
+        *node_count += 1;
+        Ok(Some((HexPath(Vec::new()), hl.put(&tree).await?)))
+    }
+    .boxed()
+}
+
+/// Deletes the value of the node at a given path in a radix hash tree,
+/// removing nodes left without a value or children and merging nodes left
+/// without a value into their only child.  Does nothing if there is no value
+/// at the path.
+/// `node_count` is incremented by the number of nodes created.
+/// `clear_value` is called with the node at the path, getting the node
+/// without its value.
+/// `hash_tree` is the top of the initial tree.
+/// Returns the top of the new tree.
+pub async fn delete_from_rh_tree<
+    'a,
+    HL: HashLookup + HashPut,
+    N: 'a + RadixHashNode,
+    CV: 'a + Send + FnOnce(N) -> Result<N, anyhow::Error>,
+>(
+    hl: &'a mut HL,
+    node_count: &'a mut usize,
+    path: &'a [u4],
+    clear_value: CV,
+    hash_tree: Hash<N>,
+) -> Result<Hash<N>, anyhow::Error> {
+    match delete_from_rh_subtree(hl, node_count, path, clear_value, hash_tree, true).await? {
+        Some((_, new_tree)) => Ok(new_tree),
+        None => bail!("top of radix hash tree was removed"),
+    }
+}
+
+/// Deletes the field at a given path in a data tree.
+pub async fn delete_from_data_tree<'a, HL: HashLookup + HashPut>(
+    hl: &'a mut HL,
+    node_count: &'a mut usize,
+    path: &'a [u4],
+    hash_tree: Hash<DataNode>,
+) -> Result<Hash<DataNode>, anyhow::Error> {
+    let clear = |mut n: DataNode| {
+        n.field = None;
+        Ok(n)
+    };
+    delete_from_rh_tree(hl, node_count, path, clear, hash_tree).await
+}
+
 /// Initializes an account node.  The resulting node is only valid in the genesis
 /// block.
 pub async fn initialize_account_node<HL: HashLookup + HashPut>(
@@ -193,6 +311,7 @@ pub async fn initialize_account_node<HL: HashLookup + HashPut>(
             gas: 0,
             stake: init.stake,
             prize: 0,
+            votes: BTreeMap::new(),
         },
     };
     Ok((fields, node))
@@ -223,12 +342,43 @@ pub async fn add_action_to_account<HL: HashLookup + HashPut>(
                 .ok_or_else(|| anyhow!("account has no data tree"))?,
         ),
     };
-    let mut at = AccountTransform::new(hl, is_init, account, hash(&last_main));
+    let gas_limit = hl.lookup(last_main.block.body.options).await?.gas_limit;
+    let mut at = AccountTransform::new(hl, is_init, account, hash(&last_main), gas_limit);
     run_action(&mut at, action).await?;
-    let new_stake = at.get_data_field_or_error(account, &field_stake()).await?;
+    let new_stake = at
+        .peek_data_field(account, &field_stake())
+        .await?
+        .ok_or_else(|| anyhow!("account has no stake"))?;
+    let gas = at.gas.used;
+    // the account's votes carry over, except for those the action changed
+    let mut votes = match lookup_account(hl, &last_main.block.body, account).await? {
+        None => BTreeMap::new(),
+        Some(prev_node) => prev_node.body.stats.votes,
+    };
+    let vote_prefix = vote_prefix();
+    for (path, value) in &at.fields_set {
+        if !is_prefix(&vote_prefix[..], &path[..]) {
+            continue;
+        }
+        let proposal = path_to_hash_code(HexPath(path[vote_prefix.len()..].to_vec()));
+        votes.retain(|(_, voted), _| *voted != proposal);
+        if let Some(value) = value {
+            let proposer: HashCode = rmp_serde::from_read(value.as_slice())?;
+            votes.insert((proposer, proposal), 0);
+        }
+    }
+    // every vote is weighted by the account's current stake
+    for stake in votes.values_mut() {
+        *stake = new_stake;
+    }
     let mut node_count = 0;
     for (path, value) in at.fields_set {
-        data_tree = insert_into_data_tree(hl, &mut node_count, &path[..], value, data_tree).await?;
+        data_tree = match value {
+            Some(value) => {
+                insert_into_data_tree(hl, &mut node_count, &path[..], value, data_tree).await?
+            }
+            None => delete_from_data_tree(hl, &mut node_count, &path[..], data_tree).await?,
+        };
     }
     Ok(QuorumNodeBody {
         last_main: Some(hash(last_main)),
@@ -240,9 +390,10 @@ pub async fn add_action_to_account<HL: HashLookup + HashPut>(
         stats: QuorumNodeStats {
             new_nodes: (node_count as u64) + 1, // node_count data nodes + 1 quorum node
             fee: action.fee,
-            gas: 0,
+            gas,
             stake: new_stake,
             prize,
+            votes,
         },
     })
 }
@@ -1,16 +1,24 @@
 //! Functionality for modifying accounts according to actions.
-use std::{collections::BTreeMap, marker::PhantomData};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    marker::PhantomData,
+};
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use async_trait::*;
 use ed25519_dalek::Signer;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::blockdata::{Action, MainBlock, SendInfo};
+use crate::blockdata::{
+    Action, InitializeSpec, MainBlock, MainBlockBody, MainOptions, ProposalInfo, SendInfo,
+    SignerSet, SlashEvidence, SlashSlot, UnbondingInfo,
+};
 use crate::crypto::{hash, sign, verify_sig, Hash, HashCode, Signature};
 use crate::hashlookup::HashLookup;
 use crate::hex_path::{bytes_to_path, HexPath};
 use crate::queries::{lookup_account, lookup_data_in_account};
+use crate::verification::{find_offender, verify_options, verify_slash_evidence};
+use crate::vm::{run_code, storage_path, Instr};
 
 /// An typed account data field.
 #[derive(Serialize, Deserialize, Debug)]
@@ -55,6 +63,43 @@ pub fn field_public_key() -> TypedDataField<ed25519_dalek::PublicKey> {
     TypedDataField::from_path(bytes_to_path(b"public_key"))
 }
 
+/// Gets the public key of an account as of a given main block, or `None` if
+/// the account does not exist.  This may differ from the key the account
+/// was created with if the key was rotated.
+pub async fn account_public_key<HL: HashLookup>(
+    hl: &HL,
+    main: &MainBlockBody,
+    acct: HashCode,
+) -> Result<Option<ed25519_dalek::PublicKey>, anyhow::Error> {
+    let acct_node = match lookup_account(hl, main, acct).await? {
+        None => return Ok(None),
+        Some(node) => node,
+    };
+    match lookup_data_in_account(hl, &acct_node, &field_public_key().path).await? {
+        None => Ok(None),
+        Some(bs) => Ok(Some(rmp_serde::from_read(bs.as_slice())?)),
+    }
+}
+
+/// Maps the hash codes of the public keys some accounts have as of a given
+/// main block to the accounts.  Consensus signatures are attributed to
+/// accounts through this map rather than by hashing the signing key, so that
+/// after a key rotation only the new key signs for the account.  Accounts
+/// that don't exist are left out.
+pub async fn accounts_by_key<HL: HashLookup>(
+    hl: &HL,
+    main: &MainBlockBody,
+    accounts: &[HashCode],
+) -> Result<BTreeMap<HashCode, HashCode>, anyhow::Error> {
+    let mut by_key = BTreeMap::new();
+    for acct in accounts.iter().collect::<BTreeSet<_>>() {
+        if let Some(key) = account_public_key(hl, main, *acct).await? {
+            by_key.insert(hash(&key).code, *acct);
+        }
+    }
+    Ok(by_key)
+}
+
 /// Field for a `SendInfo` stored in the sender's data.
 pub fn field_send(send: Hash<SendInfo>) -> TypedDataField<SendInfo> {
     let mut path = bytes_to_path(b"send");
@@ -70,6 +115,116 @@ pub fn field_received(send: Hash<SendInfo>) -> TypedDataField<bool> {
     TypedDataField::from_path(path)
 }
 
+/// The field storing the keys authorized to sign for a multi-signature
+/// account.  If present, it is used instead of `field_public_key`.
+pub fn field_signers() -> TypedDataField<SignerSet> {
+    TypedDataField::from_path(bytes_to_path(b"signers"))
+}
+
+/// The field storing whether an account has been slashed for an offense in
+/// a given slot.
+pub fn field_slashed(slot: Hash<SlashSlot>) -> TypedDataField<bool> {
+    let mut path = bytes_to_path(b"slashed");
+    path.0.extend(&bytes_to_path(&slot.code).0);
+    TypedDataField::from_path(path)
+}
+
+/// Field for a `ProposalInfo` stored in the proposer's data.
+pub fn field_proposal(proposal: Hash<ProposalInfo>) -> TypedDataField<ProposalInfo> {
+    let mut path = bytes_to_path(b"proposal");
+    path.0.extend(&bytes_to_path(&proposal.code).0);
+    TypedDataField::from_path(path)
+}
+
+/// The prefix of the paths of `field_vote`s.
+pub(crate) fn vote_prefix() -> HexPath {
+    bytes_to_path(b"vote")
+}
+
+/// Field for a vote for a `ProposalInfo` in the voter's data, storing the
+/// proposer.
+pub fn field_vote(proposal: Hash<ProposalInfo>) -> TypedDataField<HashCode> {
+    let mut path = vote_prefix();
+    path.0.extend(&bytes_to_path(&proposal.code).0);
+    TypedDataField::from_path(path)
+}
+
+/// The field storing the nonce the account's next signed action must have.
+/// It is absent until the account's first signed action, meaning 0.
+pub fn field_nonce() -> TypedDataField<u64> {
+    TypedDataField::from_path(bytes_to_path(b"nonce"))
+}
+
+/// Gets the nonce the next signed action of an account must have as of a
+/// given main block.
+pub async fn next_nonce<HL: HashLookup>(
+    hl: &HL,
+    main: &MainBlockBody,
+    acct: HashCode,
+) -> Result<u64, anyhow::Error> {
+    let acct_node = match lookup_account(hl, main, acct).await? {
+        None => return Ok(0),
+        Some(node) => node,
+    };
+    match lookup_data_in_account(hl, &acct_node, &field_nonce().path).await? {
+        None => Ok(0),
+        Some(bs) => Ok(rmp_serde::from_read(bs.as_slice())?),
+    }
+}
+
+/// The field storing the code run for commands that are not built in.
+pub fn field_code() -> TypedDataField<Vec<Instr>> {
+    TypedDataField::from_path(bytes_to_path(b"code"))
+}
+
+/// The field storing unstaked funds waiting to be withdrawn.
+pub fn field_unbonding(unbonding: Hash<UnbondingInfo>) -> TypedDataField<UnbondingInfo> {
+    let mut path = bytes_to_path(b"unbonding");
+    path.0.extend(&bytes_to_path(&unbonding.code).0);
+    TypedDataField::from_path(path)
+}
+
+/// Gas charged for reading a data field.
+pub const GAS_FIELD_READ: u128 = 10;
+
+/// Gas charged for writing a data field, in addition to `GAS_PER_FIELD_BYTE`
+/// for each byte of the value.
+pub const GAS_FIELD_WRITE: u128 = 20;
+
+/// Gas charged per byte of a written data field value.
+pub const GAS_PER_FIELD_BYTE: u128 = 1;
+
+/// Gas charged for verifying a signature.
+pub const GAS_SIGNATURE: u128 = 100;
+
+/// Gas charged per byte of action arguments.
+pub const GAS_PER_ARG_BYTE: u128 = 1;
+
+/// Tracks the gas consumed while running an action.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct GasMeter {
+    /// The maximum gas that may be consumed.
+    pub limit: u128,
+    /// The gas consumed so far.
+    pub used: u128,
+}
+
+impl GasMeter {
+    /// Creates a `GasMeter` with no gas consumed.
+    pub fn new(limit: u128) -> GasMeter {
+        GasMeter { limit, used: 0 }
+    }
+
+    /// Consumes gas, failing if this exceeds the limit.
+    pub fn charge(&mut self, gas: u128) -> Result<(), anyhow::Error> {
+        self.used = self.used.saturating_add(gas);
+        if self.used > self.limit {
+            bail!("out of gas: used {} of limit {}", self.used, self.limit);
+        }
+        Ok(())
+    }
+}
+
 /// A context providing operations related to transforming an account (e.g.
 /// running actions).
 pub struct AccountTransform<'a, HL: HashLookup> {
@@ -81,8 +236,11 @@ pub struct AccountTransform<'a, HL: HashLookup> {
     pub this_account: HashCode,
     /// The hash code of the last main block.
     pub last_main: Hash<MainBlock>,
-    /// Which fields have been overwritten so far, and their most recent values.
-    pub fields_set: BTreeMap<HexPath, Vec<u8>>,
+    /// Which fields have been overwritten so far, and their most recent
+    /// values (`None` if the field was deleted).
+    pub fields_set: BTreeMap<HexPath, Option<Vec<u8>>>,
+    /// The gas consumed so far.
+    pub gas: GasMeter,
 }
 
 #[async_trait]
@@ -90,6 +248,10 @@ impl<'a, HL: HashLookup> HashLookup for AccountTransform<'a, HL> {
     async fn lookup_bytes(&self, hash: HashCode) -> Result<Vec<u8>, anyhow::Error> {
         self.hl.lookup_bytes(hash).await
     }
+
+    async fn lookup_bytes_many(&self, hashes: &[HashCode]) -> Result<Vec<Vec<u8>>, anyhow::Error> {
+        self.hl.lookup_bytes_many(hashes).await
+    }
 }
 
 impl<'a, HL: HashLookup> AccountTransform<'a, HL> {
@@ -99,6 +261,7 @@ impl<'a, HL: HashLookup> AccountTransform<'a, HL> {
         is_initializing: bool,
         this_account: HashCode,
         last_main: Hash<MainBlock>,
+        gas_limit: u128,
     ) -> AccountTransform<'a, HL> {
         AccountTransform {
             hl,
@@ -106,11 +269,22 @@ impl<'a, HL: HashLookup> AccountTransform<'a, HL> {
             this_account,
             last_main,
             fields_set: BTreeMap::new(),
+            gas: GasMeter::new(gas_limit),
         }
     }
 
-    /// Gets the value of a given data field.
-    async fn get_data_field_bytes(
+    /// Gets the value of a given data field, charging gas for the read.
+    pub(crate) async fn get_data_field_bytes(
+        &mut self,
+        acct: HashCode,
+        field_name: &HexPath,
+    ) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        self.gas.charge(GAS_FIELD_READ)?;
+        self.peek_data_field_bytes(acct, field_name).await
+    }
+
+    /// Gets the value of a given data field without charging gas.
+    async fn peek_data_field_bytes(
         &self,
         acct: HashCode,
         field_name: &HexPath,
@@ -118,32 +292,47 @@ impl<'a, HL: HashLookup> AccountTransform<'a, HL> {
         if acct == self.this_account {
             match self.fields_set.get(field_name) {
                 Some(x) => {
-                    return Ok(Some(x.clone()));
+                    return Ok(x.clone());
                 }
                 None => {}
             }
         }
         let main = self.lookup(self.last_main).await?;
-        if let Some(acct_node) = lookup_account(self, &main.block.body, self.this_account).await? {
+        if let Some(acct_node) = lookup_account(self, &main.block.body, acct).await? {
             lookup_data_in_account(self, &acct_node, field_name).await
         } else {
             Ok(None)
         }
     }
 
+    /// Gets the version of the main block that the transformed account will
+    /// be part of, i.e. the version after the last main block.
+    pub async fn next_version(&self) -> Result<u64, anyhow::Error> {
+        Ok(self.lookup(self.last_main).await?.block.body.version + 1)
+    }
+
     /// Sets the value of a given data field.
-    fn set_data_field_bytes(
+    pub(crate) fn set_data_field_bytes(
         &mut self,
         field_name: &HexPath,
         value: Vec<u8>,
     ) -> Result<(), anyhow::Error> {
-        self.fields_set.insert(field_name.clone(), value);
+        self.gas
+            .charge(GAS_FIELD_WRITE + GAS_PER_FIELD_BYTE * value.len() as u128)?;
+        self.fields_set.insert(field_name.clone(), Some(value));
+        Ok(())
+    }
+
+    /// Deletes a given data field, if it exists.
+    pub fn delete_data_field<T>(&mut self, field: &TypedDataField<T>) -> Result<(), anyhow::Error> {
+        self.gas.charge(GAS_FIELD_WRITE)?;
+        self.fields_set.insert(field.path.clone(), None);
         Ok(())
     }
 
     /// Gets the value of a given typed data field.
     async fn get_data_field<T: DeserializeOwned>(
-        &self,
+        &mut self,
         acct: HashCode,
         field: &TypedDataField<T>,
     ) -> Result<Option<T>, anyhow::Error> {
@@ -153,9 +342,22 @@ impl<'a, HL: HashLookup> AccountTransform<'a, HL> {
         }
     }
 
+    /// Gets the value of a given typed data field without charging gas.  This
+    /// is for inspecting the result of an action after it has run.
+    pub async fn peek_data_field<T: DeserializeOwned>(
+        &self,
+        acct: HashCode,
+        field: &TypedDataField<T>,
+    ) -> Result<Option<T>, anyhow::Error> {
+        match self.peek_data_field_bytes(acct, &field.path).await? {
+            None => Ok(None),
+            Some(bs) => Ok(Some(rmp_serde::from_read(bs.as_slice())?)),
+        }
+    }
+
     /// Gets the value of a given typed data field, throwing an error if it is not found.
     pub async fn get_data_field_or_error<T: DeserializeOwned>(
-        &self,
+        &mut self,
         acct: HashCode,
         field: &TypedDataField<T>,
     ) -> Result<T, anyhow::Error> {
@@ -175,8 +377,31 @@ impl<'a, HL: HashLookup> AccountTransform<'a, HL> {
     }
 }
 
+/// Checks that an action has the current account's next nonce and advances
+/// the nonce, so the action can't be run again.
+pub(crate) async fn use_nonce<'a, HL: HashLookup>(
+    at: &mut AccountTransform<'a, HL>,
+    action: &Action,
+) -> Result<(), anyhow::Error> {
+    let nonce = at
+        .get_data_field(at.this_account, &field_nonce())
+        .await?
+        .unwrap_or(0);
+    if action.nonce != nonce {
+        bail!(
+            "action nonce {} must equal account nonce {}",
+            action.nonce,
+            nonce
+        );
+    }
+    let next = nonce
+        .checked_add(1)
+        .ok_or_else(|| anyhow!("nonce overflow"))?;
+    at.set_data_field(&field_nonce(), &next)
+}
+
 /// Causes the current account to pay a fee.
-async fn pay_fee<'a, HL: HashLookup>(
+pub(crate) async fn pay_fee<'a, HL: HashLookup>(
     at: &mut AccountTransform<'a, HL>,
     fee: u128,
 ) -> Result<(), anyhow::Error> {
@@ -190,7 +415,7 @@ async fn pay_fee<'a, HL: HashLookup>(
 }
 
 /// Causes the current account to send.
-async fn do_send<'a, HL: HashLookup>(
+pub(crate) async fn do_send<'a, HL: HashLookup>(
     at: &mut AccountTransform<'a, HL>,
     send: &SendInfo,
 ) -> Result<(), anyhow::Error> {
@@ -247,6 +472,272 @@ async fn do_receive<'a, HL: HashLookup>(
     Ok(send)
 }
 
+/// Sets up a new account according to the `InitializeSpec` committed to by
+/// the send creating it.
+fn do_initialize<'a, HL: HashLookup>(
+    at: &mut AccountTransform<'a, HL>,
+    send: &SendInfo,
+    spec: Option<Vec<u8>>,
+) -> Result<(), anyhow::Error> {
+    let spec: InitializeSpec = match (send.initialize_spec, spec) {
+        (None, None) => return Ok(()),
+        (None, Some(_)) => bail!("send has no initialize spec"),
+        (Some(_), None) => bail!("missing initialize spec committed by send"),
+        (Some(committed), Some(bs)) => {
+            if hash(&bs) != committed {
+                bail!("initialize spec does not match send");
+            }
+            rmp_serde::from_read(bs.as_slice())?
+        }
+    };
+    if let Some(signers) = &spec.signers {
+        verify_signer_set(signers)?;
+        at.set_data_field(&field_signers(), signers)?;
+    }
+    if let Some(code) = &spec.code {
+        at.set_data_field(&field_code(), code)?;
+    }
+    for (key, value) in spec.storage {
+        at.set_data_field_bytes(&storage_path(&key), value)?;
+    }
+    Ok(())
+}
+
+/// Causes the current account to move money from its balance to its stake.
+async fn do_stake<'a, HL: HashLookup>(
+    at: &mut AccountTransform<'a, HL>,
+    amount: u128,
+) -> Result<(), anyhow::Error> {
+    let bal = at
+        .get_data_field_or_error(at.this_account, &field_balance())
+        .await?;
+    if bal < amount {
+        bail!("not enough balance for stake");
+    }
+    let stake = at
+        .get_data_field_or_error(at.this_account, &field_stake())
+        .await?;
+    at.set_data_field(&field_balance(), &(bal - amount))?;
+    at.set_data_field(&field_stake(), &(stake + amount))?;
+    Ok(())
+}
+
+/// Causes the current account to unstake money, which may be withdrawn
+/// after the unbonding period.
+async fn do_unstake<'a, HL: HashLookup>(
+    at: &mut AccountTransform<'a, HL>,
+    amount: u128,
+    nonce: u64,
+) -> Result<UnbondingInfo, anyhow::Error> {
+    let stake = at
+        .get_data_field_or_error(at.this_account, &field_stake())
+        .await?;
+    if stake < amount {
+        bail!("not enough stake for unstake");
+    }
+    let main = at.lookup(at.last_main).await?;
+    let opts = at.lookup(main.block.body.options).await?;
+    let unbonding = UnbondingInfo {
+        last_main: at.last_main,
+        amount,
+        unlock_version: main.block.body.version + 1 + opts.unbonding_period,
+        nonce,
+    };
+    let unbonding_df = field_unbonding(hash(&unbonding));
+    if at
+        .get_data_field(at.this_account, &unbonding_df)
+        .await?
+        .is_some()
+    {
+        bail!("that was already unstaked");
+    }
+    at.set_data_field(&field_stake(), &(stake - amount))?;
+    at.set_data_field(&unbonding_df, &unbonding)?;
+    Ok(unbonding)
+}
+
+/// Causes the current account to withdraw unstaked money into its balance.
+async fn do_withdraw<'a, HL: HashLookup>(
+    at: &mut AccountTransform<'a, HL>,
+    unbonding_hash: Hash<UnbondingInfo>,
+) -> Result<(), anyhow::Error> {
+    let unbonding = at
+        .get_data_field_or_error(at.this_account, &field_unbonding(unbonding_hash))
+        .await?;
+    if hash(&unbonding) != unbonding_hash {
+        bail!("unbonding hashes don't match");
+    }
+    if at.next_version().await? < unbonding.unlock_version {
+        bail!("unstaked funds are still unbonding");
+    }
+    let bal = at
+        .get_data_field_or_error(at.this_account, &field_balance())
+        .await?;
+    at.set_data_field(&field_balance(), &(bal + unbonding.amount))?;
+    // deleting the record also prevents withdrawing it again
+    at.delete_data_field(&field_unbonding(unbonding_hash))?;
+    Ok(())
+}
+
+/// Deletes the record of a send by the current account once the recipient
+/// has received it as of the last main block.
+async fn do_prune_send<'a, HL: HashLookup>(
+    at: &mut AccountTransform<'a, HL>,
+    send_hash: Hash<SendInfo>,
+) -> Result<(), anyhow::Error> {
+    let send_df = field_send(send_hash);
+    let send = at
+        .get_data_field_or_error(at.this_account, &send_df)
+        .await?;
+    if hash(&send) != send_hash {
+        bail!("send hashes don't match");
+    }
+    if at
+        .get_data_field(send.recipient, &field_received(send_hash))
+        .await?
+        != Some(true)
+    {
+        bail!("send has not been received yet");
+    }
+    at.delete_data_field(&send_df)
+}
+
+/// Gets a percentage of an amount, rounded down, without overflowing.
+pub(crate) fn percent_of(amount: u128, percent: u32) -> u128 {
+    let percent = u128::from(percent);
+    amount / 100 * percent + amount % 100 * percent / 100
+}
+
+/// Slashes the stake of the current account for an offense.  Part of the
+/// slashed amount is sent to the reporter, and the action's fee is paid out
+/// of the rest.
+async fn do_slash<'a, HL: HashLookup>(
+    at: &mut AccountTransform<'a, HL>,
+    evidence: &SlashEvidence,
+    reporter: HashCode,
+    fee: u128,
+) -> Result<(), anyhow::Error> {
+    at.gas.charge(2 * GAS_SIGNATURE)?;
+    let (key, slot) = verify_slash_evidence(evidence)?;
+    if find_offender(at.hl, evidence, &key).await? != at.this_account {
+        bail!("evidence is not against this account");
+    }
+    let slashed_field = field_slashed(hash(&slot));
+    if at.get_data_field(at.this_account, &slashed_field).await? == Some(true) {
+        bail!("account was already slashed for this slot");
+    }
+    let main = at.lookup(at.last_main).await?;
+    let opts = at.lookup(main.block.body.options).await?;
+    if opts.slash_percent > 100 || opts.slash_reporter_percent > 100 {
+        bail!("slash percentages must be at most 100");
+    }
+    let stake = at
+        .get_data_field_or_error(at.this_account, &field_stake())
+        .await?;
+    let penalty = percent_of(stake, opts.slash_percent);
+    let reward = percent_of(penalty, opts.slash_reporter_percent);
+    if fee > penalty - reward {
+        bail!("slash fee exceeds the burned part of the penalty");
+    }
+    at.set_data_field(&field_stake(), &(stake - penalty))?;
+    at.set_data_field(&slashed_field, &true)?;
+    if reward > 0 {
+        // the reporter receives the reward like any other send
+        let send = SendInfo {
+            last_main: at.last_main,
+            sender: at.this_account,
+            recipient: reporter,
+            send_amount: reward,
+            initialize_spec: None,
+            message: b"slash reward".to_vec(),
+        };
+        at.set_data_field(&field_send(hash(&send)), &send)?;
+    }
+    Ok(())
+}
+
+/// Records a proposal by the current account to change the options.
+async fn do_propose_options<'a, HL: HashLookup>(
+    at: &mut AccountTransform<'a, HL>,
+    proposal: &ProposalInfo,
+) -> Result<(), anyhow::Error> {
+    if proposal.proposer != at.this_account {
+        bail!("proposal must be made by this account");
+    }
+    if proposal.last_main != at.last_main {
+        bail!("last main of proposal must be the current last main");
+    }
+    verify_options(&proposal.options)?;
+    let main = at.lookup(at.last_main).await?;
+    let period = u64::from(at.lookup(main.block.body.options).await?.quorum_period);
+    // the votes are tallied one period before activation, which must not be
+    // before this proposal is included
+    if proposal.activation_version.checked_rem(period) != Some(0)
+        || proposal.activation_version < main.block.body.version + 1 + period
+    {
+        bail!("activation version must be a multiple of quorum_period after the next period");
+    }
+    at.set_data_field(&field_proposal(hash(proposal)), proposal)
+}
+
+/// Records a vote by the current account for a proposal.  The vote is
+/// weighted by the voter's stake when the votes are tallied.
+async fn do_vote<'a, HL: HashLookup>(
+    at: &mut AccountTransform<'a, HL>,
+    proposer: HashCode,
+    proposal_hash: Hash<ProposalInfo>,
+) -> Result<(), anyhow::Error> {
+    let proposal = at
+        .get_data_field_or_error(proposer, &field_proposal(proposal_hash))
+        .await?;
+    if hash(&proposal) != proposal_hash {
+        bail!("proposal hashes don't match");
+    }
+    let main = at.lookup(at.last_main).await?;
+    let period = u64::from(at.lookup(main.block.body.options).await?.quorum_period);
+    // the votes are tallied one period before activation
+    match proposal.activation_version.checked_sub(period) {
+        Some(tally_version) if main.block.body.version < tally_version => {}
+        _ => bail!("voting on the proposal has closed"),
+    }
+    at.set_data_field(&field_vote(proposal_hash), &proposer)
+}
+
+/// Deletes a proposal made by the current account once its activation
+/// version has passed.
+async fn do_prune_proposal<'a, HL: HashLookup>(
+    at: &mut AccountTransform<'a, HL>,
+    proposal_hash: Hash<ProposalInfo>,
+) -> Result<(), anyhow::Error> {
+    let field = field_proposal(proposal_hash);
+    let proposal = at.get_data_field_or_error(at.this_account, &field).await?;
+    let main = at.lookup(at.last_main).await?;
+    if main.block.body.version < proposal.activation_version {
+        bail!("proposal has not reached its activation version yet");
+    }
+    at.delete_data_field(&field)
+}
+
+/// Deletes a vote by the current account once the proposal's activation
+/// version has passed, or once the proposer has pruned the proposal.
+async fn do_prune_vote<'a, HL: HashLookup>(
+    at: &mut AccountTransform<'a, HL>,
+    proposal_hash: Hash<ProposalInfo>,
+) -> Result<(), anyhow::Error> {
+    let field = field_vote(proposal_hash);
+    let proposer = at.get_data_field_or_error(at.this_account, &field).await?;
+    let main = at.lookup(at.last_main).await?;
+    if let Some(proposal) = at
+        .get_data_field(proposer, &field_proposal(proposal_hash))
+        .await?
+    {
+        if main.block.body.version < proposal.activation_version {
+            bail!("proposal has not reached its activation version yet");
+        }
+    }
+    at.delete_data_field(&field)
+}
+
 /// Gets an argument out of action arguments.
 fn get_arg<T: DeserializeOwned>(args: &Vec<Vec<u8>>, i: usize) -> Result<T, anyhow::Error> {
     if i >= args.len() {
@@ -255,27 +746,95 @@ fn get_arg<T: DeserializeOwned>(args: &Vec<Vec<u8>>, i: usize) -> Result<T, anyh
     Ok(rmp_serde::from_read(args[i].as_slice())?)
 }
 
-/// Verifies that the argument at a given index is a signature of a modified
-/// version of the action where the signature itself is replaced with
-/// an empty vector, and also that the signature's account matches the
-/// given account.
-fn verify_signature_argument(
-    acct: HashCode,
+/// Verifies that the argument at a given index is a vector of signatures of
+/// a modified version of the action where the signatures themselves are
+/// replaced with an empty vector, and that enough of the account's authorized
+/// keys have signed.  These are the keys of the account's signer set if it
+/// has one, and otherwise its public key.  An account that has no public key
+/// yet (because it is initializing) must instead be the account of one of the
+/// signatures' keys.  Also checks and advances the account's nonce.  Returns
+/// the signatures.
+async fn verify_signature_argument<'a, HL: HashLookup>(
+    at: &mut AccountTransform<'a, HL>,
     action: &Action,
     i: usize,
-) -> Result<(), anyhow::Error> {
-    let sig: Signature<Action> = get_arg(&action.args, i)?;
-    if sig.account() != acct {
-        bail!("signature account must equal current account");
-    }
+) -> Result<Vec<Signature<Action>>, anyhow::Error> {
+    debug_assert!(
+        SIGNED_COMMANDS.contains(&&action.command[..]),
+        "signed command missing from SIGNED_COMMANDS"
+    );
+    let sigs: Vec<Signature<Action>> = get_arg(&action.args, i)?;
     let mut act2 = action.clone();
     act2.args[i] = Vec::new();
-    if !verify_sig(&act2, &sig) {
-        bail!("invalid signature");
+    for sig in &sigs {
+        at.gas.charge(GAS_SIGNATURE)?;
+        if !verify_sig(&act2, sig) {
+            bail!("invalid signature");
+        }
+    }
+    let signed: BTreeSet<HashCode> = sigs.iter().map(|sig| sig.account()).collect();
+    match at.get_data_field(at.this_account, &field_signers()).await? {
+        Some(signers) => {
+            let authorized = signers
+                .keys
+                .iter()
+                .filter(|key| signed.contains(&hash(*key).code))
+                .count();
+            if authorized < signers.threshold as usize {
+                bail!("not enough authorized signatures");
+            }
+        }
+        None => match at
+            .get_data_field(at.this_account, &field_public_key())
+            .await?
+        {
+            Some(key) => {
+                if !signed.contains(&hash(&key).code) {
+                    bail!("action must be signed by account's public key");
+                }
+            }
+            None => {
+                if !signed.contains(&at.this_account) {
+                    bail!("signature account must equal current account");
+                }
+            }
+        },
+    }
+    use_nonce(at, action).await?;
+    Ok(sigs)
+}
+
+/// Checks that a signer set can be satisfied and has no duplicate keys.
+fn verify_signer_set(signers: &SignerSet) -> Result<(), anyhow::Error> {
+    let accounts: BTreeSet<HashCode> = signers.keys.iter().map(|key| hash(key).code).collect();
+    if accounts.len() != signers.keys.len() {
+        bail!("signer set has duplicate keys");
+    }
+    if signers.threshold == 0 || signers.threshold as usize > signers.keys.len() {
+        bail!("signer set threshold must be between 1 and the number of keys");
     }
     Ok(())
 }
 
+/// The built-in commands `run_action` authorizes with a signature argument,
+/// each of which uses the account's next nonce.  `slash` is authorized by
+/// its evidence instead, and other commands run the account's code.
+pub const SIGNED_COMMANDS: &[&[u8]] = &[
+    b"send",
+    b"receive",
+    b"stake",
+    b"unstake",
+    b"withdraw",
+    b"prune_send",
+    b"propose_options",
+    b"vote",
+    b"prune_proposal",
+    b"prune_vote",
+    b"rotate_key",
+    b"set_signers",
+    b"set_code",
+];
+
 /// Runs an action in a given `AccountTransform` context.
 pub async fn run_action<'a, HL: HashLookup>(
     at: &mut AccountTransform<'a, HL>,
@@ -284,6 +843,8 @@ pub async fn run_action<'a, HL: HashLookup>(
     if at.last_main != action.last_main {
         bail!("action last main must equal current last main");
     }
+    let arg_bytes: usize = action.args.iter().map(|arg| arg.len()).sum();
+    at.gas.charge(GAS_PER_ARG_BYTE * arg_bytes as u128)?;
     if action.command == b"send" {
         if at.is_initializing {
             bail!("send can't initialize an account");
@@ -292,7 +853,7 @@ pub async fn run_action<'a, HL: HashLookup>(
         let send_amount: u128 = get_arg(&action.args, 1)?;
         let initialize_spec: Option<Hash<Vec<u8>>> = get_arg(&action.args, 2)?;
         let message: Vec<u8> = get_arg(&action.args, 3)?;
-        verify_signature_argument(at.this_account, action, 4)?;
+        verify_signature_argument(at, action, 4).await?;
         pay_fee(at, action.fee).await?;
         let send = SendInfo {
             last_main: action.last_main,
@@ -306,34 +867,206 @@ pub async fn run_action<'a, HL: HashLookup>(
     } else if action.command == b"receive" {
         let sender: HashCode = get_arg(&action.args, 0)?;
         let send_hash: Hash<SendInfo> = get_arg(&action.args, 1)?;
-        let sig: Signature<Action> = get_arg(&action.args, 2)?;
-        verify_signature_argument(at.this_account, action, 2)?;
+        let spec: Option<Vec<u8>> = get_arg(&action.args, 2)?;
+        let sigs = verify_signature_argument(at, action, 3).await?;
         if at.is_initializing {
+            let key = sigs
+                .iter()
+                .find(|sig| sig.account() == at.this_account)
+                .ok_or_else(|| {
+                    anyhow!("initializing receive must be signed by the new account's key")
+                })?
+                .key;
             at.set_data_field(&field_balance(), &0)?;
             at.set_data_field(&field_stake(), &0)?;
-            at.set_data_field(&field_public_key(), &sig.key)?;
+            at.set_data_field(&field_public_key(), &key)?;
+        }
+        let send = do_receive(at, sender, send_hash).await?;
+        if at.is_initializing {
+            do_initialize(at, &send, spec)?;
+        } else if spec.is_some() {
+            bail!("initialize spec given for an existing account");
         }
-        do_receive(at, sender, send_hash).await?;
         pay_fee(at, action.fee).await?;
-    } else {
+    } else if action.command == b"stake" {
+        if at.is_initializing {
+            bail!("stake can't initialize an account");
+        }
+        let amount: u128 = get_arg(&action.args, 0)?;
+        verify_signature_argument(at, action, 1).await?;
+        pay_fee(at, action.fee).await?;
+        do_stake(at, amount).await?;
+    } else if action.command == b"unstake" {
+        if at.is_initializing {
+            bail!("unstake can't initialize an account");
+        }
+        let amount: u128 = get_arg(&action.args, 0)?;
+        verify_signature_argument(at, action, 1).await?;
+        pay_fee(at, action.fee).await?;
+        do_unstake(at, amount, action.nonce).await?;
+    } else if action.command == b"withdraw" {
+        if at.is_initializing {
+            bail!("withdraw can't initialize an account");
+        }
+        let unbonding_hash: Hash<UnbondingInfo> = get_arg(&action.args, 0)?;
+        verify_signature_argument(at, action, 1).await?;
+        pay_fee(at, action.fee).await?;
+        do_withdraw(at, unbonding_hash).await?;
+    } else if action.command == b"prune_send" {
+        if at.is_initializing {
+            bail!("prune_send can't initialize an account");
+        }
+        let send_hash: Hash<SendInfo> = get_arg(&action.args, 0)?;
+        verify_signature_argument(at, action, 1).await?;
+        pay_fee(at, action.fee).await?;
+        do_prune_send(at, send_hash).await?;
+    } else if action.command == b"slash" {
+        // the evidence authorizes the action, so there is no signature
+        if at.is_initializing {
+            bail!("slash can't initialize an account");
+        }
+        let evidence: SlashEvidence = get_arg(&action.args, 0)?;
+        let reporter: HashCode = get_arg(&action.args, 1)?;
+        do_slash(at, &evidence, reporter, action.fee).await?;
+    } else if action.command == b"propose_options" {
+        if at.is_initializing {
+            bail!("propose_options can't initialize an account");
+        }
+        let options: MainOptions = get_arg(&action.args, 0)?;
+        let activation_version: u64 = get_arg(&action.args, 1)?;
+        verify_signature_argument(at, action, 2).await?;
+        pay_fee(at, action.fee).await?;
+        let proposal = ProposalInfo {
+            last_main: action.last_main,
+            proposer: at.this_account,
+            options,
+            activation_version,
+        };
+        do_propose_options(at, &proposal).await?;
+    } else if action.command == b"vote" {
+        if at.is_initializing {
+            bail!("vote can't initialize an account");
+        }
+        let proposer: HashCode = get_arg(&action.args, 0)?;
+        let proposal_hash: Hash<ProposalInfo> = get_arg(&action.args, 1)?;
+        verify_signature_argument(at, action, 2).await?;
+        pay_fee(at, action.fee).await?;
+        do_vote(at, proposer, proposal_hash).await?;
+    } else if action.command == b"prune_proposal" {
+        if at.is_initializing {
+            bail!("prune_proposal can't initialize an account");
+        }
+        let proposal_hash: Hash<ProposalInfo> = get_arg(&action.args, 0)?;
+        verify_signature_argument(at, action, 1).await?;
+        pay_fee(at, action.fee).await?;
+        do_prune_proposal(at, proposal_hash).await?;
+    } else if action.command == b"prune_vote" {
+        if at.is_initializing {
+            bail!("prune_vote can't initialize an account");
+        }
+        let proposal_hash: Hash<ProposalInfo> = get_arg(&action.args, 0)?;
+        verify_signature_argument(at, action, 1).await?;
+        pay_fee(at, action.fee).await?;
+        do_prune_vote(at, proposal_hash).await?;
+    } else if action.command == b"rotate_key" {
+        if at.is_initializing {
+            bail!("rotate_key can't initialize an account");
+        }
+        let new_key: ed25519_dalek::PublicKey = get_arg(&action.args, 0)?;
+        verify_signature_argument(at, action, 1).await?;
+        if at
+            .get_data_field(at.this_account, &field_signers())
+            .await?
+            .is_some()
+        {
+            bail!("account has a signer set, which must be changed with set_signers");
+        }
+        pay_fee(at, action.fee).await?;
+        at.set_data_field(&field_public_key(), &new_key)?;
+    } else if action.command == b"set_signers" {
+        if at.is_initializing {
+            bail!("set_signers can't initialize an account");
+        }
+        let signers: SignerSet = get_arg(&action.args, 0)?;
+        verify_signature_argument(at, action, 1).await?;
+        verify_signer_set(&signers)?;
+        pay_fee(at, action.fee).await?;
+        at.set_data_field(&field_signers(), &signers)?;
+    } else if action.command == b"set_code" {
+        if at.is_initializing {
+            bail!("set_code can't initialize an account");
+        }
+        let code: Vec<Instr> = get_arg(&action.args, 0)?;
+        verify_signature_argument(at, action, 1).await?;
+        pay_fee(at, action.fee).await?;
+        at.set_data_field(&field_code(), &code)?;
+    } else if at.is_initializing {
         bail!("unknown command {:?}", action.command);
+    } else {
+        match at.get_data_field(at.this_account, &field_code()).await? {
+            None => bail!("unknown command {:?}", action.command),
+            // code authorizes actions, uses their nonces and pays their fees
+            // itself
+            Some(code) => run_code(at, action, &code).await?,
+        }
     }
     Ok(())
 }
 
+/// Signs an action with several keys, replacing its last argument with the
+/// signatures.  Every command takes its signatures as its last argument, so
+/// this can be used to sign actions from the other builders on behalf of
+/// multi-signature accounts.
+pub fn sign_action(mut act: Action, keys: &[&ed25519_dalek::Keypair]) -> Action {
+    let last = act.args.len() - 1;
+    act.args[last] = Vec::new();
+    let sigs: Vec<Signature<Action>> = keys.iter().map(|key| sign(key, act.clone())).collect();
+    act.args[last] = rmp_serde::to_vec_named(&sigs).unwrap();
+    act
+}
+
 /// Creates a send action.
+#[allow(clippy::too_many_arguments)]
 pub fn mk_send(
     last_main: Hash<MainBlock>,
     fee: u128,
+    nonce: u64,
     recipient: HashCode,
     send_amount: u128,
     initialize_spec: Option<Hash<Vec<u8>>>,
     message: Vec<u8>,
     key: &ed25519_dalek::Keypair,
 ) -> (Action, SendInfo) {
-    let mut act = Action {
+    mk_send_multisig(
         last_main,
         fee,
+        nonce,
+        hash(&key.public).code,
+        recipient,
+        send_amount,
+        initialize_spec,
+        message,
+        &[key],
+    )
+}
+
+/// Creates a send action from a multi-signature account.
+#[allow(clippy::too_many_arguments)]
+pub fn mk_send_multisig(
+    last_main: Hash<MainBlock>,
+    fee: u128,
+    nonce: u64,
+    sender: HashCode,
+    recipient: HashCode,
+    send_amount: u128,
+    initialize_spec: Option<Hash<Vec<u8>>>,
+    message: Vec<u8>,
+    keys: &[&ed25519_dalek::Keypair],
+) -> (Action, SendInfo) {
+    let act = Action {
+        last_main,
+        fee,
+        nonce,
         command: b"send".to_vec(),
         args: vec![
             rmp_serde::to_vec_named(&recipient).unwrap(),
@@ -343,10 +1076,10 @@ pub fn mk_send(
             vec![],
         ],
     };
-    act.args[4] = rmp_serde::to_vec_named(&sign(&key, &act)).unwrap();
+    let act = sign_action(act, keys);
     let si = SendInfo {
         last_main,
-        sender: hash(&key.public).code,
+        sender,
         recipient,
         send_amount,
         initialize_spec,
@@ -355,47 +1088,319 @@ pub fn mk_send(
     (act, si)
 }
 
-/// Creates a receive action.
+/// Creates a receive action.  If the receive creates the account,
+/// `initialize_spec` must be the spec committed to by the send.
 pub fn mk_receive(
     last_main: Hash<MainBlock>,
     fee: u128,
+    nonce: u64,
     sender: HashCode,
     send_hash: Hash<SendInfo>,
-    key: ed25519_dalek::Keypair,
+    initialize_spec: Option<&InitializeSpec>,
+    key: &ed25519_dalek::Keypair,
 ) -> Action {
-    let mut act = Action {
+    let act = Action {
         last_main,
         fee,
+        nonce,
         command: b"receive".to_vec(),
         args: vec![
             rmp_serde::to_vec_named(&sender).unwrap(),
             rmp_serde::to_vec_named(&send_hash).unwrap(),
+            rmp_serde::to_vec_named(&initialize_spec.map(InitializeSpec::to_bytes)).unwrap(),
             vec![],
         ],
     };
-    act.args[2] = rmp_serde::to_vec_named(&sign(&key, &act)).unwrap();
-    act
+    sign_action(act, &[key])
+}
+
+/// Creates an action with a single argument followed by a signature.
+fn mk_signed_action<T: Serialize>(
+    last_main: Hash<MainBlock>,
+    fee: u128,
+    nonce: u64,
+    command: &[u8],
+    arg: &T,
+    key: &ed25519_dalek::Keypair,
+) -> Action {
+    let act = Action {
+        last_main,
+        fee,
+        nonce,
+        command: command.to_vec(),
+        args: vec![rmp_serde::to_vec_named(arg).unwrap(), vec![]],
+    };
+    sign_action(act, &[key])
+}
+
+/// Creates a stake action.
+pub fn mk_stake(
+    last_main: Hash<MainBlock>,
+    fee: u128,
+    nonce: u64,
+    amount: u128,
+    key: &ed25519_dalek::Keypair,
+) -> Action {
+    mk_signed_action(last_main, fee, nonce, b"stake", &amount, key)
+}
+
+/// Creates an unstake action, along with the resulting `UnbondingInfo`,
+/// which will be withdrawable after the unbonding period.
+pub fn mk_unstake(
+    last_main: &MainBlock,
+    opts: &MainOptions,
+    fee: u128,
+    nonce: u64,
+    amount: u128,
+    key: &ed25519_dalek::Keypair,
+) -> (Action, UnbondingInfo) {
+    let last_main_hash = hash(last_main);
+    let act = mk_signed_action(last_main_hash, fee, nonce, b"unstake", &amount, key);
+    let unbonding = UnbondingInfo {
+        last_main: last_main_hash,
+        amount,
+        unlock_version: last_main.block.body.version + 1 + opts.unbonding_period,
+        nonce,
+    };
+    (act, unbonding)
+}
+
+/// Creates a withdraw action.
+pub fn mk_withdraw(
+    last_main: Hash<MainBlock>,
+    fee: u128,
+    nonce: u64,
+    unbonding_hash: Hash<UnbondingInfo>,
+    key: &ed25519_dalek::Keypair,
+) -> Action {
+    mk_signed_action(last_main, fee, nonce, b"withdraw", &unbonding_hash, key)
+}
+
+/// Creates an action deleting the record of a send that has been received.
+pub fn mk_prune_send(
+    last_main: Hash<MainBlock>,
+    fee: u128,
+    nonce: u64,
+    send_hash: Hash<SendInfo>,
+    key: &ed25519_dalek::Keypair,
+) -> Action {
+    mk_signed_action(last_main, fee, nonce, b"prune_send", &send_hash, key)
+}
+
+/// Creates an action slashing the account that committed the offense in
+/// `evidence`, rewarding `reporter`.  Slash actions are unsigned, so they
+/// don't use the account's nonce.
+pub fn mk_slash(
+    last_main: Hash<MainBlock>,
+    fee: u128,
+    evidence: &SlashEvidence,
+    reporter: HashCode,
+) -> Action {
+    Action {
+        last_main,
+        fee,
+        nonce: 0,
+        command: b"slash".to_vec(),
+        args: vec![
+            rmp_serde::to_vec_named(evidence).unwrap(),
+            rmp_serde::to_vec_named(&reporter).unwrap(),
+        ],
+    }
+}
+
+/// Creates an action proposing new options, along with the resulting
+/// `ProposalInfo`.
+pub fn mk_propose_options(
+    last_main: Hash<MainBlock>,
+    fee: u128,
+    nonce: u64,
+    options: MainOptions,
+    activation_version: u64,
+    key: &ed25519_dalek::Keypair,
+) -> (Action, ProposalInfo) {
+    let act = Action {
+        last_main,
+        fee,
+        nonce,
+        command: b"propose_options".to_vec(),
+        args: vec![
+            rmp_serde::to_vec_named(&options).unwrap(),
+            rmp_serde::to_vec_named(&activation_version).unwrap(),
+            vec![],
+        ],
+    };
+    let act = sign_action(act, &[key]);
+    let proposal = ProposalInfo {
+        last_main,
+        proposer: hash(&key.public).code,
+        options,
+        activation_version,
+    };
+    (act, proposal)
+}
+
+/// Creates an action voting for a proposal made by `proposer`.
+pub fn mk_vote(
+    last_main: Hash<MainBlock>,
+    fee: u128,
+    nonce: u64,
+    proposer: HashCode,
+    proposal: Hash<ProposalInfo>,
+    key: &ed25519_dalek::Keypair,
+) -> Action {
+    let act = Action {
+        last_main,
+        fee,
+        nonce,
+        command: b"vote".to_vec(),
+        args: vec![
+            rmp_serde::to_vec_named(&proposer).unwrap(),
+            rmp_serde::to_vec_named(&proposal).unwrap(),
+            vec![],
+        ],
+    };
+    sign_action(act, &[key])
+}
+
+/// Creates an action deleting a proposal made by the account.
+pub fn mk_prune_proposal(
+    last_main: Hash<MainBlock>,
+    fee: u128,
+    nonce: u64,
+    proposal: Hash<ProposalInfo>,
+    key: &ed25519_dalek::Keypair,
+) -> Action {
+    mk_signed_action(last_main, fee, nonce, b"prune_proposal", &proposal, key)
+}
+
+/// Creates an action deleting the account's vote for a proposal.
+pub fn mk_prune_vote(
+    last_main: Hash<MainBlock>,
+    fee: u128,
+    nonce: u64,
+    proposal: Hash<ProposalInfo>,
+    key: &ed25519_dalek::Keypair,
+) -> Action {
+    mk_signed_action(last_main, fee, nonce, b"prune_vote", &proposal, key)
+}
+
+/// Creates an action replacing the account's public key, signed by the
+/// current key.
+pub fn mk_rotate_key(
+    last_main: Hash<MainBlock>,
+    fee: u128,
+    nonce: u64,
+    new_key: &ed25519_dalek::PublicKey,
+    key: &ed25519_dalek::Keypair,
+) -> Action {
+    mk_signed_action(last_main, fee, nonce, b"rotate_key", new_key, key)
+}
+
+/// Creates an action replacing the keys authorized to sign for an account,
+/// signed by keys authorized to sign for it currently.
+pub fn mk_set_signers(
+    last_main: Hash<MainBlock>,
+    fee: u128,
+    nonce: u64,
+    signers: &SignerSet,
+    keys: &[&ed25519_dalek::Keypair],
+) -> Action {
+    let act = Action {
+        last_main,
+        fee,
+        nonce,
+        command: b"set_signers".to_vec(),
+        args: vec![rmp_serde::to_vec_named(signers).unwrap(), vec![]],
+    };
+    sign_action(act, keys)
+}
+
+/// Creates an action setting the code run for commands that are not built in.
+pub fn mk_set_code(
+    last_main: Hash<MainBlock>,
+    fee: u128,
+    nonce: u64,
+    code: &[Instr],
+    keys: &[&ed25519_dalek::Keypair],
+) -> Action {
+    let act = Action {
+        last_main,
+        fee,
+        nonce,
+        command: b"set_code".to_vec(),
+        args: vec![rmp_serde::to_vec_named(code).unwrap(), vec![]],
+    };
+    sign_action(act, keys)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::blockdata::{AccountInit, PreSignedMainBlock};
+    use crate::construction::genesis_block_body;
     use crate::crypto;
+    use crate::hashlookup::{HashPut, MapHashLookup};
 
     #[test]
     fn verify_send() {
-        let last_main = Hash::<MainBlock> {
-            code: [0; 32],
-            phantom: PhantomData,
+        let key = crypto::gen_private_key();
+        let other_key = crypto::gen_private_key();
+        let mut hl = MapHashLookup::new();
+        let opts = MainOptions {
+            gas_cost: 1,
+            gas_limit: u128::MAX,
+            timestamp_period_ms: 10,
+            main_block_signers: 1,
+            main_block_signatures_required: 1,
+            random_seed_period: 1,
+            quorum_period: 1,
+            max_quorum_depth: 16,
+            quorum_sizes_thresholds: vec![(1, 1)],
+            unbonding_period: 1,
+            slash_percent: 50,
+            slash_reporter_percent: 20,
+            options_vote_percent: 50,
+            block_reward: 0,
+            block_reward_halving_period: 0,
+            miner_reward_percent: 50,
         };
+        let inits = vec![AccountInit {
+            public_key: key.public,
+            balance: 100,
+            stake: 1,
+        }];
+        let body = smol::block_on(genesis_block_body(&mut hl, &inits, 0, opts)).unwrap();
+        let block = PreSignedMainBlock::sign(body, &vec![&key]);
+        let last_main = smol::block_on(hl.put(&MainBlock::sign(block, &key))).unwrap();
         let fee: u128 = 5;
         let recipient: HashCode = [0; 32];
         let send_amount: u128 = 25;
         let init_spec: Option<Hash<Vec<u8>>> = None;
         let msg: Vec<u8> = vec![];
-        let key = crypto::gen_private_key();
-        let (act, si) = mk_send(last_main, fee, recipient, send_amount, init_spec, msg, &key);
-        let res = verify_signature_argument(si.sender, &act, 4);
+        let (act, si) = mk_send(
+            last_main,
+            fee,
+            0,
+            recipient,
+            send_amount,
+            init_spec,
+            msg.clone(),
+            &key,
+        );
+        let mut at = AccountTransform::new(&hl, false, si.sender, last_main, u128::MAX);
+        let res = smol::block_on(verify_signature_argument(&mut at, &act, 4));
         assert!(res.is_ok(), "got error: {}", res.unwrap_err());
+        assert!(smol::block_on(verify_signature_argument(&mut at, &act, 4)).is_err());
+        let (forged, _) = mk_send(
+            last_main,
+            fee,
+            1,
+            recipient,
+            send_amount,
+            init_spec,
+            msg,
+            &other_key,
+        );
+        assert!(smol::block_on(verify_signature_argument(&mut at, &forged, 4)).is_err());
     }
 }
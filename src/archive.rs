@@ -0,0 +1,235 @@
+//! A streaming archive format for moving chain data between stores.
+//!
+//! An archive starts with `ARCHIVE_MAGIC` and the hash code of a root
+//! `MainBlock`, followed by records until the end of the stream.  Each record
+//! is the hash code of a blob, the length of the blob as a little-endian
+//! `u32`, and the blob itself.  Records are written in topological order,
+//! with every object after the objects it references, so that the root block
+//! is always the last record and an importer never stores an object whose
+//! contents are missing.
+
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::io::{ErrorKind, Read, Write};
+
+use anyhow::{anyhow, bail};
+
+use crate::blockdata::{MainBlock, QuorumNode};
+use crate::crypto::{hash_of_bytes, Hash, HashCode};
+use crate::hashlookup::{HashLookup, HashMismatch, HashPut};
+use crate::hex_path::{bytes_to_path, is_prefix};
+use crate::object_graph::{object_references, ObjectKind, ObjectRef};
+
+/// The bytes every archive starts with.
+pub const ARCHIVE_MAGIC: &[u8; 8] = b"MERCARC1";
+
+/// Statistics about an exported or imported archive.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct ArchiveStats {
+    /// The number of records.
+    pub objects: u64,
+    /// The total size of the blobs, in bytes.
+    pub bytes: u64,
+}
+
+/// Writes archive records, skipping objects that were already written.
+struct ArchiveWriter<'a, HL: HashLookup, W: Write> {
+    hl: &'a HL,
+    w: W,
+    written: BTreeSet<HashCode>,
+    stats: ArchiveStats,
+}
+
+impl<'a, HL: HashLookup, W: Write> ArchiveWriter<'a, HL, W> {
+    /// Writes the archive header.
+    fn start(hl: &'a HL, mut w: W, root: Hash<MainBlock>) -> Result<Self, anyhow::Error> {
+        w.write_all(ARCHIVE_MAGIC)?;
+        w.write_all(&root.code)?;
+        Ok(ArchiveWriter {
+            hl,
+            w,
+            written: BTreeSet::new(),
+            stats: ArchiveStats::default(),
+        })
+    }
+
+    /// Writes a single object, without the objects it references.
+    async fn write_object(&mut self, code: HashCode) -> Result<(), anyhow::Error> {
+        if !self.written.insert(code) {
+            return Ok(());
+        }
+        let bs = self.hl.lookup_bytes(code).await?;
+        let len: u32 = bs
+            .len()
+            .try_into()
+            .map_err(|_| anyhow!("object too large for archive"))?;
+        self.w.write_all(&code)?;
+        self.w.write_all(&len.to_le_bytes())?;
+        self.w.write_all(&bs)?;
+        self.stats.objects += 1;
+        self.stats.bytes += u64::from(len);
+        Ok(())
+    }
+
+    /// Writes an object and everything reachable from it, dependencies first.
+    async fn write_closure(&mut self, root: ObjectRef) -> Result<(), anyhow::Error> {
+        // each entry is an object along with whether its references were pushed
+        let mut stack = vec![(root, false)];
+        while let Some((obj, expanded)) = stack.pop() {
+            if self.written.contains(&obj.code) {
+                continue;
+            }
+            if expanded {
+                self.write_object(obj.code).await?;
+            } else {
+                stack.push((obj, true));
+                for r in object_references(self.hl, obj).await? {
+                    if !self.written.contains(&r.code) {
+                        stack.push((r, false));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<ArchiveStats, anyhow::Error> {
+        self.w.flush()?;
+        Ok(self.stats)
+    }
+}
+
+/// Exports a main block and everything reachable from it, along with the
+/// blocks reachable through up to `depth` of its `prev` links (and their
+/// state), into an archive.
+pub async fn export_chain<HL: HashLookup, W: Write>(
+    hl: &HL,
+    head: Hash<MainBlock>,
+    depth: u64,
+    w: W,
+) -> Result<ArchiveStats, anyhow::Error> {
+    let mut blocks = vec![head];
+    let mut block = hl.lookup(head).await?;
+    while (blocks.len() as u64) <= depth {
+        match block.block.body.prev {
+            None => break,
+            Some(prev) => {
+                blocks.push(prev);
+                block = hl.lookup(prev).await?;
+            }
+        }
+    }
+    let mut writer = ArchiveWriter::start(hl, w, head)?;
+    // oldest first, so that each block follows its `prev`
+    for block in blocks.into_iter().rev() {
+        writer.write_closure(ObjectRef::main_block(block)).await?;
+    }
+    writer.finish()
+}
+
+/// Exports the part of a main block's state needed to reach a single
+/// account: the block, its options, the `QuorumNode`s on the path to the
+/// account (without their other children), and the account node along with
+/// everything reachable from it.
+pub async fn export_account<HL: HashLookup, W: Write>(
+    hl: &HL,
+    block_hash: Hash<MainBlock>,
+    acct: HashCode,
+    w: W,
+) -> Result<ArchiveStats, anyhow::Error> {
+    let block = hl.lookup(block_hash).await?;
+    let acct_path = bytes_to_path(&acct);
+    let mut path_nodes: Vec<Hash<QuorumNode>> = vec![block.block.body.tree];
+    let mut node: QuorumNode = hl.lookup(block.block.body.tree).await?;
+    while node.body.path.len() < acct_path.len() {
+        let depth = node.body.path.len();
+        let (suffix, child) = match &node.body.children.0[acct_path[depth].0 as usize] {
+            None => bail!("account not found in main block"),
+            Some(entry) => entry.clone(),
+        };
+        if !is_prefix(&suffix[..], &acct_path[depth + 1..]) {
+            bail!("account not found in main block");
+        }
+        path_nodes.push(child);
+        node = hl.lookup(child).await?;
+    }
+    let mut writer = ArchiveWriter::start(hl, w, block_hash)?;
+    writer
+        .write_closure(ObjectRef::quorum_node(path_nodes.pop().unwrap()))
+        .await?;
+    for qn in path_nodes.into_iter().rev() {
+        writer.write_object(qn.code).await?;
+    }
+    writer
+        .write_closure(ObjectRef {
+            kind: ObjectKind::MainOptions,
+            code: block.block.body.options.code,
+        })
+        .await?;
+    writer.write_object(block_hash.code).await?;
+    writer.finish()
+}
+
+/// Reads exactly `buf.len()` bytes, returning `false` if the stream ended
+/// before any byte was read.
+fn read_exact_or_eof<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<bool, anyhow::Error> {
+    let mut read = 0;
+    while read < buf.len() {
+        match r.read(&mut buf[read..]) {
+            Ok(0) => {
+                if read == 0 {
+                    return Ok(false);
+                }
+                bail!("archive ends in the middle of a record");
+            }
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
+
+/// Imports an archive into a `HashPut`, checking the hash code of every blob
+/// before storing it.  Returns the root main block and import statistics.
+pub async fn import_archive<HP: HashPut, R: Read>(
+    hp: &mut HP,
+    mut r: R,
+) -> Result<(Hash<MainBlock>, ArchiveStats), anyhow::Error> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != ARCHIVE_MAGIC {
+        bail!("not a mercatoria archive");
+    }
+    let mut root = [0; 32];
+    r.read_exact(&mut root)?;
+    let mut stats = ArchiveStats::default();
+    let mut root_seen = false;
+    let mut header = [0; 32 + 4];
+    while read_exact_or_eof(&mut r, &mut header)? {
+        let code: HashCode = header[..32].try_into().unwrap();
+        let len = u32::from_le_bytes(header[32..].try_into().unwrap());
+        // the length is untrusted, so the buffer only grows as bytes arrive
+        let mut bs = Vec::new();
+        (&mut r).take(u64::from(len)).read_to_end(&mut bs)?;
+        if bs.len() as u64 != u64::from(len) {
+            bail!("archive ends in the middle of a record");
+        }
+        let actual = hash_of_bytes(&bs);
+        if actual != code {
+            return Err(HashMismatch {
+                expected: code,
+                actual,
+            }
+            .into());
+        }
+        hp.put_bytes(&bs).await?;
+        root_seen |= code == root;
+        stats.objects += 1;
+        stats.bytes += u64::from(len);
+    }
+    if !root_seen {
+        bail!("archive does not contain its root block");
+    }
+    Ok((Hash::from_code(root), stats))
+}
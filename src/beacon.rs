@@ -0,0 +1,96 @@
+//! The random beacon that seeds the selection of miners, signers and quorums.
+//!
+//! Each main block carries a random seed.  The miner and signers selected by
+//! the previous block contribute `BeaconShare`s, which are VRF proofs over an
+//! input derived from the previous seed and the new version.  Since a VRF
+//! output is determined by the key and the input, nobody can grind the seed
+//! by choosing which actions to include.
+//!
+//! A block carries either the shares of every participant, in order of
+//! account, in which case the seed combines all of their outputs, or the
+//! miner's share alone, in which case the seed falls back to one derived from
+//! the previous seed and the version only.  The fallback keeps the chain live
+//! when signers withhold their shares.  Since it does not depend on any
+//! share, a miner that drops the others' shares gets a seed fixed in
+//! advance rather than one of its own making, so the most a miner (or a
+//! withholding signer) can do is choose between the combined seed and the
+//! fallback.  Removing that choice would need a threshold scheme, which this
+//! module does not implement.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::bail;
+use ed25519_dalek::Keypair;
+
+use crate::account_transform::accounts_by_key;
+use crate::blockdata::{BeaconShare, MainBlock, MainBlockBody};
+use crate::crypto::{hash, HashCode};
+use crate::hashlookup::HashLookup;
+use crate::queries::miner_and_signers_by_prev_block;
+use crate::vrf::{vrf_prove, vrf_verify};
+
+/// Gets the VRF input for the beacon shares of the block following a given
+/// block.
+pub fn beacon_input(prev: &MainBlockBody) -> Vec<u8> {
+    rmp_serde::to_vec_named(&("beacon", prev.random_seed, prev.version + 1)).unwrap()
+}
+
+/// Creates a beacon share for the block following a given block.
+pub fn mk_beacon_share(prev: &MainBlockBody, key: &Keypair) -> BeaconShare {
+    let (_, proof) = vrf_prove(key, &beacon_input(prev));
+    BeaconShare {
+        key: key.public,
+        proof,
+    }
+}
+
+/// Computes the random seed of the block following a given block from its
+/// beacon shares, given the miner and signers selected by the given block
+/// and the participants by the hash codes of their keys as of that block
+/// (see `accounts_by_key`).  Fails if a share is invalid, or if the shares
+/// are neither every participant's in order of account nor the miner's alone.
+pub fn beacon_seed(
+    prev: &MainBlockBody,
+    miner: HashCode,
+    signers: &[HashCode],
+    accounts_by_key: &BTreeMap<HashCode, HashCode>,
+    shares: &[BeaconShare],
+) -> Result<HashCode, anyhow::Error> {
+    let participants: BTreeSet<HashCode> = signers.iter().copied().chain(Some(miner)).collect();
+    let input = beacon_input(prev);
+    let accounts: Vec<Option<HashCode>> = shares
+        .iter()
+        .map(|share| accounts_by_key.get(&hash(&share.key).code).copied())
+        .collect();
+    if accounts
+        .iter()
+        .copied()
+        .eq(participants.iter().copied().map(Some))
+    {
+        let mut outputs = Vec::new();
+        for share in shares {
+            outputs.push(vrf_verify(&share.key, &input, &share.proof)?);
+        }
+        return Ok(hash(&("beacon", prev.random_seed, outputs)).code);
+    }
+    match shares {
+        [share] if accounts[0] == Some(miner) => {
+            // some signers withheld their shares
+            vrf_verify(&share.key, &input, &share.proof)?;
+            Ok(hash(&("beacon fallback", prev.random_seed, prev.version + 1)).code)
+        }
+        _ => bail!("beacon shares must be every participant's or the miner's alone"),
+    }
+}
+
+/// Like `beacon_seed`, but looks up the miner and signers.
+pub async fn next_beacon_seed<HL: HashLookup>(
+    hl: &HL,
+    prev: &MainBlock,
+    shares: &[BeaconShare],
+) -> Result<HashCode, anyhow::Error> {
+    let (miner, signers) = miner_and_signers_by_prev_block(hl, prev).await?;
+    let participants: Vec<HashCode> = signers.iter().copied().chain(Some(miner)).collect();
+    let accounts = accounts_by_key(hl, &prev.block.body, &participants).await?;
+    beacon_seed(&prev.block.body, miner, &signers, &accounts, shares)
+}
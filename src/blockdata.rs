@@ -1,8 +1,12 @@
 //! The data structures used to construct the blockchain.
 
+use std::collections::BTreeMap;
+
 use crate::crypto::{self, Hash, HashCode, Signature};
 use crate::hashlookup::HashLookup;
 use crate::hex_path::{is_postfix, u4, HexPath};
+use crate::vm::Instr;
+use crate::vrf::VrfProof;
 use ed25519_dalek::{Keypair, PublicKey};
 
 use anyhow::{anyhow, bail};
@@ -73,6 +77,9 @@ pub trait RadixHashNode:
         hl: &HL,
         child: (HexPath, Hash<Self>),
     ) -> Result<Self, anyhow::Error>;
+
+    /// Whether the node holds a value, rather than only joining its children.
+    fn has_value(&self) -> bool;
 }
 
 /// The body of a `MainBlock`.  It doesn't contain signatures.
@@ -85,12 +92,41 @@ pub struct MainBlockBody {
     /// The timestamp of the block creation, as epoch milliseconds rounded based on
     /// `timestamp_period_ms`.
     pub timestamp_ms: i64,
-    /// The radix hash tree storing account data.
+    /// The radix hash tree storing account data, which is `endorsed_tree`
+    /// with the block's rewards credited.
     pub tree: Hash<QuorumNode>,
+    /// The radix hash tree built from quorum-endorsed nodes on top of the
+    /// previous block's tree.  Equal to `tree` in the genesis block.
+    pub endorsed_tree: Hash<QuorumNode>,
     /// The options.
     pub options: Hash<MainOptions>,
-    // signer slashes
-    // miner slashes
+    /// The beacon shares contributed by the miner and signers, in order of
+    /// account.  Empty for the genesis block.
+    pub beacon: Vec<BeaconShare>,
+    /// The random seed produced by the beacon.
+    pub random_seed: HashCode,
+    /// The rewards for this block, in order of account, which are credited
+    /// to the accounts' balances in `tree`.
+    pub rewards: Vec<Reward>,
+}
+
+/// A reward paid to an account for a main block.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub struct Reward {
+    /// The rewarded account.
+    pub account: HashCode,
+    /// The amount of money rewarded.
+    pub amount: u128,
+}
+
+/// A contribution to the random beacon: a VRF proof over an input derived
+/// from the previous block's random seed.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub struct BeaconShare {
+    /// The contributor's public key.
+    pub key: PublicKey,
+    /// The VRF proof.
+    pub proof: VrfProof,
 }
 
 /// Options for the blockchain, stored in a `MainBlockBody`.
@@ -115,6 +151,26 @@ pub struct MainOptions {
     /// To be endorsed, there must be some `(a, b)` in this vector such that
     /// there are at least `b` signatures by members of a quorum of size `a`.
     pub quorum_sizes_thresholds: Vec<(u32, u32)>,
+    /// The number of main block versions that unstaked funds stay locked for
+    /// before they can be withdrawn.
+    pub unbonding_period: u64,
+    /// The percentage of an offender's stake that is slashed.
+    pub slash_percent: u32,
+    /// The percentage of a slashed amount that is sent to the reporter of
+    /// the offense.  The rest is burned, apart from the fee of the slash.
+    pub slash_reporter_percent: u32,
+    /// The percentage of the total stake that must vote for an options
+    /// proposal for it to take effect.
+    pub options_vote_percent: u32,
+    /// The money created in each main block, in addition to the fees.
+    pub block_reward: u128,
+    /// The number of main block versions after which the block reward halves,
+    /// or `0` if it never does.
+    pub block_reward_halving_period: u64,
+    /// The percentage of a main block's fees and block reward, after quorum
+    /// prizes, that goes to the miner.  The rest is split between the
+    /// signers of the previous main block.
+    pub miner_reward_percent: u32,
 }
 
 /// A `MainBlockBody` signed by signers.
@@ -163,6 +219,9 @@ pub struct QuorumNodeStats {
     pub prize: u128,
     /// Total stake at or under this node.
     pub stake: u128,
+    /// Total stake of the accounts at or under this node voting for each
+    /// proposal, keyed by the proposer and the proposal's hash code.
+    pub votes: BTreeMap<(HashCode, HashCode), u128>,
 }
 
 impl QuorumNodeStats {
@@ -174,6 +233,7 @@ impl QuorumNodeStats {
             new_nodes: 0,
             prize: 0,
             stake: 0,
+            votes: BTreeMap::new(),
         }
     }
 
@@ -185,10 +245,22 @@ impl QuorumNodeStats {
             new_nodes: self.new_nodes + other.new_nodes,
             prize: self.prize + other.prize,
             stake: self.stake + other.stake,
+            votes: add_votes(self.votes.clone(), &other.votes),
         }
     }
 }
 
+/// Adds the stake voting for each proposal in `other` to `votes`.
+fn add_votes(
+    mut votes: BTreeMap<(HashCode, HashCode), u128>,
+    other: &BTreeMap<(HashCode, HashCode), u128>,
+) -> BTreeMap<(HashCode, HashCode), u128> {
+    for (proposal, stake) in other {
+        *votes.entry(*proposal).or_insert(0) += stake;
+    }
+    votes
+}
+
 /// The body of a `QuorumNode`.  It does not contain signatures.
 #[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
 pub struct QuorumNodeBody {
@@ -256,6 +328,7 @@ impl RadixHashNode for QuorumNode {
                 );
             }
             self.body.stats.stake += child.body.stats.stake;
+            self.body.stats.votes = add_votes(self.body.stats.votes, &child.body.stats.votes);
             if self.body.last_main == child.body.last_main {
                 self.body.stats.fee += child.body.stats.fee;
                 self.body.stats.gas += child.body.stats.gas;
@@ -292,6 +365,10 @@ impl RadixHashNode for QuorumNode {
             },
         })
     }
+
+    fn has_value(&self) -> bool {
+        self.body.data_tree.is_some()
+    }
 }
 
 /// A radix hash node containing account data.
@@ -328,6 +405,10 @@ impl RadixHashNode for DataNode {
             children,
         })
     }
+
+    fn has_value(&self) -> bool {
+        self.field.is_some()
+    }
 }
 
 /// An action that may be run on an account.
@@ -337,6 +418,10 @@ pub struct Action {
     pub last_main: Hash<MainBlock>,
     /// The fee paid for this action.
     pub fee: u128,
+    /// The account's nonce, which must equal the account's `field_nonce`
+    /// for signed actions, so each signed action runs at most once and in
+    /// order.
+    pub nonce: u64,
     /// The command to run, e.g. b"send".
     pub command: Vec<u8>,
     /// The arguments of the command.
@@ -360,6 +445,115 @@ pub struct SendInfo {
     pub message: Vec<u8>,
 }
 
+/// Information about unstaked funds waiting to be withdrawn, stored in the
+/// unstaking account's data.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub struct UnbondingInfo {
+    /// The hash code of the last main block when the funds were unstaked.
+    pub last_main: Hash<MainBlock>,
+    /// The amount of money unstaked.
+    pub amount: u128,
+    /// The first main block version in which the funds may be withdrawn.
+    pub unlock_version: u64,
+    /// The nonce of the unstake action, which keeps the records of
+    /// otherwise identical unstakes apart.
+    pub nonce: u64,
+}
+
+/// A proposal to change the `MainOptions`, stored in the proposer's data.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub struct ProposalInfo {
+    /// The hash code of the last main block when the proposal was made.
+    pub last_main: Hash<MainBlock>,
+    /// The account making the proposal.
+    pub proposer: HashCode,
+    /// The proposed options.
+    pub options: MainOptions,
+    /// The main block version at which the options take effect if the
+    /// proposal passes.  It is a multiple of `quorum_period`, and votes are
+    /// tallied in the block one `quorum_period` before it.
+    pub activation_version: u64,
+}
+
+/// A set of keys authorized to sign actions for an account, along with how
+/// many of them must sign.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub struct SignerSet {
+    /// The authorized public keys.
+    pub keys: Vec<PublicKey>,
+    /// The number of distinct authorized keys that must sign an action.
+    pub threshold: u32,
+}
+
+/// The initial contents of an account created by receiving a send.  The send
+/// commits to them with the hash of their serialization, which is stored in
+/// `SendInfo.initialize_spec`.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct InitializeSpec {
+    /// A signer set authorizing the account's actions instead of its key.
+    pub signers: Option<SignerSet>,
+    /// The code run for commands that are not built in.
+    pub code: Option<Vec<Instr>>,
+    /// Initial values of storage keys of the account code.
+    pub storage: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl InitializeSpec {
+    /// Serializes the spec, as passed to the receive creating the account.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec_named(self).unwrap()
+    }
+
+    /// Gets the hash a send must commit to in order to create an account
+    /// with this spec.
+    pub fn commitment(&self) -> Hash<Vec<u8>> {
+        crypto::hash(&self.to_bytes())
+    }
+}
+
+/// Two signatures by the same key on different values for the same slot.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub struct ConflictingSignatures<T> {
+    /// The first value.
+    pub first: T,
+    /// The signature of the first value.
+    pub first_signature: Signature<T>,
+    /// The second value.
+    pub second: T,
+    /// The signature of the second value.
+    pub second_signature: Signature<T>,
+}
+
+/// Evidence that an account signed conflicting values, for which its stake
+/// is slashed.  Evidence is included in a main block through a `slash`
+/// action on the offending account, rather than in a list in the
+/// `MainBlockBody` like rewards: crediting a reward only changes a balance
+/// (see the `rewards` module), while slashing changes the stake the tree's
+/// stats sum up, so it goes through the account's quorum.  The evidence
+/// itself authorizes the action, so it needs no signature.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub enum SlashEvidence {
+    /// A quorum member signed different `QuorumNodeBody`s with the same
+    /// `last_main` and `path`.
+    Quorum(Box<ConflictingSignatures<QuorumNodeBody>>),
+    /// A miner signed `PreSignedMainBlock`s with different bodies and the
+    /// same version.
+    Miner(Box<ConflictingSignatures<PreSignedMainBlock>>),
+}
+
+/// The slot in which an offense was committed.  An account is slashed at
+/// most once per slot.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub enum SlashSlot {
+    /// Signing quorum nodes at a path for a last main block.
+    Quorum {
+        last_main: Option<Hash<MainBlock>>,
+        path: HexPath,
+    },
+    /// Mining a main block with a version.
+    Miner { version: u64 },
+}
+
 /// Information to initialize an account in the genesis block.
 #[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
 pub struct AccountInit {
@@ -4,16 +4,19 @@ use std::collections::BTreeMap;
 use anyhow::bail;
 
 use crate::account_construction::{initialize_account_node, insert_into_rh_tree};
+use crate::beacon::next_beacon_seed;
 use crate::blockdata::{
-    AccountInit, MainBlock, MainBlockBody, MainOptions, QuorumNode, QuorumNodeBody,
+    AccountInit, BeaconShare, MainBlock, MainBlockBody, MainOptions, QuorumNode, QuorumNodeBody,
     QuorumNodeStats, RadixChildren,
 };
-use crate::crypto::Hash;
+use crate::crypto::{hash, Hash};
+use crate::governance::put_next_options;
 use crate::hashlookup::{HashLookup, HashPut};
 use crate::hex_path::{is_prefix, HexPath};
 use crate::queries::lookup_quorum_node;
+use crate::rewards::{block_rewards, credit_rewards};
 
-use crate::verification::verify_endorsed_quorum_node;
+use crate::verification::{verify_endorsed_quorum_node, verify_options};
 
 /// Adds a descendent to a quorum node.  It does not have to be an
 /// immediate child.  It replaces any old node at that path.
@@ -131,6 +134,7 @@ pub async fn genesis_block_body<HL: HashLookup + HashPut>(
     timestamp_ms: i64,
     opts: MainOptions,
 ) -> Result<MainBlockBody, anyhow::Error> {
+    verify_options(&opts)?;
     let mut stats = QuorumNodeStats::zero();
     stats.new_nodes += 1;
     let mut top = hl
@@ -163,19 +167,27 @@ pub async fn genesis_block_body<HL: HashLookup + HashPut>(
         version: 0,
         timestamp_ms,
         tree: top,
+        endorsed_tree: top,
         options: opts_hash,
+        beacon: Vec::new(),
+        random_seed: hash(&("genesis", timestamp_ms, top, opts_hash)).code,
+        rewards: Vec::new(),
     })
 }
 
-/// Creates the body of the next main block given an already-constructed quorum tree.
-pub async fn next_main_block_body<HL: HashLookup>(
-    hl: &HL,
+/// Creates the body of the next main block given an already-constructed quorum tree
+/// and beacon shares.  If a proposal activates in the block, its options are
+/// stored, and the block's rewards are credited to a copy of the tree.
+pub async fn next_main_block_body<HL: HashLookup + HashPut>(
+    hl: &mut HL,
     timestamp_ms: i64,
     prev_hash: Hash<MainBlock>,
     top_hash: Hash<QuorumNode>,
+    beacon: Vec<BeaconShare>,
 ) -> Result<MainBlockBody, anyhow::Error> {
     let prev = hl.lookup(prev_hash).await?;
-    let opts = hl.lookup(prev.block.body.options).await?;
+    let options = put_next_options(hl, &prev.block.body).await?;
+    let opts = hl.lookup(options).await?;
     if timestamp_ms % (opts.timestamp_period_ms as i64) != 0
         || timestamp_ms <= prev.block.body.timestamp_ms
     {
@@ -188,11 +200,19 @@ pub async fn next_main_block_body<HL: HashLookup>(
     if top_hash != prev.block.body.tree {
         verify_endorsed_quorum_node(hl, &prev, &top).await?;
     }
-    Ok(MainBlockBody {
+    let random_seed = next_beacon_seed(hl, &prev, &beacon).await?;
+    let mut body = MainBlockBody {
         prev: Some(prev_hash),
         version: prev.block.body.version + 1,
         timestamp_ms,
         tree: top_hash,
-        options: prev.block.body.options,
-    })
+        endorsed_tree: top_hash,
+        options,
+        beacon,
+        random_seed,
+        rewards: Vec::new(),
+    };
+    body.rewards = block_rewards(hl, &prev, &body).await?;
+    body.tree = credit_rewards(hl, top_hash, &body.rewards).await?;
+    Ok(body)
 }
@@ -28,6 +28,16 @@ pub struct Hash<T> {
     pub(crate) phantom: std::marker::PhantomData<fn() -> T>,
 }
 
+impl<T> Hash<T> {
+    /// Tags a hash code as being the hash code of a `T`.
+    pub fn from_code(code: HashCode) -> Hash<T> {
+        Hash {
+            code,
+            phantom: PhantomData,
+        }
+    }
+}
+
 impl<T> Clone for Hash<T> {
     fn clone(&self) -> Self {
         Self {
@@ -0,0 +1,544 @@
+//! A persistent `HashLookup`/`HashPut` implementation that stores values in an
+//! append-only log of segment files on disk.
+//!
+//! Each segment is a sequence of records, where a record consists of the
+//! 32-byte hash code of the value, the length of the value as a little-endian
+//! `u32`, and the value itself.  The index from hash codes to record locations
+//! is kept in memory and rebuilt by scanning the segments when the store is
+//! opened.  Since every record carries its own hash code, a torn write at the
+//! end of the last segment is detected during this scan and truncated away.
+//!
+//! Removing a value appends a tombstone record, whose length field is
+//! `TOMBSTONE_LEN` and whose payload is the hash of the removed hash code.
+//! Space used by removed values is only reclaimed by `DiskHashStore::compact`.
+
+use std::collections::{btree_map::Entry, BTreeMap};
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, bail};
+use async_trait::*;
+
+use crate::crypto::{hash_of_bytes, HashCode};
+use crate::hashlookup::{HashLookup, HashPut, HashRemove};
+
+/// The length of a record header: a hash code followed by a `u32` length.
+const RECORD_HEADER_LEN: usize = 32 + 4;
+
+/// The length field of a tombstone record.
+const TOMBSTONE_LEN: u32 = u32::MAX;
+
+/// The file extension of segment files.
+const SEGMENT_EXTENSION: &str = "seg";
+
+/// When to flush written segment data to durable storage.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SyncPolicy {
+    /// Never sync explicitly; rely on the operating system to flush writes.
+    Never,
+    /// Sync after every put or removal that writes to the log.
+    EveryPut,
+    /// Sync after every `n` puts or removals that write to the log.
+    EveryNPuts(u32),
+}
+
+/// Options for opening a `DiskHashStore`.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct DiskStoreOptions {
+    /// When to sync writes to disk.
+    pub sync_policy: SyncPolicy,
+    /// The size after which a new segment file is started.
+    pub max_segment_bytes: u64,
+}
+
+impl Default for DiskStoreOptions {
+    fn default() -> Self {
+        DiskStoreOptions {
+            sync_policy: SyncPolicy::EveryPut,
+            max_segment_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// The location of a stored value in the segment log.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+struct ValueLocation {
+    /// The segment containing the record.
+    segment: u32,
+    /// The offset of the record's header within the segment.
+    offset: u64,
+    /// The length of the value.
+    len: u32,
+}
+
+/// A implementation of `HashLookup` and `HashPut` that persists values to
+/// append-only segment files in a directory.
+pub struct DiskHashStore {
+    /// The directory containing the segment files.
+    dir: PathBuf,
+    /// The options the store was opened with.
+    options: DiskStoreOptions,
+    /// Locations of all stored values.
+    index: BTreeMap<HashCode, ValueLocation>,
+    /// Open read handles, indexed by segment number.
+    readers: Mutex<BTreeMap<u32, File>>,
+    /// The segment currently being appended to.
+    active_segment: u32,
+    /// The write handle of the active segment.
+    active_file: File,
+    /// The length of the active segment.
+    active_len: u64,
+    /// The number of records written since the last sync.
+    unsynced_puts: u32,
+}
+
+/// Gets the path of a segment file.
+fn segment_path(dir: &Path, segment: u32) -> PathBuf {
+    dir.join(format!("{:08}.{}", segment, SEGMENT_EXTENSION))
+}
+
+/// Lists the segment numbers in a directory, in increasing order.
+fn list_segments(dir: &Path) -> Result<Vec<u32>, anyhow::Error> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+        if let Some(segment) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u32>().ok())
+        {
+            segments.push(segment);
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// A record found by scanning a segment.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum ScannedRecord {
+    /// A stored value, with the offset of its record and its length.
+    Value(HashCode, u64, u32),
+    /// A tombstone for a removed value.
+    Tombstone(HashCode),
+}
+
+/// Encodes a record for a value with a given hash code.
+fn encode_record(code: &HashCode, bs: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let len: u32 = bs
+        .len()
+        .try_into()
+        .ok()
+        .filter(|len| *len != TOMBSTONE_LEN)
+        .ok_or_else(|| anyhow!("value too large for disk store"))?;
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + bs.len());
+    record.extend_from_slice(code);
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(bs);
+    Ok(record)
+}
+
+/// Encodes a tombstone record for a removed hash code.
+fn encode_tombstone(code: &HashCode) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + 32);
+    record.extend_from_slice(code);
+    record.extend_from_slice(&TOMBSTONE_LEN.to_le_bytes());
+    record.extend_from_slice(&hash_of_bytes(code));
+    record
+}
+
+/// Scans the records of a segment.  Returns the valid records, along with
+/// the length of the valid prefix of the segment.  Scanning stops at the
+/// first incomplete or corrupt record.
+fn scan_segment(bs: &[u8]) -> (Vec<ScannedRecord>, usize) {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while bs.len() - pos >= RECORD_HEADER_LEN {
+        let code: HashCode = bs[pos..pos + 32].try_into().unwrap();
+        let len = u32::from_le_bytes(bs[pos + 32..pos + RECORD_HEADER_LEN].try_into().unwrap());
+        let start = pos + RECORD_HEADER_LEN;
+        if len == TOMBSTONE_LEN {
+            let end = start + 32;
+            if end > bs.len() || hash_of_bytes(&code) != bs[start..end] {
+                break;
+            }
+            records.push(ScannedRecord::Tombstone(code));
+            pos = end;
+        } else {
+            let end = start + len as usize;
+            if end > bs.len() || hash_of_bytes(&bs[start..end]) != code {
+                break;
+            }
+            records.push(ScannedRecord::Value(code, pos as u64, len));
+            pos = end;
+        }
+    }
+    (records, pos)
+}
+
+impl DiskHashStore {
+    /// Opens a `DiskHashStore` in a given directory, creating the directory if
+    /// it does not exist.  Any torn write at the end of the last segment is
+    /// truncated.
+    pub fn open<P: AsRef<Path>>(
+        dir: P,
+        options: DiskStoreOptions,
+    ) -> Result<DiskHashStore, anyhow::Error> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut segments = list_segments(&dir)?;
+        if segments.is_empty() {
+            segments.push(0);
+        }
+        let last_segment = *segments.last().unwrap();
+        let mut index = BTreeMap::new();
+        let mut active_len = 0;
+        for &segment in &segments {
+            let path = segment_path(&dir, segment);
+            let bs = if path.exists() {
+                fs::read(&path)?
+            } else {
+                Vec::new()
+            };
+            let (records, valid_len) = scan_segment(&bs);
+            if valid_len != bs.len() {
+                if segment != last_segment {
+                    bail!("segment {} is corrupt at offset {}", segment, valid_len);
+                }
+                // torn write at the end of the log
+                let file = OpenOptions::new().write(true).open(&path)?;
+                file.set_len(valid_len as u64)?;
+                file.sync_all()?;
+            }
+            for record in records {
+                match record {
+                    ScannedRecord::Value(code, offset, len) => {
+                        index.insert(
+                            code,
+                            ValueLocation {
+                                segment,
+                                offset,
+                                len,
+                            },
+                        );
+                    }
+                    ScannedRecord::Tombstone(code) => {
+                        index.remove(&code);
+                    }
+                }
+            }
+            active_len = valid_len as u64;
+        }
+        let active_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&dir, last_segment))?;
+        Ok(DiskHashStore {
+            dir,
+            options,
+            index,
+            readers: Mutex::new(BTreeMap::new()),
+            active_segment: last_segment,
+            active_file,
+            active_len,
+            unsynced_puts: 0,
+        })
+    }
+
+    /// The number of values stored.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Whether no values are stored.
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Whether a value with the given hash code is stored.
+    pub fn contains(&self, hash: &HashCode) -> bool {
+        self.index.contains_key(hash)
+    }
+
+    /// Flushes all written data in the active segment to disk.
+    pub fn sync(&mut self) -> Result<(), anyhow::Error> {
+        self.active_file.sync_data()?;
+        self.unsynced_puts = 0;
+        Ok(())
+    }
+
+    /// Starts a new segment, syncing the old one first.
+    fn start_new_segment(&mut self) -> Result<(), anyhow::Error> {
+        self.sync()?;
+        self.active_segment += 1;
+        self.active_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&self.dir, self.active_segment))?;
+        self.active_len = 0;
+        Ok(())
+    }
+
+    /// Appends an encoded record to the log, starting a new segment if
+    /// needed and syncing according to the sync policy.  Returns the
+    /// segment and offset the record was written at.
+    fn append_record(&mut self, record: &[u8]) -> Result<(u32, u64), anyhow::Error> {
+        if self.active_len > 0
+            && self.active_len + record.len() as u64 > self.options.max_segment_bytes
+        {
+            self.start_new_segment()?;
+        }
+        self.active_file.write_all(record)?;
+        let written_at = (self.active_segment, self.active_len);
+        self.active_len += record.len() as u64;
+        self.unsynced_puts += 1;
+        match self.options.sync_policy {
+            SyncPolicy::Never => {}
+            SyncPolicy::EveryPut => self.sync()?,
+            SyncPolicy::EveryNPuts(n) => {
+                if self.unsynced_puts >= n {
+                    self.sync()?;
+                }
+            }
+        }
+        Ok(written_at)
+    }
+
+    /// Rewrites the log so that it contains only the currently stored values,
+    /// reclaiming the space of removed values.  The new segments are synced
+    /// before the old ones are deleted, so a crash during compaction loses
+    /// nothing.
+    pub fn compact(&mut self) -> Result<(), anyhow::Error> {
+        let old_segments = list_segments(&self.dir)?;
+        self.start_new_segment()?;
+        let mut new_index = BTreeMap::new();
+        for (code, loc) in self.index.clone() {
+            let bs = self.read_value(loc)?;
+            let (segment, offset) = self.append_record(&encode_record(&code, &bs)?)?;
+            new_index.insert(
+                code,
+                ValueLocation {
+                    segment,
+                    offset,
+                    len: loc.len,
+                },
+            );
+        }
+        self.sync()?;
+        self.index = new_index;
+        self.readers
+            .lock()
+            .map_err(|_| anyhow!("disk store reader lock poisoned"))?
+            .clear();
+        for segment in old_segments {
+            fs::remove_file(segment_path(&self.dir, segment))?;
+        }
+        Ok(())
+    }
+
+    /// Reads the value at a given location.
+    fn read_value(&self, loc: ValueLocation) -> Result<Vec<u8>, anyhow::Error> {
+        let mut readers = self
+            .readers
+            .lock()
+            .map_err(|_| anyhow!("disk store reader lock poisoned"))?;
+        let file = match readers.entry(loc.segment) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(File::open(segment_path(&self.dir, loc.segment))?),
+        };
+        file.seek(SeekFrom::Start(loc.offset + RECORD_HEADER_LEN as u64))?;
+        let mut bs = vec![0; loc.len as usize];
+        file.read_exact(&mut bs)?;
+        Ok(bs)
+    }
+}
+
+#[async_trait]
+impl HashLookup for DiskHashStore {
+    async fn lookup_bytes(&self, hash: HashCode) -> Result<Vec<u8>, anyhow::Error> {
+        match self.index.get(&hash) {
+            None => bail!("not found"),
+            Some(loc) => self.read_value(*loc),
+        }
+    }
+}
+
+#[async_trait]
+impl HashPut for DiskHashStore {
+    async fn put_bytes(&mut self, bs: &[u8]) -> Result<HashCode, anyhow::Error> {
+        let code = hash_of_bytes(bs);
+        if self.index.contains_key(&code) {
+            return Ok(code);
+        }
+        let (segment, offset) = self.append_record(&encode_record(&code, bs)?)?;
+        self.index.insert(
+            code,
+            ValueLocation {
+                segment,
+                offset,
+                len: bs.len() as u32,
+            },
+        );
+        Ok(code)
+    }
+}
+
+#[async_trait]
+impl HashRemove for DiskHashStore {
+    async fn stored_codes(&self) -> Result<Vec<HashCode>, anyhow::Error> {
+        Ok(self.index.keys().copied().collect())
+    }
+
+    async fn remove_bytes(&mut self, hash: HashCode) -> Result<Option<u64>, anyhow::Error> {
+        match self.index.get(&hash) {
+            None => Ok(None),
+            Some(loc) => {
+                let len = u64::from(loc.len);
+                self.append_record(&encode_tombstone(&hash))?;
+                self.index.remove(&hash);
+                Ok(Some(len))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdata::{AccountInit, MainOptions};
+    use crate::construction::genesis_block_body;
+    use crate::crypto::gen_private_key;
+    use crate::verification::verify_valid_main_block_body;
+
+    fn temp_store_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "mercatoria-disk-store-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn persists_across_reopen() {
+        let dir = temp_store_dir("reopen");
+        let opts = DiskStoreOptions {
+            sync_policy: SyncPolicy::EveryNPuts(2),
+            max_segment_bytes: 64,
+        };
+        let values: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 20 + i as usize]).collect();
+        let mut codes = Vec::new();
+        {
+            let mut store = DiskHashStore::open(&dir, opts.clone()).unwrap();
+            for v in &values {
+                codes.push(smol::block_on(store.put_bytes(v)).unwrap());
+            }
+            store.sync().unwrap();
+        }
+        assert!(list_segments(&dir).unwrap().len() > 1);
+        let store = DiskHashStore::open(&dir, opts).unwrap();
+        assert_eq!(values.len(), store.len());
+        for (code, v) in codes.iter().zip(values.iter()) {
+            assert_eq!(v, &smol::block_on(store.lookup_bytes(*code)).unwrap());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncates_torn_write() {
+        let dir = temp_store_dir("torn");
+        let code = {
+            let mut store = DiskHashStore::open(&dir, DiskStoreOptions::default()).unwrap();
+            smol::block_on(store.put_bytes(b"complete")).unwrap()
+        };
+        let path = segment_path(&dir, 0);
+        let complete_len = fs::metadata(&path).unwrap().len();
+        let mut torn = encode_record(&hash_of_bytes(b"torn write"), b"torn write").unwrap();
+        torn.truncate(torn.len() - 3);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&torn)
+            .unwrap();
+        let mut store = DiskHashStore::open(&dir, DiskStoreOptions::default()).unwrap();
+        assert_eq!(complete_len, fs::metadata(&path).unwrap().len());
+        assert_eq!(1, store.len());
+        assert!(store.contains(&code));
+        let new_code = smol::block_on(store.put_bytes(b"after recovery")).unwrap();
+        let store = DiskHashStore::open(&dir, DiskStoreOptions::default()).unwrap();
+        assert_eq!(
+            b"after recovery".to_vec(),
+            smol::block_on(store.lookup_bytes(new_code)).unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remove_and_compact() {
+        let dir = temp_store_dir("compact");
+        let mut store = DiskHashStore::open(&dir, DiskStoreOptions::default()).unwrap();
+        let kept = smol::block_on(store.put_bytes(b"kept")).unwrap();
+        let removed = smol::block_on(store.put_bytes(b"removed")).unwrap();
+        assert_eq!(
+            Some(7),
+            smol::block_on(store.remove_bytes(removed)).unwrap()
+        );
+        assert_eq!(None, smol::block_on(store.remove_bytes(removed)).unwrap());
+        let reopened = DiskHashStore::open(&dir, DiskStoreOptions::default()).unwrap();
+        assert!(reopened.contains(&kept));
+        assert!(!reopened.contains(&removed));
+        store.compact().unwrap();
+        assert_eq!(vec![1], list_segments(&dir).unwrap());
+        let reopened = DiskHashStore::open(&dir, DiskStoreOptions::default()).unwrap();
+        assert_eq!(1, reopened.len());
+        assert_eq!(
+            b"kept".to_vec(),
+            smol::block_on(reopened.lookup_bytes(kept)).unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn genesis_block_in_disk_store() {
+        let dir = temp_store_dir("genesis");
+        let key = gen_private_key();
+        let inits = vec![AccountInit {
+            public_key: key.public,
+            balance: 100,
+            stake: 10,
+        }];
+        let opts = MainOptions {
+            gas_cost: 1,
+            gas_limit: u128::MAX,
+            timestamp_period_ms: 10,
+            main_block_signers: 1,
+            main_block_signatures_required: 1,
+            random_seed_period: 10,
+            quorum_period: 90,
+            max_quorum_depth: 16,
+            quorum_sizes_thresholds: vec![(1, 1)],
+            unbonding_period: 20,
+            slash_percent: 50,
+            slash_reporter_percent: 20,
+            options_vote_percent: 50,
+            block_reward: 0,
+            block_reward_halving_period: 0,
+            miner_reward_percent: 50,
+        };
+        let main = {
+            let mut store = DiskHashStore::open(&dir, DiskStoreOptions::default()).unwrap();
+            smol::block_on(genesis_block_body(&mut store, &inits, 0, opts)).unwrap()
+        };
+        let store = DiskHashStore::open(&dir, DiskStoreOptions::default()).unwrap();
+        smol::block_on(verify_valid_main_block_body(&store, &main)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+//! Mark-and-sweep garbage collection of hash-addressed blockchain data.
+//!
+//! Each new block creates fresh copies of the `QuorumNode`s and `DataNode`s
+//! along modified paths, so the nodes they supersede are only reachable
+//! through older blocks.  Collecting garbage relative to a set of recent
+//! blocks deletes everything that is not reachable from those blocks, except
+//! for what the next block still looks up in their ancestors: the main blocks
+//! themselves (for rewards, random seeds and `block_with_version`), and the
+//! full state of the blocks that quorums, miners and signers are selected
+//! from and votes are tallied in.  Queries reaching further back, such as the
+//! account history, fail with a lookup error once that state is collected.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::bail;
+
+use crate::blockdata::MainBlock;
+use crate::crypto::{Hash, HashCode};
+use crate::hashlookup::{HashLookup, HashRemove};
+use crate::object_graph::{reachable_objects, ObjectRef};
+
+/// Statistics about a garbage collection.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct GcStats {
+    /// The number of objects that were kept.
+    pub objects_kept: u64,
+    /// The number of objects that were removed.
+    pub objects_reclaimed: u64,
+    /// The total size of the removed objects, in bytes.
+    pub bytes_reclaimed: u64,
+}
+
+/// Gets the hashes of the last `n` main blocks ending at `head`, newest first.
+/// Fewer blocks are returned if the chain is shorter than `n`.
+pub async fn last_main_blocks<HL: HashLookup>(
+    hl: &HL,
+    head: Hash<MainBlock>,
+    n: usize,
+) -> Result<Vec<Hash<MainBlock>>, anyhow::Error> {
+    let mut blocks = Vec::new();
+    let mut next = Some(head);
+    while let Some(hash) = next {
+        if blocks.len() >= n {
+            break;
+        }
+        blocks.push(hash);
+        next = hl.lookup(hash).await?.block.body.prev;
+    }
+    Ok(blocks)
+}
+
+/// Gets the main blocks that the blocks after the given roots may look up,
+/// which are every ancestor of a root.  Each block maps to its options and
+/// whether its full state is kept: quorums for the block after a root are
+/// selected by a block up to two quorum periods back, which selects accounts
+/// by the stake in a block up to two random seed periods further back.
+///
+/// Roots are walked starting with the one keeping the oldest full state, so
+/// a walk can stop at the first block an earlier walk already reached: that
+/// walk kept the full state of every ancestor this one would.
+async fn kept_main_blocks<HL: HashLookup>(
+    hl: &HL,
+    roots: &[Hash<MainBlock>],
+) -> Result<BTreeMap<HashCode, (HashCode, bool)>, anyhow::Error> {
+    let mut walks = Vec::new();
+    for root in roots {
+        let main = hl.lookup(*root).await?;
+        let opts = hl.lookup(main.block.body.options).await?;
+        let periods = u64::from(opts.quorum_period) + u64::from(opts.random_seed_period);
+        let oldest_state = (main.block.body.version + 1).saturating_sub(2 * periods);
+        walks.push((oldest_state, *root));
+    }
+    walks.sort_by_key(|(oldest_state, _)| *oldest_state);
+    let mut kept: BTreeMap<HashCode, (HashCode, bool)> = BTreeMap::new();
+    for (oldest_state, root) in walks {
+        let mut next = Some(root);
+        while let Some(hash) = next {
+            if kept.contains_key(&hash.code) {
+                break;
+            }
+            let body = hl.lookup(hash).await?.block.body;
+            kept.insert(hash.code, (body.options.code, body.version >= oldest_state));
+            next = body.prev;
+        }
+    }
+    Ok(kept)
+}
+
+/// Removes every object that is not needed by one of the given main blocks or
+/// the blocks after them.  Blocks older than the state the next blocks select
+/// accounts from are kept without the parts of their state that the roots no
+/// longer reference.  For a `DiskHashStore`, the disk space is only freed
+/// once it is compacted.
+pub async fn collect_garbage<S: HashLookup + HashRemove>(
+    store: &mut S,
+    roots: &[Hash<MainBlock>],
+) -> Result<GcStats, anyhow::Error> {
+    if roots.is_empty() {
+        bail!("garbage collection requires at least one root");
+    }
+    let mut root_refs = Vec::new();
+    let mut live = BTreeSet::new();
+    for (code, (options, full)) in kept_main_blocks(store, roots).await? {
+        if full {
+            root_refs.push(ObjectRef::main_block(Hash::from_code(code)));
+        } else {
+            live.insert(code);
+            live.insert(options);
+        }
+    }
+    live.extend(reachable_objects(store, &root_refs).await?);
+    let mut stats = GcStats::default();
+    for code in store.stored_codes().await? {
+        if live.contains(&code) {
+            stats.objects_kept += 1;
+        } else if let Some(len) = store.remove_bytes(code).await? {
+            stats.objects_reclaimed += 1;
+            stats.bytes_reclaimed += len;
+        }
+    }
+    Ok(stats)
+}
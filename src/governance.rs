@@ -0,0 +1,110 @@
+//! Governance of the `MainOptions`.
+//!
+//! Accounts propose new options with the `propose_options` command and vote
+//! for proposals with the `vote` command.  Votes are tallied in the main block
+//! one `quorum_period` before a proposal's activation version, each weighted
+//! by the voter's stake in that block.  If a proposal gets votes from at least
+//! `options_vote_percent` of the total stake, its options take effect at its
+//! activation version.  Of several such proposals, the one with the most
+//! votes wins, with ties going to the lowest hash code.
+//!
+//! Once a proposal's activation version has passed, the proposer and voters
+//! may delete it and their votes with the `prune_proposal` and `prune_vote`
+//! commands.
+
+use std::collections::BTreeMap;
+
+use crate::account_transform::{field_proposal, percent_of};
+use crate::blockdata::{MainBlockBody, MainOptions, ProposalInfo};
+use crate::crypto::{hash, Hash, HashCode};
+use crate::hashlookup::{HashLookup, HashPut};
+use crate::queries::{block_with_version, lookup_account, lookup_data_in_account};
+
+/// A proposal along with the stake voting for it.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct ProposalTally {
+    /// The proposal.
+    pub proposal: ProposalInfo,
+    /// The total stake of the accounts voting for the proposal.
+    pub votes: u128,
+}
+
+/// Tallies the votes in a main block for the proposals activating at a given
+/// version, keyed by the proposals' hash codes.  The stake voting for each
+/// proposal is summed up the quorum tree, so this only looks up the proposals
+/// that have votes.
+pub async fn tally_votes<HL: HashLookup>(
+    hl: &HL,
+    main: &MainBlockBody,
+    activation_version: u64,
+) -> Result<BTreeMap<HashCode, ProposalTally>, anyhow::Error> {
+    let top = hl.lookup(main.tree).await?;
+    let mut tallies = BTreeMap::new();
+    for ((proposer, code), votes) in top.body.stats.votes {
+        // accounts without stake have no votes
+        if votes == 0 {
+            continue;
+        }
+        let proposer_node = match lookup_account(hl, main, proposer).await? {
+            None => continue,
+            Some(node) => node,
+        };
+        let field = field_proposal(Hash::from_code(code));
+        let proposal: ProposalInfo =
+            match lookup_data_in_account(hl, &proposer_node, &field.path).await? {
+                None => continue,
+                Some(bs) => rmp_serde::from_read(bs.as_slice())?,
+            };
+        if proposal.activation_version == activation_version {
+            tallies.insert(code, ProposalTally { proposal, votes });
+        }
+    }
+    Ok(tallies)
+}
+
+/// Gets the proposal that takes effect in the block following a given block,
+/// if any.
+pub async fn activated_proposal<HL: HashLookup>(
+    hl: &HL,
+    prev: &MainBlockBody,
+) -> Result<Option<ProposalInfo>, anyhow::Error> {
+    let opts = hl.lookup(prev.options).await?;
+    let period = u64::from(opts.quorum_period);
+    let version = prev.version + 1;
+    if version.checked_rem(period) != Some(0) {
+        return Ok(None);
+    }
+    let tally_main = block_with_version(hl, prev, version - period).await?;
+    let total_stake = hl.lookup(tally_main.tree).await?.body.stats.stake;
+    let needed = percent_of(total_stake, opts.options_vote_percent);
+    let mut best: Option<ProposalTally> = None;
+    for tally in tally_votes(hl, &tally_main, version).await?.into_values() {
+        if tally.votes >= needed && best.iter().all(|best| tally.votes > best.votes) {
+            best = Some(tally);
+        }
+    }
+    Ok(best.map(|tally| tally.proposal))
+}
+
+/// Gets the hash of the options of the block following a given block.
+pub async fn next_options<HL: HashLookup>(
+    hl: &HL,
+    prev: &MainBlockBody,
+) -> Result<Hash<MainOptions>, anyhow::Error> {
+    match activated_proposal(hl, prev).await? {
+        None => Ok(prev.options),
+        Some(proposal) => Ok(hash(&proposal.options)),
+    }
+}
+
+/// Like `next_options`, but also stores the options if they change, so they
+/// can be looked up from the new block.
+pub async fn put_next_options<HL: HashLookup + HashPut>(
+    hl: &mut HL,
+    prev: &MainBlockBody,
+) -> Result<Hash<MainOptions>, anyhow::Error> {
+    match activated_proposal(hl, prev).await? {
+        None => Ok(prev.options),
+        Some(proposal) => hl.put(&proposal.options).await,
+    }
+}
@@ -1,11 +1,16 @@
 //! Traits for indexing serializable values by their hash codes.
 
 use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
 
 use crate::crypto::{hash_of_bytes, Hash, HashCode};
+use crate::hex_path::bytes_to_path;
+use crate::lookup_cache::Lru;
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use async_trait::*;
+use futures::future::try_join_all;
 use serde::{de::DeserializeOwned, Serialize};
 
 /// A trait supporting looking up values by their hash code.
@@ -20,6 +25,80 @@ pub trait HashLookup: Send + Sync {
             self.lookup_bytes(hash.code).await?.as_slice(),
         )?)
     }
+
+    /// Looks up a serializable value by its hash code, like `lookup`.  The
+    /// bounds allow implementations to answer from a cache of decoded
+    /// values, so queries that look up the same nodes repeatedly should use
+    /// this method.
+    async fn lookup_cached<T>(&self, hash: Hash<T>) -> Result<T, anyhow::Error>
+    where
+        T: DeserializeOwned + Clone + Send + Sync + 'static,
+    {
+        self.lookup(hash).await
+    }
+
+    /// Looks up several byte vectors by their hash codes, returning them in
+    /// the same order.  The default implementation looks them up one at a
+    /// time; implementations may override it to fetch them concurrently.
+    async fn lookup_bytes_many(&self, hashes: &[HashCode]) -> Result<Vec<Vec<u8>>, anyhow::Error> {
+        let mut values = Vec::with_capacity(hashes.len());
+        for hash in hashes {
+            values.push(self.lookup_bytes(*hash).await?);
+        }
+        Ok(values)
+    }
+
+    /// Looks up several serializable values by their hash codes, returning
+    /// them in the same order.
+    async fn lookup_many<T>(&self, hashes: &[Hash<T>]) -> Result<Vec<T>, anyhow::Error>
+    where
+        T: DeserializeOwned + Send,
+    {
+        let codes: Vec<HashCode> = hashes.iter().map(|h| h.code).collect();
+        let mut values = Vec::with_capacity(codes.len());
+        let fetched = check_batch_len(&codes, self.lookup_bytes_many(&codes).await?)?;
+        for bs in fetched {
+            values.push(rmp_serde::from_read(bs.as_slice())?);
+        }
+        Ok(values)
+    }
+
+    /// Looks up several serializable values by their hash codes, like
+    /// `lookup_many`, allowing implementations to answer from a cache of
+    /// decoded values as in `lookup_cached`.
+    async fn lookup_cached_many<T>(&self, hashes: &[Hash<T>]) -> Result<Vec<T>, anyhow::Error>
+    where
+        T: DeserializeOwned + Clone + Send + Sync + 'static,
+    {
+        self.lookup_many(hashes).await
+    }
+}
+
+/// Checks that a batch lookup returned one byte vector per hash code, so
+/// that a misbehaving `HashLookup` causes an error rather than a panic.
+pub(crate) fn check_batch_len(
+    hashes: &[HashCode],
+    values: Vec<Vec<u8>>,
+) -> Result<Vec<Vec<u8>>, anyhow::Error> {
+    if values.len() != hashes.len() {
+        bail!(
+            "batch lookup returned {} values for {} hash codes",
+            values.len(),
+            hashes.len()
+        );
+    }
+    Ok(values)
+}
+
+/// Looks up several byte vectors concurrently, returning them in the same
+/// order as the hash codes.  This is suitable for overriding
+/// `HashLookup::lookup_bytes_many` when individual lookups are slow, e.g.
+/// because they go over the network.
+pub async fn lookup_bytes_concurrently<HL: HashLookup + ?Sized>(
+    hl: &HL,
+    hashes: &[HashCode],
+) -> Result<Vec<Vec<u8>>, anyhow::Error> {
+    try_join_all(hashes.iter().map(|hash| hl.lookup_bytes(*hash))).await
 }
 
 /// A trait supporting inserting values indexed by their hash code.
@@ -40,6 +119,17 @@ pub trait HashPut: Send + Sync {
     }
 }
 
+/// A trait supporting removing values indexed by their hash code.
+#[async_trait]
+pub trait HashRemove: Send + Sync {
+    /// Lists the hash codes of all stored values.
+    async fn stored_codes(&self) -> Result<Vec<HashCode>, anyhow::Error>;
+
+    /// Removes a byte vector by its hash code, returning its length, or `None`
+    /// if it was not stored.
+    async fn remove_bytes(&mut self, hash: HashCode) -> Result<Option<u64>, anyhow::Error>;
+}
+
 /// A implementation of `HashLookup` and `HashPut` that stores a map.
 pub struct MapHashLookup {
     map: BTreeMap<HashCode, Vec<u8>>,
@@ -73,11 +163,57 @@ impl HashPut for MapHashLookup {
     }
 }
 
+#[async_trait]
+impl HashRemove for MapHashLookup {
+    async fn stored_codes(&self) -> Result<Vec<HashCode>, anyhow::Error> {
+        Ok(self.map.keys().copied().collect())
+    }
+
+    async fn remove_bytes(&mut self, hash: HashCode) -> Result<Option<u64>, anyhow::Error> {
+        Ok(self.map.remove(&hash).map(|bs| bs.len() as u64))
+    }
+}
+
+/// A point in the history of a `HashPutOfHashLookup` that it can be rolled
+/// back to.  Each savepoint taken by an overlay has a distinct id, so a
+/// savepoint that was released or rolled back can't be confused with a newer
+/// one.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Savepoint(u64);
+
+/// Values put into a `HashPutOfHashLookup` that have not yet been written to
+/// a persistent store.  This does not borrow the underlying `HashLookup`, so
+/// it can be committed into it.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct PendingPuts {
+    /// The values to write, indexed by hash code.
+    pub values: BTreeMap<HashCode, Vec<u8>>,
+}
+
+impl PendingPuts {
+    /// Writes the values into a `HashPut`, returning how many were written.
+    pub async fn commit<HP: HashPut>(self, target: &mut HP) -> Result<usize, anyhow::Error> {
+        let count = self.values.len();
+        for bs in self.values.values() {
+            target.put_bytes(bs).await?;
+        }
+        Ok(count)
+    }
+}
+
 /// A `HashLookup + HashPut` implementation made of an underlying
-/// `HashLookup` and a cache of put values.
+/// `HashLookup` and a cache of put values.  It acts as a transactional overlay:
+/// puts may be rolled back to nested savepoints, and the remaining puts may be
+/// committed into a persistent `HashPut`.  Overlays may themselves be layered
+/// over overlays for speculative execution.
 pub struct HashPutOfHashLookup<'a, HL: HashLookup> {
     pub hl: &'a HL,
-    pub put_values: BTreeMap<HashCode, Vec<u8>>,
+    put_values: BTreeMap<HashCode, Vec<u8>>,
+    /// For each open savepoint, its id and the hash codes first put after it
+    /// was taken.
+    savepoints: Vec<(u64, Vec<HashCode>)>,
+    /// The id of the next savepoint.
+    next_savepoint: u64,
 }
 
 impl<'a, HL: HashLookup> HashPutOfHashLookup<'a, HL> {
@@ -86,6 +222,80 @@ impl<'a, HL: HashLookup> HashPutOfHashLookup<'a, HL> {
         HashPutOfHashLookup {
             hl,
             put_values: BTreeMap::new(),
+            savepoints: Vec::new(),
+            next_savepoint: 0,
+        }
+    }
+
+    /// Takes a savepoint.  Savepoints nest: rolling back to or releasing a
+    /// savepoint also rolls back or releases every savepoint taken after it.
+    pub fn savepoint(&mut self) -> Savepoint {
+        let id = self.next_savepoint;
+        self.next_savepoint += 1;
+        self.savepoints.push((id, Vec::new()));
+        Savepoint(id)
+    }
+
+    /// Gets the position of an open savepoint.
+    fn savepoint_index(&self, sp: Savepoint) -> Result<usize, anyhow::Error> {
+        match self.savepoints.iter().position(|(id, _)| *id == sp.0) {
+            None => bail!("savepoint was already released or rolled back"),
+            Some(ix) => Ok(ix),
+        }
+    }
+
+    /// Discards all values put since a savepoint was taken, and closes it.
+    pub fn rollback_to(&mut self, sp: Savepoint) -> Result<(), anyhow::Error> {
+        let ix = self.savepoint_index(sp)?;
+        for (_, codes) in self.savepoints.drain(ix..) {
+            for code in codes {
+                self.put_values.remove(&code);
+            }
+        }
+        Ok(())
+    }
+
+    /// Closes a savepoint, keeping the values put since it was taken.
+    pub fn release(&mut self, sp: Savepoint) -> Result<(), anyhow::Error> {
+        let ix = self.savepoint_index(sp)?;
+        let released: Vec<HashCode> = self
+            .savepoints
+            .drain(ix..)
+            .flat_map(|(_, codes)| codes)
+            .collect();
+        if let Some((_, codes)) = self.savepoints.last_mut() {
+            codes.extend(released);
+        }
+        Ok(())
+    }
+
+    /// Gets the values put so far and not rolled back, by hash code.
+    pub fn pending(&self) -> &BTreeMap<HashCode, Vec<u8>> {
+        &self.put_values
+    }
+
+    /// Discards all put values and savepoints.
+    pub fn rollback(&mut self) {
+        self.put_values.clear();
+        self.savepoints.clear();
+    }
+
+    /// Writes all put values into a `HashPut` (which must not be the
+    /// underlying `HashLookup`; see `into_pending`), then clears them.
+    /// Returns how many values were written.
+    pub async fn commit<HP: HashPut>(&mut self, target: &mut HP) -> Result<usize, anyhow::Error> {
+        let pending = PendingPuts {
+            values: std::mem::take(&mut self.put_values),
+        };
+        self.savepoints.clear();
+        pending.commit(target).await
+    }
+
+    /// Converts the put values into `PendingPuts`, releasing the borrow of
+    /// the underlying `HashLookup` so that they can be committed into it.
+    pub fn into_pending(self) -> PendingPuts {
+        PendingPuts {
+            values: self.put_values,
         }
     }
 }
@@ -98,13 +308,363 @@ impl<'a, HL: HashLookup> HashLookup for HashPutOfHashLookup<'a, HL> {
             None => self.hl.lookup_bytes(hash).await,
         }
     }
+
+    async fn lookup_bytes_many(&self, hashes: &[HashCode]) -> Result<Vec<Vec<u8>>, anyhow::Error> {
+        let missing: Vec<HashCode> = hashes
+            .iter()
+            .filter(|hash| !self.put_values.contains_key(*hash))
+            .copied()
+            .collect();
+        let fetched = check_batch_len(&missing, self.hl.lookup_bytes_many(&missing).await?)?;
+        let mut fetched = fetched.into_iter();
+        let mut values = Vec::with_capacity(hashes.len());
+        for hash in hashes {
+            match self.put_values.get(hash) {
+                Some(x) => values.push(x.clone()),
+                None => values.push(fetched.next().unwrap()),
+            }
+        }
+        Ok(values)
+    }
 }
 
 #[async_trait]
 impl<'a, HL: HashLookup> HashPut for HashPutOfHashLookup<'a, HL> {
     async fn put_bytes(&mut self, bs: &[u8]) -> Result<HashCode, anyhow::Error> {
         let code = hash_of_bytes(&bs);
-        self.put_values.insert(code, bs.to_vec());
+        if self.put_values.insert(code, bs.to_vec()).is_none() {
+            if let Some((_, codes)) = self.savepoints.last_mut() {
+                codes.push(code);
+            }
+        }
         Ok(code)
     }
 }
+
+/// An error indicating that bytes looked up by a hash code have a different
+/// hash code.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct HashMismatch {
+    /// The hash code that was looked up.
+    pub expected: HashCode,
+    /// The hash code of the bytes that were returned.
+    pub actual: HashCode,
+}
+
+impl fmt::Display for HashMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "hash mismatch: expected {}, got {}",
+            bytes_to_path(&self.expected),
+            bytes_to_path(&self.actual)
+        )
+    }
+}
+
+impl std::error::Error for HashMismatch {}
+
+/// A `HashLookup` that rehashes every byte vector returned by an underlying
+/// `HashLookup`, failing with a `HashMismatch` error if it does not have the
+/// requested hash code.  Verified byte vectors may optionally be cached so
+/// that frequently used values are not rehashed.
+pub struct VerifyingHashLookup<'a, HL: HashLookup> {
+    /// The underlying, untrusted `HashLookup`.
+    pub hl: &'a HL,
+    /// Verified byte vectors, each counting as an entry of size 1.
+    cache: Option<Mutex<Lru<HashCode, Vec<u8>>>>,
+}
+
+impl<'a, HL: HashLookup> VerifyingHashLookup<'a, HL> {
+    /// Creates a new `VerifyingHashLookup` without a cache.
+    pub fn new(hl: &'a HL) -> VerifyingHashLookup<'a, HL> {
+        VerifyingHashLookup { hl, cache: None }
+    }
+
+    /// Creates a new `VerifyingHashLookup` caching up to `capacity` of the
+    /// most recently used verified byte vectors.
+    pub fn with_cache(hl: &'a HL, capacity: usize) -> VerifyingHashLookup<'a, HL> {
+        VerifyingHashLookup {
+            hl,
+            cache: Some(Mutex::new(Lru::new(capacity))),
+        }
+    }
+}
+
+impl<'a, HL: HashLookup> VerifyingHashLookup<'a, HL> {
+    /// Gets a previously verified byte vector from the cache, if any.
+    fn get_cached(&self, hash: &HashCode) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        match &self.cache {
+            None => Ok(None),
+            Some(cache) => Ok(cache
+                .lock()
+                .map_err(|_| anyhow!("verified cache lock poisoned"))?
+                .get(hash)),
+        }
+    }
+
+    /// Checks that a fetched byte vector has the expected hash code, caching
+    /// it if so.
+    fn verify_and_cache(&self, hash: HashCode, bs: Vec<u8>) -> Result<Vec<u8>, anyhow::Error> {
+        let actual = hash_of_bytes(&bs);
+        if actual != hash {
+            return Err(HashMismatch {
+                expected: hash,
+                actual,
+            }
+            .into());
+        }
+        if let Some(cache) = &self.cache {
+            cache
+                .lock()
+                .map_err(|_| anyhow!("verified cache lock poisoned"))?
+                .insert(hash, bs.clone(), 1);
+        }
+        Ok(bs)
+    }
+}
+
+#[async_trait]
+impl<'a, HL: HashLookup> HashLookup for VerifyingHashLookup<'a, HL> {
+    async fn lookup_bytes(&self, hash: HashCode) -> Result<Vec<u8>, anyhow::Error> {
+        if let Some(bs) = self.get_cached(&hash)? {
+            return Ok(bs);
+        }
+        let bs = self.hl.lookup_bytes(hash).await?;
+        self.verify_and_cache(hash, bs)
+    }
+
+    async fn lookup_bytes_many(&self, hashes: &[HashCode]) -> Result<Vec<Vec<u8>>, anyhow::Error> {
+        let mut values = Vec::with_capacity(hashes.len());
+        let mut missing = Vec::new();
+        for hash in hashes {
+            let cached = self.get_cached(hash)?;
+            if cached.is_none() {
+                missing.push(*hash);
+            }
+            values.push(cached);
+        }
+        let fetched = check_batch_len(&missing, self.hl.lookup_bytes_many(&missing).await?)?;
+        let mut fetched = missing.iter().zip(fetched);
+        values
+            .into_iter()
+            .map(|cached| match cached {
+                Some(bs) => Ok(bs),
+                None => {
+                    let (hash, bs) = fetched.next().unwrap();
+                    self.verify_and_cache(*hash, bs)
+                }
+            })
+            .collect()
+    }
+}
+
+/// A `HashLookup` that records every byte vector it looks up in an
+/// underlying `HashLookup`.  This is used to collect the objects some
+/// computation depends on, so that they can be sent to a party that does not
+/// have the full store (such as a light client).
+pub struct RecordingHashLookup<'a, HL: HashLookup> {
+    /// The underlying `HashLookup`.
+    pub hl: &'a HL,
+    recorded: Mutex<BTreeMap<HashCode, Vec<u8>>>,
+}
+
+impl<'a, HL: HashLookup> RecordingHashLookup<'a, HL> {
+    /// Creates a new `RecordingHashLookup` that has recorded nothing.
+    pub fn new(hl: &'a HL) -> RecordingHashLookup<'a, HL> {
+        RecordingHashLookup {
+            hl,
+            recorded: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn record(&self, hash: HashCode, bs: &[u8]) -> Result<(), anyhow::Error> {
+        self.recorded
+            .lock()
+            .map_err(|_| anyhow!("recording lock poisoned"))?
+            .entry(hash)
+            .or_insert_with(|| bs.to_vec());
+        Ok(())
+    }
+
+    /// Gets the byte vectors looked up so far, ordered by hash code.
+    pub fn into_recorded(self) -> Result<Vec<Vec<u8>>, anyhow::Error> {
+        Ok(self
+            .recorded
+            .into_inner()
+            .map_err(|_| anyhow!("recording lock poisoned"))?
+            .into_values()
+            .collect())
+    }
+}
+
+#[async_trait]
+impl<'a, HL: HashLookup> HashLookup for RecordingHashLookup<'a, HL> {
+    async fn lookup_bytes(&self, hash: HashCode) -> Result<Vec<u8>, anyhow::Error> {
+        let bs = self.hl.lookup_bytes(hash).await?;
+        self.record(hash, &bs)?;
+        Ok(bs)
+    }
+
+    async fn lookup_bytes_many(&self, hashes: &[HashCode]) -> Result<Vec<Vec<u8>>, anyhow::Error> {
+        let values = check_batch_len(hashes, self.hl.lookup_bytes_many(hashes).await?)?;
+        for (hash, bs) in hashes.iter().zip(values.iter()) {
+            self.record(*hash, bs)?;
+        }
+        Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lookup_cache::{CacheOptions, CachingHashLookup};
+
+    /// A `HashLookup` whose batch lookups drop the last value.
+    struct ShortBatches(MapHashLookup);
+
+    #[async_trait]
+    impl HashLookup for ShortBatches {
+        async fn lookup_bytes(&self, hash: HashCode) -> Result<Vec<u8>, anyhow::Error> {
+            self.0.lookup_bytes(hash).await
+        }
+
+        async fn lookup_bytes_many(
+            &self,
+            hashes: &[HashCode],
+        ) -> Result<Vec<Vec<u8>>, anyhow::Error> {
+            let mut values = self.0.lookup_bytes_many(hashes).await?;
+            values.pop();
+            Ok(values)
+        }
+    }
+
+    #[test]
+    fn batch_lookups_keep_order() {
+        let mut map = MapHashLookup::new();
+        let a = smol::block_on(map.put(&1u32)).unwrap();
+        let b = smol::block_on(map.put(&2u32)).unwrap();
+        let missing = hash_of_bytes(b"missing");
+        assert_eq!(
+            vec![2, 1, 2],
+            smol::block_on(map.lookup_many(&[b, a, b])).unwrap()
+        );
+        let codes = [a.code, b.code, a.code];
+        let expected = smol::block_on(map.lookup_bytes_many(&codes)).unwrap();
+        assert_eq!(
+            vec![
+                rmp_serde::to_vec_named(&1u32).unwrap(),
+                rmp_serde::to_vec_named(&2u32).unwrap(),
+                rmp_serde::to_vec_named(&1u32).unwrap(),
+            ],
+            expected
+        );
+        assert_eq!(
+            expected,
+            smol::block_on(lookup_bytes_concurrently(&map, &codes)).unwrap()
+        );
+        assert!(smol::block_on(lookup_bytes_concurrently(&map, &[a.code, missing])).is_err());
+        assert!(smol::block_on(map.lookup_bytes_many(&[missing])).is_err());
+        assert!(smol::block_on(map.lookup_bytes_many(&[]))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn short_batches_are_errors() {
+        let mut map = MapHashLookup::new();
+        let a = smol::block_on(map.put(&1u32)).unwrap();
+        let b = smol::block_on(map.put(&2u32)).unwrap();
+        let short = ShortBatches(map);
+        let codes = [a.code, b.code];
+        assert!(smol::block_on(short.lookup_many(&[a, b])).is_err());
+        let overlay = HashPutOfHashLookup::new(&short);
+        assert!(smol::block_on(overlay.lookup_bytes_many(&codes)).is_err());
+        let verifying = VerifyingHashLookup::with_cache(&short, 1);
+        assert!(smol::block_on(verifying.lookup_bytes_many(&codes)).is_err());
+        let recording = RecordingHashLookup::new(&short);
+        assert!(smol::block_on(recording.lookup_bytes_many(&codes)).is_err());
+        let caching = CachingHashLookup::new(&short, CacheOptions::default());
+        assert!(smol::block_on(caching.lookup_bytes_many(&codes)).is_err());
+        assert!(smol::block_on(caching.lookup_cached_many(&[a, b])).is_err());
+        // single lookups are unaffected
+        assert_eq!(2, smol::block_on(overlay.lookup(b)).unwrap());
+    }
+
+    #[test]
+    fn verifying_lookup_rejects_tampered_bytes() {
+        let mut map = MapHashLookup::new();
+        let good = smol::block_on(map.put_bytes(b"good")).unwrap();
+        let tampered = hash_of_bytes(b"original");
+        map.map.insert(tampered, b"tampered".to_vec());
+        let verifying = VerifyingHashLookup::with_cache(&map, 1);
+        assert_eq!(
+            b"good".to_vec(),
+            smol::block_on(verifying.lookup_bytes(good)).unwrap()
+        );
+        let err = smol::block_on(verifying.lookup_bytes(tampered)).unwrap_err();
+        assert_eq!(
+            Some(&HashMismatch {
+                expected: tampered,
+                actual: hash_of_bytes(b"tampered"),
+            }),
+            err.downcast_ref::<HashMismatch>()
+        );
+    }
+
+    #[test]
+    fn verified_cache_evicts_least_recently_used() {
+        let mut map = MapHashLookup::new();
+        let a = smol::block_on(map.put_bytes(b"a")).unwrap();
+        let b = smol::block_on(map.put_bytes(b"b")).unwrap();
+        let c = smol::block_on(map.put_bytes(b"c")).unwrap();
+        let verifying = VerifyingHashLookup::with_cache(&map, 2);
+        for hash in [a, b, a, c] {
+            smol::block_on(verifying.lookup_bytes(hash)).unwrap();
+        }
+        assert!(verifying.get_cached(&a).unwrap().is_some());
+        assert!(verifying.get_cached(&b).unwrap().is_none());
+        assert!(verifying.get_cached(&c).unwrap().is_some());
+    }
+
+    #[test]
+    fn overlay_savepoints_and_commit() {
+        let mut store = MapHashLookup::new();
+        let base = smol::block_on(store.put_bytes(b"base")).unwrap();
+        let (kept, inner_kept, discarded) = {
+            let mut overlay = HashPutOfHashLookup::new(&store);
+            let kept = smol::block_on(overlay.put_bytes(b"kept")).unwrap();
+            let outer = overlay.savepoint();
+            let discarded = smol::block_on(overlay.put_bytes(b"discarded")).unwrap();
+            let inner = overlay.savepoint();
+            smol::block_on(overlay.put_bytes(b"also discarded")).unwrap();
+            overlay.release(inner).unwrap();
+            overlay.rollback_to(outer).unwrap();
+            assert!(overlay.rollback_to(inner).is_err());
+            // stale savepoints don't refer to newer ones
+            let newer = overlay.savepoint();
+            let newer_put = smol::block_on(overlay.put_bytes(b"newer")).unwrap();
+            assert!(overlay.rollback_to(outer).is_err());
+            assert!(overlay.release(outer).is_err());
+            assert!(smol::block_on(overlay.lookup_bytes(newer_put)).is_ok());
+            overlay.rollback_to(newer).unwrap();
+            assert!(smol::block_on(overlay.lookup_bytes(discarded)).is_err());
+            assert!(overlay.pending().contains_key(&kept));
+            assert!(!overlay.pending().contains_key(&discarded));
+            let inner_kept = {
+                let mut nested = HashPutOfHashLookup::new(&overlay);
+                assert!(smol::block_on(nested.lookup_bytes(base)).is_ok());
+                let inner_kept = smol::block_on(nested.put_bytes(b"nested")).unwrap();
+                let pending = nested.into_pending();
+                assert_eq!(1, smol::block_on(pending.commit(&mut overlay)).unwrap());
+                inner_kept
+            };
+            let pending = overlay.into_pending();
+            assert_eq!(2, smol::block_on(pending.commit(&mut store)).unwrap());
+            (kept, inner_kept, discarded)
+        };
+        assert!(smol::block_on(store.lookup_bytes(kept)).is_ok());
+        assert!(smol::block_on(store.lookup_bytes(inner_kept)).is_ok());
+        assert!(smol::block_on(store.lookup_bytes(discarded)).is_err());
+    }
+}
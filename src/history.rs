@@ -0,0 +1,161 @@
+//! Queries about how an account changed over a range of main blocks.
+
+use std::collections::BTreeSet;
+
+use serde::de::DeserializeOwned;
+
+use crate::account_transform::TypedDataField;
+use crate::blockdata::{Action, MainBlock, MainBlockBody, QuorumNode};
+use crate::crypto::{hash, Hash, HashCode};
+use crate::hashlookup::HashLookup;
+use crate::hex_path::HexPath;
+use crate::queries::lookup_account;
+use crate::state_machine::{get_account_state, AccountState};
+
+/// A change to a single field of an account.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct FieldDiff {
+    /// The path of the field in account data.
+    pub path: HexPath,
+    /// The serialized value before the change, `None` if the field was absent.
+    pub old: Option<Vec<u8>>,
+    /// The serialized value after the change, `None` if the field was removed.
+    pub new: Option<Vec<u8>>,
+}
+
+/// The decoded old and new values of a field, `None` where the field is absent.
+pub type DecodedFieldDiff<T> = (Option<T>, Option<T>);
+
+impl FieldDiff {
+    /// Decodes the old and new values as the type of a given field.  Returns
+    /// `None` if the diff is for a different field.
+    pub fn decode<T: DeserializeOwned>(
+        &self,
+        field: &TypedDataField<T>,
+    ) -> Result<Option<DecodedFieldDiff<T>>, anyhow::Error> {
+        if self.path != field.path {
+            return Ok(None);
+        }
+        let decode = |value: &Option<Vec<u8>>| -> Result<Option<T>, anyhow::Error> {
+            match value {
+                None => Ok(None),
+                Some(bs) => Ok(Some(rmp_serde::from_read(bs.as_slice())?)),
+            }
+        };
+        Ok(Some((decode(&self.old)?, decode(&self.new)?)))
+    }
+}
+
+/// Computes the field changes between two states of an account, ordered by path.
+pub fn diff_account_states(old: &AccountState, new: &AccountState) -> Vec<FieldDiff> {
+    let paths: BTreeSet<&HexPath> = old.fields.keys().chain(new.fields.keys()).collect();
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let old_value = old.fields.get(path);
+            let new_value = new.fields.get(path);
+            if old_value == new_value {
+                None
+            } else {
+                Some(FieldDiff {
+                    path: path.clone(),
+                    old: old_value.cloned(),
+                    new: new_value.cloned(),
+                })
+            }
+        })
+        .collect()
+}
+
+/// An entry in the history of an account, describing how it changed in a
+/// main block relative to the previous main block.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct AccountHistoryEntry {
+    /// The version of the main block.
+    pub version: u64,
+    /// The timestamp of the main block.
+    pub timestamp_ms: i64,
+    /// The account's `QuorumNode` in this block, `None` if it is absent.
+    pub node: Option<Hash<QuorumNode>>,
+    /// The action applied to the account, if its `QuorumNode` has a different
+    /// `last_main` than in the previous block.
+    pub new_action: Option<Action>,
+    /// The changes to the account's fields.
+    pub diffs: Vec<FieldDiff>,
+}
+
+/// Gets the state of an account given its `QuorumNode`, treating an absent
+/// account as having no fields.
+async fn account_node_state<HL: HashLookup>(
+    hl: &HL,
+    node: &Option<QuorumNode>,
+) -> Result<AccountState, anyhow::Error> {
+    match node.as_ref().and_then(|qn| qn.body.data_tree) {
+        None => Ok(AccountState::empty()),
+        Some(data_tree) => get_account_state(hl, data_tree).await,
+    }
+}
+
+/// Gets the history of an account over the main blocks ending at `head`, in
+/// order of increasing version.  Blocks with versions below `min_version` are
+/// not included, and blocks in which the account's `QuorumNode` did not
+/// change are skipped.
+pub async fn account_history<HL: HashLookup>(
+    hl: &HL,
+    head: Hash<MainBlock>,
+    acct: HashCode,
+    min_version: u64,
+) -> Result<Vec<AccountHistoryEntry>, anyhow::Error> {
+    let mut entries = Vec::new();
+    let mut block: MainBlockBody = hl.lookup(head).await?.block.body;
+    let mut node = lookup_account(hl, &block, acct).await?;
+    // the state of `node`, once it has been needed
+    let mut state: Option<AccountState> = None;
+    while block.version >= min_version {
+        let prev_block = match block.prev {
+            None => None,
+            Some(prev) => Some(hl.lookup(prev).await?.block.body),
+        };
+        let prev_node = match &prev_block {
+            None => None,
+            Some(prev_block) if prev_block.tree == block.tree => node.clone(),
+            Some(prev_block) => lookup_account(hl, prev_block, acct).await?,
+        };
+        let node_hash = node.as_ref().map(hash);
+        if node_hash != prev_node.as_ref().map(hash) {
+            let new_state = match state.take() {
+                Some(new_state) => new_state,
+                None => account_node_state(hl, &node).await?,
+            };
+            let prev_state = account_node_state(hl, &prev_node).await?;
+            let new_action = match &node {
+                Some(qn)
+                    if prev_node.as_ref().map(|p| p.body.last_main) != Some(qn.body.last_main) =>
+                {
+                    match qn.body.new_action {
+                        None => None,
+                        Some(action) => Some(hl.lookup(action).await?),
+                    }
+                }
+                _ => None,
+            };
+            entries.push(AccountHistoryEntry {
+                version: block.version,
+                timestamp_ms: block.timestamp_ms,
+                node: node_hash,
+                new_action,
+                diffs: diff_account_states(&prev_state, &new_state),
+            });
+            state = Some(prev_state);
+        }
+        match prev_block {
+            None => break,
+            Some(prev_block) => {
+                block = prev_block;
+                node = prev_node;
+            }
+        }
+    }
+    entries.reverse();
+    Ok(entries)
+}
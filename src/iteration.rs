@@ -0,0 +1,333 @@
+//! Ordered iteration over the values in radix hash trees.
+//!
+//! Values are visited in order of their paths.  Since a node's path is a
+//! prefix of the paths of its descendants, this is a pre-order traversal with
+//! children visited in order of their first hex digit.  Subtrees that cannot
+//! contain paths in the requested range are skipped without being looked up.
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::blockdata::{DataNode, MainBlockBody, QuorumNode, QuorumNodeStats, RadixHashNode};
+use crate::crypto::{path_to_hash_code, Hash, HashCode};
+use crate::hashlookup::HashLookup;
+use crate::hex_path::{bytes_to_path, is_prefix, u4, HexPath};
+use crate::queries::lookup_account;
+
+/// A range of paths in a radix hash tree.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub struct PathRange {
+    /// Only paths starting with this prefix are included.
+    pub prefix: HexPath,
+    /// If set, only paths at or after this path are included.
+    pub start: Option<HexPath>,
+    /// If set, only paths before this path are included.
+    pub end: Option<HexPath>,
+}
+
+impl PathRange {
+    /// The range of all paths starting with a given prefix.
+    pub fn with_prefix(prefix: HexPath) -> PathRange {
+        PathRange {
+            prefix,
+            start: None,
+            end: None,
+        }
+    }
+
+    /// Whether a path is in the range.
+    pub fn contains(&self, path: &HexPath) -> bool {
+        is_prefix(&self.prefix[..], &path[..])
+            && self.start.iter().all(|start| path >= start)
+            && self.end.iter().all(|end| path < end)
+    }
+
+    /// Whether a subtree whose top node has a given path may contain paths
+    /// in the range.
+    fn may_contain_under(&self, path: &HexPath) -> bool {
+        // every path in the subtree starts with `path`, so it is at least `path`
+        (is_prefix(&self.prefix[..], &path[..]) || is_prefix(&path[..], &self.prefix[..]))
+            && self
+                .start
+                .iter()
+                .all(|start| path >= start || is_prefix(&path[..], &start[..]))
+            && !self.is_past(path)
+    }
+
+    /// Whether a path and every path after it are past the end of the range.
+    fn is_past(&self, path: &HexPath) -> bool {
+        self.end.iter().any(|end| path >= end)
+            || (path > &self.prefix && !is_prefix(&self.prefix[..], &path[..]))
+    }
+}
+
+/// Gets the first path after a given path, which is where to continue
+/// iterating after it.
+pub fn path_successor(path: &HexPath) -> HexPath {
+    let mut next = path.clone();
+    next.0.push(u4(0));
+    next
+}
+
+/// An iterator over the nodes with values in a radix hash tree whose paths
+/// are in a `PathRange`, in order of their paths.
+pub struct RadixTreeIter<'a, HL: HashLookup, N: RadixHashNode> {
+    hl: &'a HL,
+    range: PathRange,
+    /// Subtrees that remain to be visited, with the next one last.
+    stack: Vec<(HexPath, Hash<N>)>,
+}
+
+impl<'a, HL: HashLookup, N: RadixHashNode> RadixTreeIter<'a, HL, N> {
+    /// Creates an iterator over a tree whose top node has the empty path.
+    pub fn new(hl: &'a HL, tree: Hash<N>, range: PathRange) -> RadixTreeIter<'a, HL, N> {
+        RadixTreeIter::subtree(hl, HexPath(Vec::new()), tree, range)
+    }
+
+    /// Creates an iterator over a subtree whose top node has a given path.
+    pub fn subtree(
+        hl: &'a HL,
+        top_path: HexPath,
+        tree: Hash<N>,
+        range: PathRange,
+    ) -> RadixTreeIter<'a, HL, N> {
+        RadixTreeIter {
+            hl,
+            range,
+            stack: vec![(top_path, tree)],
+        }
+    }
+
+    /// Gets the next node with a value, along with its path and hash, or
+    /// `None` if there are no more.
+    pub async fn next_node(&mut self) -> Result<Option<(HexPath, Hash<N>, N)>, anyhow::Error> {
+        self.next_node_where(|_| true).await
+    }
+
+    /// Like `next_node`, but skips nodes for which `keep` returns false along
+    /// with everything under them.
+    pub async fn next_node_where(
+        &mut self,
+        keep: impl Fn(&N) -> bool,
+    ) -> Result<Option<(HexPath, Hash<N>, N)>, anyhow::Error> {
+        while let Some((path, node_hash)) = self.stack.pop() {
+            if self.range.is_past(&path) {
+                // every remaining subtree comes after this one
+                self.stack.clear();
+                break;
+            }
+            if !self.range.may_contain_under(&path) {
+                continue;
+            }
+            let node: N = self.hl.lookup(node_hash).await?;
+            if !keep(&node) {
+                continue;
+            }
+            for (digit, entry) in node.get_children().0.iter().enumerate().rev() {
+                if let Some((suffix, child)) = entry {
+                    let mut child_path = path.clone();
+                    child_path.0.push(u4(digit as u8));
+                    child_path.0.extend_from_slice(&suffix[..]);
+                    self.stack.push((child_path, *child));
+                }
+            }
+            if node.has_value() && self.range.contains(&path) {
+                return Ok(Some((path, node_hash, node)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// An iterator over the fields of a data tree whose paths are in a
+/// `PathRange`, in order of their paths.
+pub struct DataFieldIter<'a, HL: HashLookup> {
+    nodes: RadixTreeIter<'a, HL, DataNode>,
+}
+
+impl<'a, HL: HashLookup> DataFieldIter<'a, HL> {
+    /// Creates an iterator over the fields of a data tree.
+    pub fn new(hl: &'a HL, tree: Hash<DataNode>, range: PathRange) -> DataFieldIter<'a, HL> {
+        DataFieldIter {
+            nodes: RadixTreeIter::new(hl, tree, range),
+        }
+    }
+
+    /// Gets the next field's path and value, or `None` if there are no more.
+    pub async fn next_field(&mut self) -> Result<Option<(HexPath, Vec<u8>)>, anyhow::Error> {
+        Ok(self
+            .nodes
+            .next_node()
+            .await?
+            .map(|(path, _, node)| (path, node.field.unwrap())))
+    }
+}
+
+/// A page of data fields.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub struct DataFieldPage {
+    /// The fields in the page, in order of their paths.
+    pub fields: Vec<(HexPath, Vec<u8>)>,
+    /// If there may be more fields in the range, the range to request for
+    /// the next page.
+    pub next: Option<PathRange>,
+}
+
+/// Lists up to `limit` fields of a data tree in a range.  The limit must be
+/// positive, since an empty page could not make progress.
+pub async fn list_data_fields<HL: HashLookup>(
+    hl: &HL,
+    tree: Hash<DataNode>,
+    range: PathRange,
+    limit: usize,
+) -> Result<DataFieldPage, anyhow::Error> {
+    if limit == 0 {
+        bail!("page limit must be positive");
+    }
+    let mut iter = DataFieldIter::new(hl, tree, range.clone());
+    let mut fields = Vec::new();
+    while fields.len() < limit {
+        match iter.next_field().await? {
+            None => return Ok(DataFieldPage { fields, next: None }),
+            Some(field) => fields.push(field),
+        }
+    }
+    // the page is full, so it has a last field
+    let (last, _) = fields.last().unwrap();
+    let next = PathRange {
+        start: Some(path_successor(last)),
+        ..range
+    };
+    Ok(DataFieldPage {
+        fields,
+        next: Some(next),
+    })
+}
+
+/// Lists up to `limit` fields in a range of an account's data in a main
+/// block.  An absent account has no fields.  The limit must be positive.
+pub async fn list_account_fields<HL: HashLookup>(
+    hl: &HL,
+    main: &MainBlockBody,
+    acct: HashCode,
+    range: PathRange,
+    limit: usize,
+) -> Result<DataFieldPage, anyhow::Error> {
+    if limit == 0 {
+        bail!("page limit must be positive");
+    }
+    match lookup_account(hl, main, acct)
+        .await?
+        .and_then(|qn| qn.body.data_tree)
+    {
+        None => Ok(DataFieldPage {
+            fields: Vec::new(),
+            next: None,
+        }),
+        Some(tree) => list_data_fields(hl, tree, range, limit).await,
+    }
+}
+
+/// Conditions on the stats of accounts to list.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AccountFilter {
+    /// The minimum stake of listed accounts.  Subtrees with less total stake
+    /// are skipped.
+    pub min_stake: u128,
+}
+
+/// An account, the hash of its `QuorumNode` and the node's stats.
+pub type AccountEntry = (HashCode, Hash<QuorumNode>, QuorumNodeStats);
+
+/// An iterator over the accounts in a main block, in order of their hash
+/// codes.
+pub struct AccountIter<'a, HL: HashLookup> {
+    nodes: RadixTreeIter<'a, HL, QuorumNode>,
+    filter: AccountFilter,
+}
+
+impl<'a, HL: HashLookup> AccountIter<'a, HL> {
+    /// Creates an iterator over the accounts in a main block starting at a
+    /// given account (or the first one) and passing a filter.
+    pub fn new(
+        hl: &'a HL,
+        main: &MainBlockBody,
+        start: Option<HashCode>,
+        filter: AccountFilter,
+    ) -> AccountIter<'a, HL> {
+        let range = PathRange {
+            prefix: HexPath(Vec::new()),
+            start: start.map(|start| bytes_to_path(&start)),
+            end: None,
+        };
+        AccountIter {
+            nodes: RadixTreeIter::new(hl, main.tree, range),
+            filter,
+        }
+    }
+
+    /// Gets the next account, or `None` if there are no more.
+    pub async fn next_account(&mut self) -> Result<Option<AccountEntry>, anyhow::Error> {
+        let min_stake = self.filter.min_stake;
+        Ok(self
+            .nodes
+            .next_node_where(|qn| qn.body.stats.stake >= min_stake)
+            .await?
+            .map(|(path, node_hash, node)| (path_to_hash_code(path), node_hash, node.body.stats)))
+    }
+}
+
+/// Gets the hash code following a given one, or `None` if it is the last.
+fn hash_code_successor(code: &HashCode) -> Option<HashCode> {
+    let mut next = *code;
+    for byte in next.iter_mut().rev() {
+        if *byte == u8::MAX {
+            *byte = 0;
+        } else {
+            *byte += 1;
+            return Some(next);
+        }
+    }
+    None
+}
+
+/// A page of accounts.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub struct AccountPage {
+    /// The accounts in the page, in order of their hash codes.
+    pub accounts: Vec<AccountEntry>,
+    /// If there may be more accounts, the start of the next page.
+    pub next: Option<HashCode>,
+}
+
+/// Lists up to `limit` accounts in a main block passing a filter, starting
+/// at a given account (or the first one).  The limit must be positive, since
+/// an empty page could not make progress.
+pub async fn list_accounts<HL: HashLookup>(
+    hl: &HL,
+    main: &MainBlockBody,
+    start: Option<HashCode>,
+    filter: AccountFilter,
+    limit: usize,
+) -> Result<AccountPage, anyhow::Error> {
+    if limit == 0 {
+        bail!("page limit must be positive");
+    }
+    let mut iter = AccountIter::new(hl, main, start, filter);
+    let mut accounts = Vec::new();
+    while accounts.len() < limit {
+        match iter.next_account().await? {
+            None => {
+                return Ok(AccountPage {
+                    accounts,
+                    next: None,
+                })
+            }
+            Some(account) => accounts.push(account),
+        }
+    }
+    // the page is full, so it has a last account
+    let (last, _, _) = accounts.last().unwrap();
+    let next = hash_code_successor(last);
+    Ok(AccountPage { accounts, next })
+}
@@ -2,14 +2,27 @@ pub mod hex_path;
 
 pub mod crypto;
 
+pub mod vrf;
+
 pub mod blockdata;
+pub mod disk_store;
+pub mod lookup_cache;
+pub mod object_graph;
 pub mod hashlookup;
 pub mod network;
 
 pub mod queries;
 
+pub mod beacon;
+
+pub mod iteration;
+
+pub mod proofs;
+
 pub mod account_transform;
 
+pub mod vm;
+
 pub mod account_construction;
 
 pub mod verification;
@@ -17,3 +30,17 @@ pub mod verification;
 pub mod construction;
 
 pub mod state_machine;
+
+pub mod governance;
+
+pub mod rewards;
+
+pub mod history;
+
+pub mod tree_diff;
+
+pub mod garbage_collection;
+
+pub mod archive;
+
+pub mod light_client;
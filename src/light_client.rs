@@ -0,0 +1,152 @@
+//! A light client that follows the main chain by checking block endorsements
+//! only.
+//!
+//! The light client stores `MainBlock` headers (and the `MainOptions` they
+//! reference) but never downloads quorum trees.  To check that a block is
+//! endorsed by the miner and signers selected from the previous block, it is
+//! given an `EndorsementProof` holding the objects the check depends on:
+//! mostly the `QuorumNode`s visited when selecting accounts proportional to
+//! stake.  Since every object is addressed by its hash code, the proof cannot
+//! substitute different data; at worst it is missing objects, in which case
+//! verification fails.
+
+use std::collections::BTreeMap;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::blockdata::{MainBlock, MainOptions};
+use crate::crypto::{hash, Hash, HashCode};
+use crate::hashlookup::{
+    HashLookup, HashPut, HashPutOfHashLookup, MapHashLookup, RecordingHashLookup,
+};
+use crate::hex_path::HexPath;
+use crate::proofs::{verify_field_proof, FieldProof};
+use crate::verification::verify_endorsed_main_block;
+
+/// The objects needed to verify that a `MainBlock` is endorsed, given the
+/// headers leading up to it.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub struct EndorsementProof {
+    /// Serialized objects looked up during verification.
+    pub objects: Vec<Vec<u8>>,
+}
+
+/// Generates an `EndorsementProof` for a block, failing if the block is not
+/// endorsed.
+pub async fn prove_endorsement<HL: HashLookup>(
+    hl: &HL,
+    main: &MainBlock,
+) -> Result<EndorsementProof, anyhow::Error> {
+    let recording = RecordingHashLookup::new(hl);
+    verify_endorsed_main_block(&recording, main).await?;
+    Ok(EndorsementProof {
+        objects: recording.into_recorded()?,
+    })
+}
+
+/// A client tracking the best chain of endorsed main block headers.
+pub struct LightClient {
+    /// Verified headers along with the options they reference.
+    store: MapHashLookup,
+    /// The versions of the verified headers.
+    versions: BTreeMap<HashCode, u64>,
+    best: Hash<MainBlock>,
+}
+
+impl LightClient {
+    /// Creates a light client starting from a trusted block, usually the
+    /// genesis block.  Blocks are only accepted if selecting their signers
+    /// does not require headers older than the trusted block.
+    pub async fn new(
+        trusted: MainBlock,
+        options: MainOptions,
+    ) -> Result<LightClient, anyhow::Error> {
+        if hash(&options) != trusted.block.body.options {
+            bail!("options do not match trusted block");
+        }
+        let mut store = MapHashLookup::new();
+        store.put(&options).await?;
+        let best = store.put(&trusted).await?;
+        let mut versions = BTreeMap::new();
+        versions.insert(best.code, trusted.block.body.version);
+        Ok(LightClient {
+            store,
+            versions,
+            best,
+        })
+    }
+
+    /// Gets the hash of the best known block, i.e. the one with the highest
+    /// version.  Of blocks with the same version, the first one added wins.
+    pub fn best(&self) -> Hash<MainBlock> {
+        self.best
+    }
+
+    /// Gets the best known block.
+    pub async fn best_block(&self) -> Result<MainBlock, anyhow::Error> {
+        self.store.lookup(self.best).await
+    }
+
+    /// Checks whether a block has been verified.
+    pub fn contains(&self, block: Hash<MainBlock>) -> bool {
+        self.versions.contains_key(&block.code)
+    }
+
+    /// Gets a verified block.
+    pub async fn block(&self, block: Hash<MainBlock>) -> Result<MainBlock, anyhow::Error> {
+        if !self.contains(block) {
+            bail!("block is not known to the light client");
+        }
+        self.store.lookup(block).await
+    }
+
+    /// Verifies that a block following a known block is endorsed and adds it
+    /// to the known blocks.  Returns whether it became the best block.
+    pub async fn add_block(
+        &mut self,
+        main: MainBlock,
+        proof: &EndorsementProof,
+    ) -> Result<bool, anyhow::Error> {
+        match main.block.body.prev {
+            None => bail!("genesis block is never endorsed"),
+            Some(prev) => {
+                if !self.contains(prev) {
+                    bail!("previous block is not known to the light client");
+                }
+            }
+        }
+        let options = {
+            let mut overlay = HashPutOfHashLookup::new(&self.store);
+            for bs in &proof.objects {
+                overlay.put_bytes(bs).await?;
+            }
+            verify_endorsed_main_block(&overlay, &main).await?;
+            overlay.lookup(main.block.body.options).await?
+        };
+        self.store.put(&options).await?;
+        let hash = self.store.put(&main).await?;
+        let version = main.block.body.version;
+        self.versions.insert(hash.code, version);
+        if version > self.versions[&self.best.code] {
+            self.best = hash;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Verifies a `FieldProof` relative to a known block, returning the
+    /// field's value or `None` if the proof shows that it is absent.
+    pub fn verify_field(
+        &self,
+        block: Hash<MainBlock>,
+        acct: HashCode,
+        field: &HexPath,
+        proof: &FieldProof,
+    ) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        if !self.contains(block) {
+            bail!("block is not known to the light client");
+        }
+        verify_field_proof(block, acct, field, proof)
+    }
+}
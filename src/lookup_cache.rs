@@ -0,0 +1,498 @@
+//! A bounded least-recently-used cache layered over a `HashLookup`.
+use std::any::{Any, TypeId};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::anyhow;
+use async_trait::*;
+use serde::de::DeserializeOwned;
+
+use crate::crypto::{Hash, HashCode};
+use crate::hashlookup::{check_batch_len, HashLookup};
+
+/// Options for a `CachingHashLookup`.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct CacheOptions {
+    /// The maximum total size of cached entries, in bytes.  Decoded values
+    /// are counted as the size of their encoding, in addition to the bytes
+    /// they were decoded from.
+    pub max_bytes: usize,
+    /// Whether `lookup_cached` and `lookup_cached_many` cache decoded values
+    /// in addition to bytes.
+    pub cache_decoded: bool,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions {
+            max_bytes: 64 * 1024 * 1024,
+            cache_decoded: true,
+        }
+    }
+}
+
+/// Counters describing the effectiveness of a `CachingHashLookup`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    /// Byte lookups answered from the cache.
+    pub hits: u64,
+    /// Byte lookups passed to the underlying `HashLookup`.
+    pub misses: u64,
+    /// Decoded lookups answered from the cache.
+    pub decoded_hits: u64,
+    /// Decoded lookups that had to decode bytes.
+    pub decoded_misses: u64,
+    /// Entries evicted to stay within the size bound.
+    pub evictions: u64,
+    /// The number of entries currently cached.
+    pub entries: u64,
+    /// The total size of the entries currently cached, in bytes.
+    pub bytes: u64,
+}
+
+/// The key of a cache entry.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+enum CacheKey {
+    /// Raw bytes with a given hash code.
+    Bytes(HashCode),
+    /// A decoded value of a given type with a given hash code.
+    Decoded(HashCode, TypeId),
+}
+
+/// The value of a cache entry.
+#[derive(Clone)]
+enum CachedValue {
+    Bytes(Vec<u8>),
+    Decoded(Arc<dyn Any + Send + Sync>),
+}
+
+/// An entry of an `Lru`, along with its size and the time it was last used.
+struct LruEntry<V> {
+    value: V,
+    size: usize,
+    last_used: u64,
+}
+
+/// A map that evicts its least recently used entries once the total size of
+/// its entries would exceed a bound.  Bounding the number of entries instead
+/// is done by giving every entry a size of 1.
+pub(crate) struct Lru<K, V> {
+    max_size: usize,
+    entries: BTreeMap<K, LruEntry<V>>,
+    /// Keys indexed by the time they were last used.
+    by_use: BTreeMap<u64, K>,
+    clock: u64,
+    size: usize,
+}
+
+impl<K: Ord + Clone, V: Clone> Lru<K, V> {
+    /// Creates an empty `Lru` holding entries of a total size of at most
+    /// `max_size`.
+    pub(crate) fn new(max_size: usize) -> Lru<K, V> {
+        Lru {
+            max_size,
+            entries: BTreeMap::new(),
+            by_use: BTreeMap::new(),
+            clock: 0,
+            size: 0,
+        }
+    }
+
+    /// Gets an entry, marking it as recently used.
+    pub(crate) fn get(&mut self, key: &K) -> Option<V> {
+        self.clock += 1;
+        let clock = self.clock;
+        let entry = self.entries.get_mut(key)?;
+        self.by_use.remove(&entry.last_used);
+        entry.last_used = clock;
+        self.by_use.insert(clock, key.clone());
+        Some(entry.value.clone())
+    }
+
+    /// Inserts an entry, evicting least recently used entries until it
+    /// fits, and returns how many were evicted.  Entries larger than the
+    /// bound and keys that are already present are not inserted.
+    pub(crate) fn insert(&mut self, key: K, value: V, size: usize) -> u64 {
+        if size > self.max_size || self.entries.contains_key(&key) {
+            return 0;
+        }
+        let mut evicted = 0;
+        while self.size + size > self.max_size {
+            let old_key = match self.by_use.values().next() {
+                Some(k) => k.clone(),
+                None => break,
+            };
+            let old = self.entries.remove(&old_key).unwrap();
+            self.by_use.remove(&old.last_used);
+            self.size -= old.size;
+            evicted += 1;
+        }
+        self.clock += 1;
+        self.by_use.insert(self.clock, key.clone());
+        self.entries.insert(
+            key,
+            LruEntry {
+                value,
+                size,
+                last_used: self.clock,
+            },
+        );
+        self.size += size;
+        evicted
+    }
+
+    /// Gets the number of entries.
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Gets the total size of the entries.
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Removes all entries.
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.by_use.clear();
+        self.size = 0;
+    }
+}
+
+/// The mutable state of a `CachingHashLookup`.
+struct LruState {
+    lru: Lru<CacheKey, CachedValue>,
+    stats: CacheStats,
+}
+
+impl LruState {
+    /// Inserts an entry, counting the entries evicted to make room for it.
+    fn insert(&mut self, key: CacheKey, value: CachedValue, size: usize) {
+        self.stats.evictions += self.lru.insert(key, value, size);
+    }
+}
+
+/// A `HashLookup` that caches the results of an underlying `HashLookup`,
+/// evicting the least recently used entries once a size bound is reached.
+pub struct CachingHashLookup<'a, HL: HashLookup> {
+    /// The underlying `HashLookup`.
+    pub hl: &'a HL,
+    options: CacheOptions,
+    state: Mutex<LruState>,
+}
+
+impl<'a, HL: HashLookup> CachingHashLookup<'a, HL> {
+    /// Creates a new `CachingHashLookup` over an underlying `HashLookup`.
+    pub fn new(hl: &'a HL, options: CacheOptions) -> CachingHashLookup<'a, HL> {
+        CachingHashLookup {
+            hl,
+            state: Mutex::new(LruState {
+                lru: Lru::new(options.max_bytes),
+                stats: CacheStats::default(),
+            }),
+            options,
+        }
+    }
+
+    fn lock_state(&self) -> Result<MutexGuard<'_, LruState>, anyhow::Error> {
+        self.state
+            .lock()
+            .map_err(|_| anyhow!("lookup cache lock poisoned"))
+    }
+
+    /// Gets the current cache statistics.
+    pub fn stats(&self) -> Result<CacheStats, anyhow::Error> {
+        let state = self.lock_state()?;
+        let mut stats = state.stats;
+        stats.entries = state.lru.len() as u64;
+        stats.bytes = state.lru.size() as u64;
+        Ok(stats)
+    }
+
+    /// Resets the hit, miss and eviction counters.
+    pub fn reset_stats(&self) -> Result<(), anyhow::Error> {
+        self.lock_state()?.stats = CacheStats::default();
+        Ok(())
+    }
+
+    /// Removes all cached entries.
+    pub fn clear(&self) -> Result<(), anyhow::Error> {
+        self.lock_state()?.lru.clear();
+        Ok(())
+    }
+
+    /// Gets cached bytes, counting a hit or a miss.
+    fn get_bytes(&self, hash: HashCode) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let mut state = self.lock_state()?;
+        if let Some(CachedValue::Bytes(bs)) = state.lru.get(&CacheKey::Bytes(hash)) {
+            state.stats.hits += 1;
+            return Ok(Some(bs));
+        }
+        state.stats.misses += 1;
+        Ok(None)
+    }
+
+    /// Caches bytes fetched from the underlying `HashLookup`.
+    fn insert_bytes(&self, hash: HashCode, bs: &[u8]) -> Result<(), anyhow::Error> {
+        self.lock_state()?.insert(
+            CacheKey::Bytes(hash),
+            CachedValue::Bytes(bs.to_vec()),
+            bs.len(),
+        );
+        Ok(())
+    }
+
+    /// Gets a cached decoded value, counting a decoded hit or miss.
+    fn get_decoded<T: Clone + 'static>(&self, hash: HashCode) -> Result<Option<T>, anyhow::Error> {
+        let mut state = self.lock_state()?;
+        let key = CacheKey::Decoded(hash, TypeId::of::<T>());
+        if let Some(CachedValue::Decoded(value)) = state.lru.get(&key) {
+            if let Some(value) = value.downcast_ref::<T>() {
+                state.stats.decoded_hits += 1;
+                return Ok(Some(value.clone()));
+            }
+        }
+        state.stats.decoded_misses += 1;
+        Ok(None)
+    }
+
+    /// Decodes bytes and caches the decoded value.  The bytes stay cached
+    /// as well, so byte lookups of the same hash code still hit.
+    fn decode_and_cache<T: DeserializeOwned + Clone + Send + Sync + 'static>(
+        &self,
+        hash: HashCode,
+        bs: &[u8],
+    ) -> Result<T, anyhow::Error> {
+        let value: T = rmp_serde::from_read(bs)?;
+        self.lock_state()?.insert(
+            CacheKey::Decoded(hash, TypeId::of::<T>()),
+            CachedValue::Decoded(Arc::new(value.clone())),
+            bs.len(),
+        );
+        Ok(value)
+    }
+}
+
+#[async_trait]
+impl<'a, HL: HashLookup> HashLookup for CachingHashLookup<'a, HL> {
+    /// Looks up a value by its hash code, caching the decoded value (if
+    /// enabled in the options) so that it need not be deserialized again.
+    async fn lookup_cached<T>(&self, hash: Hash<T>) -> Result<T, anyhow::Error>
+    where
+        T: DeserializeOwned + Clone + Send + Sync + 'static,
+    {
+        if !self.options.cache_decoded {
+            let bs = self.lookup_bytes(hash.code).await?;
+            return Ok(rmp_serde::from_read(bs.as_slice())?);
+        }
+        if let Some(value) = self.get_decoded(hash.code)? {
+            return Ok(value);
+        }
+        let bs = self.lookup_bytes(hash.code).await?;
+        self.decode_and_cache(hash.code, &bs)
+    }
+
+    async fn lookup_cached_many<T>(&self, hashes: &[Hash<T>]) -> Result<Vec<T>, anyhow::Error>
+    where
+        T: DeserializeOwned + Clone + Send + Sync + 'static,
+    {
+        let mut values = Vec::with_capacity(hashes.len());
+        let mut missing = Vec::new();
+        for hash in hashes {
+            let cached = if self.options.cache_decoded {
+                self.get_decoded(hash.code)?
+            } else {
+                None
+            };
+            if cached.is_none() {
+                missing.push(hash.code);
+            }
+            values.push(cached);
+        }
+        let fetched = check_batch_len(&missing, self.lookup_bytes_many(&missing).await?)?;
+        let mut fetched = missing.iter().zip(fetched);
+        values
+            .into_iter()
+            .map(|cached| match cached {
+                Some(value) => Ok(value),
+                None => {
+                    let (hash, bs) = fetched.next().unwrap();
+                    if self.options.cache_decoded {
+                        self.decode_and_cache(*hash, &bs)
+                    } else {
+                        Ok(rmp_serde::from_read(bs.as_slice())?)
+                    }
+                }
+            })
+            .collect()
+    }
+
+    async fn lookup_bytes(&self, hash: HashCode) -> Result<Vec<u8>, anyhow::Error> {
+        if let Some(bs) = self.get_bytes(hash)? {
+            return Ok(bs);
+        }
+        let bs = self.hl.lookup_bytes(hash).await?;
+        self.insert_bytes(hash, &bs)?;
+        Ok(bs)
+    }
+
+    async fn lookup_bytes_many(&self, hashes: &[HashCode]) -> Result<Vec<Vec<u8>>, anyhow::Error> {
+        let mut values = Vec::with_capacity(hashes.len());
+        let mut missing = Vec::new();
+        {
+            let mut state = self.lock_state()?;
+            for hash in hashes {
+                match state.lru.get(&CacheKey::Bytes(*hash)) {
+                    Some(CachedValue::Bytes(bs)) => {
+                        state.stats.hits += 1;
+                        values.push(Some(bs));
+                    }
+                    _ => {
+                        state.stats.misses += 1;
+                        missing.push(*hash);
+                        values.push(None);
+                    }
+                }
+            }
+        }
+        let fetched = check_batch_len(&missing, self.hl.lookup_bytes_many(&missing).await?)?;
+        let mut fetched = missing.iter().zip(fetched);
+        values
+            .into_iter()
+            .map(|cached| match cached {
+                Some(bs) => Ok(bs),
+                None => {
+                    let (hash, bs) = fetched.next().unwrap();
+                    self.insert_bytes(*hash, &bs)?;
+                    Ok(bs)
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account_construction::insert_into_data_tree;
+    use crate::blockdata::{DataNode, RadixChildren};
+    use crate::hashlookup::{HashPut, MapHashLookup};
+    use crate::hex_path::bytes_to_path;
+    use crate::queries::rh_follow_path;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut map = MapHashLookup::new();
+        let a = smol::block_on(map.put(&vec![1u8; 10])).unwrap();
+        let b = smol::block_on(map.put(&vec![2u8; 10])).unwrap();
+        let c = smol::block_on(map.put(&vec![3u8; 10])).unwrap();
+        let cache = CachingHashLookup::new(
+            &map,
+            CacheOptions {
+                max_bytes: 2 * 12,
+                cache_decoded: false,
+            },
+        );
+        smol::block_on(cache.lookup_bytes(a.code)).unwrap();
+        smol::block_on(cache.lookup_bytes(b.code)).unwrap();
+        smol::block_on(cache.lookup_bytes(a.code)).unwrap();
+        smol::block_on(cache.lookup_bytes(c.code)).unwrap();
+        smol::block_on(cache.lookup_bytes(a.code)).unwrap();
+        smol::block_on(cache.lookup_bytes(b.code)).unwrap();
+        let stats = cache.stats().unwrap();
+        assert_eq!((2, 4, 2), (stats.hits, stats.misses, stats.evictions));
+        assert_eq!(
+            vec![2u8; 10],
+            smol::block_on(cache.lookup_cached(b)).unwrap()
+        );
+        assert_eq!(
+            vec![2u8; 10],
+            smol::block_on(cache.lookup_cached(b)).unwrap()
+        );
+        assert_eq!(0, cache.stats().unwrap().decoded_hits);
+    }
+
+    #[test]
+    fn caches_decoded_values() {
+        let mut map = MapHashLookup::new();
+        let a = smol::block_on(map.put(&(1u64, "one".to_string()))).unwrap();
+        let cache = CachingHashLookup::new(&map, CacheOptions::default());
+        for _ in 0..3 {
+            assert_eq!(
+                (1u64, "one".to_string()),
+                smol::block_on(cache.lookup_cached(a)).unwrap()
+            );
+        }
+        let stats = cache.stats().unwrap();
+        assert_eq!((2, 1), (stats.decoded_hits, stats.decoded_misses));
+        assert_eq!((0, 1), (stats.hits, stats.misses));
+    }
+
+    #[test]
+    fn decoded_values_keep_their_bytes() {
+        let mut map = MapHashLookup::new();
+        let a = smol::block_on(map.put(&(1u64, "one".to_string()))).unwrap();
+        let size = smol::block_on(map.lookup_bytes(a.code)).unwrap().len() as u64;
+        let cache = CachingHashLookup::new(&map, CacheOptions::default());
+        smol::block_on(cache.lookup_bytes(a.code)).unwrap();
+        smol::block_on(cache.lookup_cached(a)).unwrap();
+        smol::block_on(cache.lookup_bytes(a.code)).unwrap();
+        let stats = cache.stats().unwrap();
+        assert_eq!((2, 2 * size), (stats.entries, stats.bytes));
+        assert_eq!((2, 1), (stats.hits, stats.misses));
+
+        // without decoded values, decoded lookups are byte lookups
+        let cache = CachingHashLookup::new(
+            &map,
+            CacheOptions {
+                cache_decoded: false,
+                ..CacheOptions::default()
+            },
+        );
+        for _ in 0..2 {
+            smol::block_on(cache.lookup_cached(a)).unwrap();
+        }
+        smol::block_on(cache.lookup_cached_many(&[a, a])).unwrap();
+        let stats = cache.stats().unwrap();
+        assert_eq!((0, 0), (stats.decoded_hits, stats.decoded_misses));
+        assert_eq!((3, 1, size), (stats.hits, stats.misses, stats.bytes));
+    }
+
+    #[test]
+    fn queries_use_decoded_cache() {
+        let mut map = MapHashLookup::new();
+        let empty = DataNode {
+            field: None,
+            children: RadixChildren::default(),
+        };
+        let mut tree = smol::block_on(map.put(&empty)).unwrap();
+        let mut node_count = 0;
+        for name in &[&b"a"[..], b"b"] {
+            let path = bytes_to_path(name);
+            tree = smol::block_on(insert_into_data_tree(
+                &mut map,
+                &mut node_count,
+                &path[..],
+                name.to_vec(),
+                tree,
+            ))
+            .unwrap();
+        }
+        let cache = CachingHashLookup::new(&map, CacheOptions::default());
+        let top = smol::block_on(cache.lookup_cached(tree)).unwrap();
+        let path = bytes_to_path(b"b");
+        for _ in 0..3 {
+            let (node, rest) = smol::block_on(rh_follow_path(&cache, top.clone(), &path[..]))
+                .unwrap()
+                .unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(Some(b"b".to_vec()), node.field);
+        }
+        // only the first query decodes the nodes below the top one
+        let stats = cache.stats().unwrap();
+        let below_top = stats.decoded_misses - 1;
+        assert!(below_top > 0);
+        assert_eq!(2 * below_top, stats.decoded_hits);
+        assert_eq!((0, stats.decoded_misses), (stats.hits, stats.misses));
+    }
+}
@@ -6,7 +6,7 @@ use super::peer_tracker::PeerTracker;
 use super::role::Role;
 use super::Network;
 use crate::crypto::{hash, xor_hash_codes, HashCode};
-use crate::hashlookup::{HashLookup, HashPut};
+use crate::hashlookup::{lookup_bytes_concurrently, HashLookup, HashPut};
 use anyhow::bail;
 use async_trait::*;
 use std::collections::BTreeSet;
@@ -51,4 +51,8 @@ impl<N: Network + 'static + Send + Sync> HashLookup for HashOps<N> {
     async fn lookup_bytes(&self, hash: HashCode) -> Result<Vec<u8>, anyhow::Error> {
         bail!("TODO")
     }
+
+    async fn lookup_bytes_many(&self, hashes: &[HashCode]) -> Result<Vec<Vec<u8>>, anyhow::Error> {
+        lookup_bytes_concurrently(self, hashes).await
+    }
 }
@@ -0,0 +1,130 @@
+//! Enumeration of the hash-addressed objects that make up the blockchain.
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::blockdata::{DataNode, MainBlock, QuorumNode};
+use crate::crypto::{Hash, HashCode};
+use crate::hashlookup::HashLookup;
+
+/// The type of a hash-addressed object stored in a `HashLookup`.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum ObjectKind {
+    /// A `MainBlock`.
+    MainBlock,
+    /// A `MainOptions`.
+    MainOptions,
+    /// A `QuorumNode`.
+    QuorumNode,
+    /// The signatures of a `QuorumNode`.
+    QuorumSignatures,
+    /// A `DataNode`.
+    DataNode,
+    /// An `Action`.
+    Action,
+}
+
+/// A hash code of an object of a known kind.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ObjectRef {
+    /// The kind of the object.
+    pub kind: ObjectKind,
+    /// The hash code of the object.
+    pub code: HashCode,
+}
+
+impl ObjectRef {
+    /// Creates an `ObjectRef` to a `MainBlock`.
+    pub fn main_block(hash: Hash<MainBlock>) -> ObjectRef {
+        ObjectRef {
+            kind: ObjectKind::MainBlock,
+            code: hash.code,
+        }
+    }
+
+    /// Creates an `ObjectRef` to a `QuorumNode`.
+    pub fn quorum_node(hash: Hash<QuorumNode>) -> ObjectRef {
+        ObjectRef {
+            kind: ObjectKind::QuorumNode,
+            code: hash.code,
+        }
+    }
+
+    /// Creates an `ObjectRef` to a `DataNode`.
+    pub fn data_node(hash: Hash<DataNode>) -> ObjectRef {
+        ObjectRef {
+            kind: ObjectKind::DataNode,
+            code: hash.code,
+        }
+    }
+}
+
+/// Gets the objects directly referenced by a given object.  The `prev` link
+/// of a `MainBlock` is not included, so that walks starting from a block stay
+/// within that block's state unless they follow `prev` explicitly.
+pub async fn object_references<HL: HashLookup>(
+    hl: &HL,
+    obj: ObjectRef,
+) -> Result<Vec<ObjectRef>, anyhow::Error> {
+    let mut refs = Vec::new();
+    match obj.kind {
+        ObjectKind::MainBlock => {
+            let main: MainBlock = hl.lookup(Hash::from_code(obj.code)).await?;
+            refs.push(ObjectRef {
+                kind: ObjectKind::MainOptions,
+                code: main.block.body.options.code,
+            });
+            refs.push(ObjectRef::quorum_node(main.block.body.tree));
+            if main.block.body.endorsed_tree != main.block.body.tree {
+                refs.push(ObjectRef::quorum_node(main.block.body.endorsed_tree));
+            }
+        }
+        ObjectKind::QuorumNode => {
+            let qn: QuorumNode = hl.lookup(Hash::from_code(obj.code)).await?;
+            if let Some(sigs) = qn.signatures {
+                refs.push(ObjectRef {
+                    kind: ObjectKind::QuorumSignatures,
+                    code: sigs.code,
+                });
+            }
+            if let Some(data_tree) = qn.body.data_tree {
+                refs.push(ObjectRef::data_node(data_tree));
+            }
+            if let Some(action) = qn.body.new_action {
+                refs.push(ObjectRef {
+                    kind: ObjectKind::Action,
+                    code: action.code,
+                });
+            }
+            for (_, child) in qn.body.children.iter_entries() {
+                refs.push(ObjectRef::quorum_node(*child));
+            }
+        }
+        ObjectKind::DataNode => {
+            let dn: DataNode = hl.lookup(Hash::from_code(obj.code)).await?;
+            for (_, child) in dn.children.iter_entries() {
+                refs.push(ObjectRef::data_node(*child));
+            }
+        }
+        ObjectKind::MainOptions | ObjectKind::QuorumSignatures | ObjectKind::Action => {}
+    }
+    Ok(refs)
+}
+
+/// Gets the hash codes of all objects reachable from the given roots,
+/// including the roots themselves.  `prev` links of main blocks are not
+/// followed.
+pub async fn reachable_objects<HL: HashLookup>(
+    hl: &HL,
+    roots: &[ObjectRef],
+) -> Result<BTreeSet<HashCode>, anyhow::Error> {
+    let mut seen = BTreeSet::new();
+    let mut stack: Vec<ObjectRef> = roots.to_vec();
+    while let Some(obj) = stack.pop() {
+        if !seen.insert(obj.code) {
+            continue;
+        }
+        stack.extend(object_references(hl, obj).await?);
+    }
+    Ok(seen)
+}
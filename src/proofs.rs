@@ -0,0 +1,109 @@
+//! Merkle proofs of account data, which can be checked against a trusted
+//! `Hash<MainBlock>` without access to a `HashLookup`.  Proofs are generated
+//! by functions in `queries`.
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::blockdata::{DataNode, MainBlock, QuorumNode, RadixHashNode};
+use crate::crypto::{hash, Hash, HashCode};
+use crate::hex_path::{bytes_to_path, is_prefix, u4, HexPath};
+
+/// A proof of the `QuorumNode` of an account (or of its absence) in a main block.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub struct AccountProof {
+    /// The main block the proof is relative to.
+    pub main: MainBlock,
+    /// The `QuorumNode`s on the path from `MainBlockBody.tree` to the account.
+    /// If the account is absent, the path ends at the node proving its absence.
+    pub quorum_nodes: Vec<QuorumNode>,
+}
+
+/// A proof of the value of a field in an account's data (or of its absence).
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub struct FieldProof {
+    /// The proof of the account's `QuorumNode`.
+    pub account: AccountProof,
+    /// The `DataNode`s on the path from the account's data tree to the field.
+    /// If the field is absent, the path ends at the node proving its absence.
+    /// This is empty if the account is absent.
+    pub data_nodes: Vec<DataNode>,
+}
+
+/// Checks that a chain of nodes follows a path down a radix hash tree whose
+/// top node has a given hash.  Returns the node at the path, or `None` if the
+/// chain proves that there is no node at the path.
+pub fn verify_rh_path<'a, N: RadixHashNode>(
+    top: Hash<N>,
+    nodes: &'a [N],
+    mut path: &[u4],
+) -> Result<Option<&'a N>, anyhow::Error> {
+    let mut expected = top;
+    for (i, node) in nodes.iter().enumerate() {
+        if hash(node) != expected {
+            bail!("proof node does not match its parent's hash");
+        }
+        let is_last = i + 1 == nodes.len();
+        if path.is_empty() {
+            if !is_last {
+                bail!("proof continues past the end of its path");
+            }
+            return Ok(Some(node));
+        }
+        let rest = &path[1..];
+        match &node.get_children().0[path[0].0 as usize] {
+            Some((prefix, child)) if is_prefix(&prefix[..], rest) => {
+                if is_last {
+                    bail!("proof ends before the end of its path");
+                }
+                path = &rest[prefix.len()..];
+                expected = *child;
+            }
+            _ => {
+                if !is_last {
+                    bail!("proof continues past a missing child");
+                }
+                return Ok(None);
+            }
+        }
+    }
+    bail!("proof contains no nodes")
+}
+
+/// Verifies an `AccountProof` against a trusted main block hash, returning
+/// the account's `QuorumNode`, or `None` if the proof shows it is absent.
+pub fn verify_account_proof(
+    trusted: Hash<MainBlock>,
+    acct: HashCode,
+    proof: &AccountProof,
+) -> Result<Option<&QuorumNode>, anyhow::Error> {
+    if hash(&proof.main) != trusted {
+        bail!("proof main block does not match trusted hash");
+    }
+    let path = bytes_to_path(&acct);
+    verify_rh_path(proof.main.block.body.tree, &proof.quorum_nodes, &path[..])
+}
+
+/// Verifies a `FieldProof` against a trusted main block hash, returning the
+/// field's value, or `None` if the proof shows that the account or the field
+/// is absent.
+pub fn verify_field_proof(
+    trusted: Hash<MainBlock>,
+    acct: HashCode,
+    field: &HexPath,
+    proof: &FieldProof,
+) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    match verify_account_proof(trusted, acct, &proof.account)? {
+        None => {
+            if !proof.data_nodes.is_empty() {
+                bail!("proof of absent account must not contain data nodes");
+            }
+            Ok(None)
+        }
+        Some(qn) => match qn.body.data_tree {
+            None => bail!("account node has no data tree"),
+            Some(data_tree) => Ok(verify_rh_path(data_tree, &proof.data_nodes, &field[..])?
+                .and_then(|dn| dn.field.clone())),
+        },
+    }
+}
@@ -1,9 +1,10 @@
 //! Functions to fetch blockchain data using `HashLookup`.
 
 use crate::blockdata::{DataNode, MainBlock, MainBlockBody, QuorumNode, RadixHashNode};
-use crate::crypto::{hash, path_to_hash_code, HashCode};
+use crate::crypto::{hash, path_to_hash_code, Hash, HashCode};
 use crate::hashlookup::HashLookup;
 use crate::hex_path::{bytes_to_path, is_prefix, u4, HexPath};
+use crate::proofs::{AccountProof, FieldProof};
 
 use anyhow::{anyhow, bail};
 
@@ -13,7 +14,7 @@ pub fn longest_prefix_length<T: Eq>(xs: &[T], ys: &[T]) -> usize {
 }
 
 /// Follows a path in a radix hash tree.
-pub async fn rh_follow_path<HL: HashLookup, N: RadixHashNode>(
+pub async fn rh_follow_path<HL: HashLookup, N: RadixHashNode + 'static>(
     hl: &HL,
     mut node: N,
     mut path: &[u4],
@@ -35,7 +36,7 @@ pub async fn rh_follow_path<HL: HashLookup, N: RadixHashNode>(
 
         if is_prefix(&prefix[..], &rest[..]) {
             path = &rest[prefix.len()..];
-            node = hl.lookup(*child_hash).await?;
+            node = hl.lookup_cached(*child_hash).await?;
             continue;
         } else if is_prefix(&rest[..], &prefix[..]) {
             break;
@@ -64,7 +65,7 @@ pub async fn lookup_quorum_node<HL: HashLookup>(
     main: &MainBlockBody,
     path: &HexPath,
 ) -> Result<Option<(QuorumNode, HexPath)>, anyhow::Error> {
-    quorum_node_follow_path(hl, &hl.lookup(main.tree).await?, path).await
+    quorum_node_follow_path(hl, &hl.lookup_cached(main.tree).await?, path).await
 }
 
 /// Looks up an account in a given main block body.
@@ -103,7 +104,7 @@ pub async fn lookup_data_in_account<HL: HashLookup>(
     path: &HexPath,
 ) -> Result<Option<Vec<u8>>, anyhow::Error> {
     let top_dn = hl
-        .lookup(qn.body.data_tree.ok_or(anyhow!("no data tree"))?)
+        .lookup_cached(qn.body.data_tree.ok_or(anyhow!("no data tree"))?)
         .await?;
     match data_node_follow_path(hl, &top_dn, path).await? {
         None => Ok(None),
@@ -117,6 +118,78 @@ pub async fn lookup_data_in_account<HL: HashLookup>(
     }
 }
 
+/// Collects the nodes on a path down a radix hash tree, starting from a given
+/// node, as far as the path can be followed.  The last node is the one at the
+/// path if it exists; otherwise its children show that the path is absent.
+pub async fn rh_path_nodes<HL: HashLookup, N: RadixHashNode + 'static>(
+    hl: &HL,
+    mut node: N,
+    mut path: &[u4],
+) -> Result<Vec<N>, anyhow::Error> {
+    let mut nodes = Vec::new();
+    loop {
+        let next = match path.first() {
+            None => None,
+            Some(digit) => match &node.get_children().0[digit.0 as usize] {
+                Some((prefix, child)) if is_prefix(&prefix[..], &path[1..]) => {
+                    Some((*child, 1 + prefix.len()))
+                }
+                _ => None,
+            },
+        };
+        nodes.push(node);
+        match next {
+            None => return Ok(nodes),
+            Some((child, consumed)) => {
+                path = &path[consumed..];
+                node = hl.lookup_cached(child).await?;
+            }
+        }
+    }
+}
+
+/// Generates a proof of an account's `QuorumNode` (or of its absence) in a
+/// given main block.
+pub async fn prove_account<HL: HashLookup>(
+    hl: &HL,
+    main_hash: Hash<MainBlock>,
+    acct: HashCode,
+) -> Result<AccountProof, anyhow::Error> {
+    let main = hl.lookup_cached(main_hash).await?;
+    let top = hl.lookup_cached(main.block.body.tree).await?;
+    let quorum_nodes = rh_path_nodes(hl, top, &bytes_to_path(&acct)[..]).await?;
+    Ok(AccountProof { main, quorum_nodes })
+}
+
+/// Generates a proof of the value of a field in an account's data (or of the
+/// absence of the field or the account) in a given main block.
+pub async fn prove_field<HL: HashLookup>(
+    hl: &HL,
+    main_hash: Hash<MainBlock>,
+    acct: HashCode,
+    path: &HexPath,
+) -> Result<FieldProof, anyhow::Error> {
+    let account = prove_account(hl, main_hash, acct).await?;
+    let acct_node = account.quorum_nodes.last().unwrap();
+    let data_nodes = if acct_node.body.path == bytes_to_path(&acct) {
+        let top_dn = hl
+            .lookup_cached(
+                acct_node
+                    .body
+                    .data_tree
+                    .ok_or_else(|| anyhow!("no data tree"))?,
+            )
+            .await?;
+        rh_path_nodes(hl, top_dn, &path[..]).await?
+    } else {
+        Vec::new()
+    };
+    Ok(FieldProof {
+        account,
+        data_nodes,
+    })
+}
+
 /// Finds a block with a given version starting from the given block
 /// going backwards.
 pub async fn block_with_version<HL: HashLookup>(
@@ -136,7 +209,7 @@ pub async fn block_with_version<HL: HashLookup>(
         match &mb.prev {
             None => bail!("tried to get version before the first block"),
             Some(hash) => {
-                placeholder = hl.lookup(*hash).await?.block.body;
+                placeholder = hl.lookup_cached(*hash).await?.block.body;
                 mb = &placeholder;
             }
         }
@@ -144,15 +217,17 @@ pub async fn block_with_version<HL: HashLookup>(
 }
 
 /// Gets the random seed for a given main block.  The random seed changes
-/// with a period equal to `random_seed_period` in the main options.
-/// TODO real randomness
+/// with a period equal to `random_seed_period` in the main options, and is
+/// the beacon seed of the block starting the period.
 async fn random_seed_of_block<HL: HashLookup>(
     hl: &HL,
     main: &MainBlockBody,
 ) -> Result<HashCode, anyhow::Error> {
-    let period = hl.lookup(main.options).await?.random_seed_period;
+    let period = hl.lookup_cached(main.options).await?.random_seed_period;
     let version_to_get = main.version / u64::from(period) * u64::from(period);
-    Ok(hash(&block_with_version(hl, main, version_to_get).await?).code)
+    Ok(block_with_version(hl, main, version_to_get)
+        .await?
+        .random_seed)
 }
 
 /// Gets the account whose stake corresponds to the given index.
@@ -165,6 +240,7 @@ async fn stake_indexed_account<HL: HashLookup>(
     qn: &QuorumNode,
     mut stake_ix: u128,
 ) -> Result<HashCode, anyhow::Error> {
+    let mut qn = qn.clone();
     'outer: loop {
         if stake_ix >= qn.body.stats.stake {
             bail!("index exceeds total stake");
@@ -173,18 +249,21 @@ async fn stake_indexed_account<HL: HashLookup>(
         if path.len() == 64 {
             return Ok(path_to_hash_code(path));
         }
-        let mut children = Vec::new();
-        for (_, child_hash) in qn.body.children.iter_entries() {
-            children.push(hl.lookup(*child_hash).await?);
-        }
-        let child_stakes: Vec<u128> = children.iter().map(|ch| ch.body.stats.stake).collect();
+        let child_hashes: Vec<Hash<QuorumNode>> = qn
+            .body
+            .children
+            .iter_entries()
+            .map(|(_, child)| *child)
+            .collect();
         let mut sum_so_far = 0;
-        for i in 0..children.len() {
-            if stake_ix < sum_so_far + child_stakes[i] {
+        for child in hl.lookup_cached_many(&child_hashes).await? {
+            let child_stake = child.body.stats.stake;
+            if stake_ix < sum_so_far + child_stake {
                 stake_ix -= sum_so_far;
+                qn = child;
                 continue 'outer;
             }
-            sum_so_far += child_stakes[i];
+            sum_so_far += child_stake;
         }
         bail!("total stake does not equal sum of child node total stakes!")
     }
@@ -199,13 +278,13 @@ pub async fn random_account<HL: HashLookup>(
     seed: HashCode,
     rand_id: String,
 ) -> Result<HashCode, anyhow::Error> {
-    let rand_period = hl.lookup(main.options).await?.random_seed_period;
+    let rand_period = hl.lookup_cached(main.options).await?.random_seed_period;
     let mut rounded = main.version / u64::from(rand_period) * u64::from(rand_period);
     if rounded > 0 {
         rounded -= u64::from(rand_period);
     }
     let stake_main = block_with_version(hl, main, rounded).await?;
-    let top = hl.lookup(stake_main.tree).await?;
+    let top = hl.lookup_cached(stake_main.tree).await?;
     if top.body.stats.stake == 0 {
         bail!("can't select random account when there is no stake");
     }
@@ -225,7 +304,7 @@ pub async fn miner_and_signers_by_prev_block<HL: HashLookup>(
     main: &MainBlock,
 ) -> Result<(HashCode, Vec<HashCode>), anyhow::Error> {
     let body = &main.block.body;
-    let num_signers = hl.lookup(body.options).await?.main_block_signers;
+    let num_signers = hl.lookup_cached(body.options).await?.main_block_signers;
     let seed = random_seed_of_block(hl, body).await?;
     let miner = random_account(hl, body, seed, "miner".to_string()).await?;
     let mut signers = Vec::new();
@@ -242,8 +321,11 @@ pub async fn quorums_by_prev_block<HL: HashLookup>(
     main: &MainBlockBody,
     path: HexPath,
 ) -> Result<Vec<(Vec<HashCode>, u32)>, anyhow::Error> {
-    let sizes_thresholds = hl.lookup(main.options).await?.quorum_sizes_thresholds;
-    let period = hl.lookup(main.options).await?.quorum_period;
+    let sizes_thresholds = hl
+        .lookup_cached(main.options)
+        .await?
+        .quorum_sizes_thresholds;
+    let period = hl.lookup_cached(main.options).await?.quorum_period;
     let mut base_version = main.version / u64::from(period) * u64::from(period);
     if base_version > 0 {
         base_version -= u64::from(period);
@@ -0,0 +1,194 @@
+//! Rewards for creating main blocks.
+//!
+//! The fees paid by a main block's actions are not destroyed, but paid out
+//! along with the block reward.  The distinct quorum members who signed each
+//! new `QuorumNode` with a prize split the prize; other signatures on the
+//! node earn nothing.  Of the rest, the miner gets `miner_reward_percent`,
+//! and the remainder is split between the signer slots of the previous block
+//! whose accounts actually signed it, with rounding leftovers (or everything,
+//! if no slot signed) going to the miner.  Signers are paid one block late
+//! because a block's own signatures are collected after its rewards are
+//! fixed.
+//!
+//! Rewards are settled when the main block is created.  The quorums endorse
+//! the block's `endorsed_tree`, from which the rewards are computed; the
+//! block's `tree` is that tree with each reward added to the rewarded
+//! account's balance.  Crediting only rewrites the rewarded accounts' data
+//! trees and the nodes above them, keeping every node's stats, so no quorum
+//! needs to endorse it: verification recomputes the rewards and the credited
+//! tree from the endorsed one.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, bail};
+
+use crate::account_construction::{insert_into_data_tree, insert_into_rh_tree};
+use crate::account_transform::{accounts_by_key, field_balance, percent_of};
+use crate::blockdata::{MainBlock, MainBlockBody, MainOptions, QuorumNode, Reward};
+use crate::crypto::{hash, verify_sig, Hash, HashCode};
+use crate::hashlookup::{HashLookup, HashPut};
+use crate::hex_path::bytes_to_path;
+use crate::queries::{
+    lookup_data_in_account, miner_and_signers_by_prev_block, quorums_by_prev_block,
+    rh_follow_path,
+};
+
+/// Gets the money created in a main block with a given version.
+pub fn block_reward_at(opts: &MainOptions, version: u64) -> u128 {
+    if opts.block_reward_halving_period == 0 {
+        return opts.block_reward;
+    }
+    let halvings = version / opts.block_reward_halving_period;
+    u32::try_from(halvings)
+        .ok()
+        .and_then(|halvings| opts.block_reward.checked_shr(halvings))
+        .unwrap_or(0)
+}
+
+/// Gets the signer slots selected for a main block whose accounts have valid
+/// signatures in it.  A slot is listed once per time it was selected.
+async fn signed_slots<HL: HashLookup>(
+    hl: &HL,
+    main: &MainBlock,
+) -> Result<Vec<HashCode>, anyhow::Error> {
+    let prev = match main.block.body.prev {
+        // the genesis block has no selected signers
+        None => return Ok(Vec::new()),
+        Some(prev) => hl.lookup(prev).await?,
+    };
+    let (_miner, signers) = miner_and_signers_by_prev_block(hl, &prev).await?;
+    let accounts = accounts_by_key(hl, &prev.block.body, &signers).await?;
+    let signed: BTreeSet<HashCode> = main
+        .block
+        .signatures
+        .iter()
+        .filter(|sig| verify_sig(&main.block.body, *sig))
+        .filter_map(|sig| accounts.get(&hash(&sig.key).code).copied())
+        .collect();
+    Ok(signers
+        .into_iter()
+        .filter(|signer| signed.contains(signer))
+        .collect())
+}
+
+/// Computes the rewards of a main block with a given body following a given
+/// block.
+pub async fn block_rewards<HL: HashLookup>(
+    hl: &HL,
+    prev: &MainBlock,
+    body: &MainBlockBody,
+) -> Result<Vec<Reward>, anyhow::Error> {
+    let opts = hl.lookup(body.options).await?;
+    let mut rewards: BTreeMap<HashCode, u128> = BTreeMap::new();
+    let mut pool = block_reward_at(&opts, body.version);
+    let top = hl.lookup(body.endorsed_tree).await?;
+    // only nodes created for this block have fees and prizes
+    if top.body.last_main == Some(hash(prev)) {
+        pool += top
+            .body
+            .stats
+            .fee
+            .checked_sub(top.body.stats.prize)
+            .ok_or_else(|| anyhow!("quorum tree prizes exceed its fees"))?;
+        let mut stack = vec![top];
+        while let Some(node) = stack.pop() {
+            if node.body.prize > 0 {
+                let sigs = match node.signatures {
+                    None => bail!("quorum node with a prize must be signed"),
+                    Some(sigs_hash) => hl.lookup(sigs_hash).await?,
+                };
+                // signatures by keys other than those of the node's quorum
+                // members earn nothing
+                let members: Vec<HashCode> =
+                    quorums_by_prev_block(hl, &prev.block.body, node.body.path.clone())
+                        .await?
+                        .into_iter()
+                        .flat_map(|(members, _threshold)| members)
+                        .collect();
+                let accounts = accounts_by_key(hl, &prev.block.body, &members).await?;
+                let signers: BTreeSet<HashCode> = sigs
+                    .iter()
+                    .filter(|sig| verify_sig(&node.body, *sig))
+                    .filter_map(|sig| accounts.get(&hash(&sig.key).code).copied())
+                    .collect();
+                if signers.is_empty() {
+                    bail!("quorum node with a prize must be signed by a quorum member");
+                }
+                let share = node.body.prize / signers.len() as u128;
+                for signer in &signers {
+                    *rewards.entry(*signer).or_insert(0) += share;
+                }
+                pool += node.body.prize - share * signers.len() as u128;
+            }
+            let children: Vec<_> = node
+                .body
+                .children
+                .iter_entries()
+                .map(|(_, child)| *child)
+                .collect();
+            for child in hl.lookup_many(&children).await? {
+                if child.body.last_main == node.body.last_main {
+                    stack.push(child);
+                }
+            }
+        }
+    }
+    let (miner, _signers) = miner_and_signers_by_prev_block(hl, prev).await?;
+    let signers = signed_slots(hl, prev).await?;
+    let signer_share = match signers.len() as u128 {
+        0 => 0,
+        num_signers => (pool - percent_of(pool, opts.miner_reward_percent)) / num_signers,
+    };
+    for signer in &signers {
+        *rewards.entry(*signer).or_insert(0) += signer_share;
+    }
+    *rewards.entry(miner).or_insert(0) += pool - signer_share * signers.len() as u128;
+    Ok(rewards
+        .into_iter()
+        .filter(|(_, amount)| *amount > 0)
+        .map(|(account, amount)| Reward { account, amount })
+        .collect())
+}
+
+/// Credits rewards to the balances of the rewarded accounts in a quorum
+/// tree, returning the new tree.  The account nodes keep their stats, and
+/// lose their signatures along with their ancestors.
+pub async fn credit_rewards<HL: HashLookup + HashPut>(
+    hl: &mut HL,
+    mut tree: Hash<QuorumNode>,
+    rewards: &[Reward],
+) -> Result<Hash<QuorumNode>, anyhow::Error> {
+    for reward in rewards {
+        let path = bytes_to_path(&reward.account);
+        let mut node = match rh_follow_path(hl, hl.lookup(tree).await?, &path[..]).await? {
+            Some((node, rest)) if rest.is_empty() => node,
+            _ => bail!("rewarded account does not exist"),
+        };
+        let balance: u128 = match lookup_data_in_account(hl, &node, &field_balance().path).await? {
+            None => bail!("rewarded account has no balance"),
+            Some(bs) => rmp_serde::from_read(bs.as_slice())?,
+        };
+        let balance = balance
+            .checked_add(reward.amount)
+            .ok_or_else(|| anyhow!("reward overflows balance"))?;
+        let data_tree = node
+            .body
+            .data_tree
+            .ok_or_else(|| anyhow!("account has no data tree"))?;
+        let mut node_count = 0;
+        node.body.data_tree = Some(
+            insert_into_data_tree(
+                hl,
+                &mut node_count,
+                &field_balance().path[..],
+                rmp_serde::to_vec_named(&balance)?,
+                data_tree,
+            )
+            .await?,
+        );
+        node.signatures = None;
+        tree = insert_into_rh_tree(hl, &mut node_count, &path[..], |_| Ok(node), tree).await?;
+    }
+    Ok(tree)
+}
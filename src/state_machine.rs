@@ -8,21 +8,23 @@
 //!   |                     |
 //!   v                     v
 //! state 1       ------> state 2
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::future::Future;
 use std::pin::Pin;
 
 use futures_lite::FutureExt;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::account_transform::{
-    field_balance, field_public_key, field_received, field_stake, run_action, AccountTransform,
+    field_balance, field_nonce, field_public_key, field_received, field_signers, field_stake,
+    field_unbonding, run_action, AccountTransform, TypedDataField, SIGNED_COMMANDS,
 };
 use crate::blockdata::{
-    AccountInit, Action, DataNode, MainBlock, MainBlockBody, QuorumNode, SendInfo,
+    AccountInit, Action, DataNode, MainBlock, MainBlockBody, QuorumNode, Reward, SendInfo,
+    UnbondingInfo,
 };
-use crate::crypto::{hash, path_to_hash_code, Hash, HashCode};
+use crate::crypto::{hash, path_to_hash_code, verify_sig, Hash, HashCode, Signature};
 use crate::hashlookup::HashLookup;
 use crate::hex_path::{bytes_to_path, HexPath};
 
@@ -60,6 +62,18 @@ impl AccountState {
         }
     }
 
+    /// The unstaked funds of this account that have not been withdrawn yet.
+    pub fn unbondings(&self) -> Vec<UnbondingInfo> {
+        let prefix = bytes_to_path(b"unbonding");
+        let mut res = Vec::new();
+        for (path, value) in &self.fields {
+            if path.len() >= prefix.len() && path[0..prefix.len()][..] == prefix[..] {
+                res.push(rmp_serde::from_read::<_, UnbondingInfo>(value.as_slice()).unwrap());
+            }
+        }
+        res
+    }
+
     /// The balance of the account.
     pub fn balance(&self) -> u128 {
         rmp_serde::from_read::<_, u128>(self.fields.get(&field_balance().path).unwrap().as_slice())
@@ -71,6 +85,27 @@ impl AccountState {
         rmp_serde::from_read::<_, u128>(self.fields.get(&field_stake().path).unwrap().as_slice())
             .unwrap()
     }
+
+    /// The nonce the account's next signed action must have.
+    pub fn nonce(&self) -> u64 {
+        match self.fields.get(&field_nonce().path) {
+            None => 0,
+            Some(value) => rmp_serde::from_read::<_, u64>(value.as_slice()).unwrap(),
+        }
+    }
+
+    /// Gets the value of a typed field, if it is set.
+    fn get<T: DeserializeOwned>(&self, field: &TypedDataField<T>) -> Option<T> {
+        self.fields
+            .get(&field.path)
+            .map(|value| rmp_serde::from_read(value.as_slice()).unwrap())
+    }
+
+    /// Sets the value of a typed field.
+    fn set<T: Serialize>(&mut self, field: &TypedDataField<T>, value: &T) {
+        self.fields
+            .insert(field.path.clone(), rmp_serde::to_vec_named(value).unwrap());
+    }
 }
 
 impl fmt::Display for AccountState {
@@ -131,6 +166,20 @@ impl MainState {
             accounts: BTreeMap::new(),
         }
     }
+
+    /// The total stake of all accounts.
+    pub fn total_stake(&self) -> u128 {
+        self.accounts.values().map(|acct| acct.stake()).sum()
+    }
+
+    /// Credits a block's rewards to the balances of the rewarded accounts.
+    pub fn credit_rewards(&mut self, rewards: &[Reward]) {
+        for reward in rewards {
+            let acct = self.accounts.get_mut(&reward.account).unwrap();
+            let balance = acct.balance() + reward.amount;
+            acct.set(&field_balance(), &balance);
+        }
+    }
 }
 
 impl fmt::Display for MainState {
@@ -146,19 +195,24 @@ impl fmt::Display for MainState {
 /// Collects account states under a given `QuorumNode` into a `MainState`.
 fn get_account_states_under<'a, HL: HashLookup>(
     hl: &'a HL,
-    node_hash: Hash<QuorumNode>,
+    node: QuorumNode,
     state: &'a mut MainState,
 ) -> Pin<Box<dyn Future<Output = Result<(), anyhow::Error>> + Send + 'a>> {
     async move {
-        let node = hl.lookup(node_hash).await?;
         let depth = node.body.path.len();
         if depth == 64 {
             let acct = path_to_hash_code(node.body.path);
             let acct_state = get_account_state(hl, node.body.data_tree.unwrap()).await?;
             state.accounts.insert(acct, acct_state);
         } else {
-            for (_, child) in node.body.children.iter_entries() {
-                get_account_states_under(hl, *child, state).await?;
+            let child_hashes: Vec<Hash<QuorumNode>> = node
+                .body
+                .children
+                .iter_entries()
+                .map(|(_, child)| *child)
+                .collect();
+            for child in hl.lookup_many(&child_hashes).await? {
+                get_account_states_under(hl, child, state).await?;
             }
         }
         Ok(())
@@ -172,7 +226,7 @@ pub async fn get_main_state<HL: HashLookup>(
     main: &MainBlockBody,
 ) -> Result<MainState, anyhow::Error> {
     let mut state = MainState::empty();
-    get_account_states_under(hl, main.tree, &mut state).await?;
+    get_account_states_under(hl, hl.lookup(main.tree).await?, &mut state).await?;
     Ok(state)
 }
 
@@ -212,16 +266,137 @@ pub async fn get_next_account_state<HL: HashLookup>(
         None => (AccountState::empty(), true),
         Some(state) => ((*state).clone(), false),
     };
-    let mut at = AccountTransform::new(hl, is_init, this_account, last_main);
-    match run_action(&mut at, action).await {
-        Ok(()) => {
-            for (field, val) in at.fields_set {
-                curr_state.fields.insert(field, val);
-            }
-            Some(curr_state)
+    // signed actions must have the account's next nonce, which they then
+    // advance; the model checks this itself rather than trusting the
+    // transform it runs for most commands
+    let signed = is_signed_command(action);
+    if signed && action.nonce != curr_state.nonce() {
+        return None;
+    }
+    let mut next_state = if is_staking_command(action) {
+        if is_init || action.last_main != last_main {
+            return None;
+        }
+        get_next_staking_state(hl, action, curr_state).await?
+    } else {
+        let gas_limit = match hl.lookup(last_main).await {
+            Ok(main) => match hl.lookup(main.block.body.options).await {
+                Ok(opts) => opts.gas_limit,
+                Err(_) => return None,
+            },
+            Err(_) => return None,
+        };
+        let mut at = AccountTransform::new(hl, is_init, this_account, last_main, gas_limit);
+        run_action(&mut at, action).await.ok()?;
+        for (field, val) in at.fields_set {
+            match val {
+                Some(val) => curr_state.fields.insert(field, val),
+                None => curr_state.fields.remove(&field),
+            };
+        }
+        curr_state
+    };
+    if signed {
+        next_state.set(&field_nonce(), &action.nonce.checked_add(1)?);
+    }
+    Some(next_state)
+}
+
+/// Whether an action is a built-in command authorized by signatures, which
+/// must use the account's next nonce.  Commands run by account code use
+/// nonces only if the code checks a signature, so those are left to the code.
+fn is_signed_command(action: &Action) -> bool {
+    SIGNED_COMMANDS.contains(&&action.command[..])
+}
+
+/// Whether an action moves funds between an account's balance, stake and
+/// unbonding records.  The model computes these itself rather than running
+/// the action.
+fn is_staking_command(action: &Action) -> bool {
+    action.command == b"stake" || action.command == b"unstake" || action.command == b"withdraw"
+}
+
+/// Gets an argument out of an action's arguments.
+fn get_arg<T: DeserializeOwned>(action: &Action, i: usize) -> Option<T> {
+    rmp_serde::from_read(action.args.get(i)?.as_slice()).ok()
+}
+
+/// Whether the signatures in the argument at a given index are valid and
+/// include enough of the account's authorized keys.
+fn is_authorized(state: &AccountState, action: &Action, i: usize) -> bool {
+    let sigs: Vec<Signature<Action>> = match get_arg(action, i) {
+        None => return false,
+        Some(sigs) => sigs,
+    };
+    let mut unsigned = action.clone();
+    unsigned.args[i] = Vec::new();
+    if !sigs.iter().all(|sig| verify_sig(&unsigned, sig)) {
+        return false;
+    }
+    let signed: BTreeSet<HashCode> = sigs.iter().map(|sig| sig.account()).collect();
+    match state.get(&field_signers()) {
+        Some(signers) => {
+            signers
+                .keys
+                .iter()
+                .filter(|key| signed.contains(&hash(*key).code))
+                .count()
+                >= signers.threshold as usize
+        }
+        None => match state.get(&field_public_key()) {
+            Some(key) => signed.contains(&hash(&key).code),
+            None => false,
+        },
+    }
+}
+
+/// Computes the next state of an existing account running a `stake`,
+/// `unstake` or `withdraw` action.  Unstaking moves funds from the stake into
+/// an unbonding record that unlocks `unbonding_period` versions after the
+/// block the action is in; withdrawing an unlocked record deletes it and
+/// moves its funds into the balance.  Gas is not modeled.
+async fn get_next_staking_state<HL: HashLookup>(
+    hl: &HL,
+    action: &Action,
+    mut state: AccountState,
+) -> Option<AccountState> {
+    if !is_authorized(&state, action, 1) {
+        return None;
+    }
+    let main = hl.lookup(action.last_main).await.ok()?;
+    let opts = hl.lookup(main.block.body.options).await.ok()?;
+    let version = main.block.body.version + 1;
+    let balance = state.balance().checked_sub(action.fee)?;
+    let stake = state.stake();
+    if action.command == b"stake" {
+        let amount: u128 = get_arg(action, 0)?;
+        state.set(&field_balance(), &balance.checked_sub(amount)?);
+        state.set(&field_stake(), &(stake + amount));
+    } else if action.command == b"unstake" {
+        let amount: u128 = get_arg(action, 0)?;
+        let unbonding = UnbondingInfo {
+            last_main: action.last_main,
+            amount,
+            unlock_version: version + opts.unbonding_period,
+            nonce: action.nonce,
+        };
+        let unbonding_field = field_unbonding(hash(&unbonding));
+        if state.get(&unbonding_field).is_some() {
+            return None;
+        }
+        state.set(&field_balance(), &balance);
+        state.set(&field_stake(), &stake.checked_sub(amount)?);
+        state.set(&unbonding_field, &unbonding);
+    } else {
+        let unbonding_hash: Hash<UnbondingInfo> = get_arg(action, 0)?;
+        let unbonding = state.get(&field_unbonding(unbonding_hash))?;
+        if version < unbonding.unlock_version {
+            return None;
         }
-        Err(_) => None,
+        state.set(&field_balance(), &(balance + unbonding.amount));
+        state.fields.remove(&field_unbonding(unbonding_hash).path);
     }
+    Some(state)
 }
 
 /// Computes the next main state given a previous state and actions to run for some subset of
@@ -0,0 +1,236 @@
+//! Structural diffs of radix hash trees.
+//!
+//! Two trees are compared top-down, and subtrees with the same hash are
+//! skipped, so the cost of a diff is proportional to the size of the change
+//! rather than the size of the trees.
+
+use std::future::Future;
+use std::pin::Pin;
+
+use futures_lite::FutureExt;
+
+use crate::blockdata::{DataNode, QuorumNode, RadixHashNode};
+use crate::crypto::{hash, path_to_hash_code, Hash, HashCode};
+use crate::hashlookup::HashLookup;
+use crate::hex_path::{is_prefix, u4, HexPath};
+use crate::history::FieldDiff;
+use crate::iteration::{PathRange, RadixTreeIter};
+
+/// A change to the value at a path in a radix hash tree.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct NodeDiff<N> {
+    /// The path of the node.
+    pub path: HexPath,
+    /// The old node, `None` if it had no value.
+    pub old: Option<N>,
+    /// The new node, `None` if it has no value.
+    pub new: Option<N>,
+}
+
+/// A subtree along with the path of its top node.
+type Subtree<N> = Option<(HexPath, Hash<N>)>;
+
+/// Gets the children of a node at a given path, along with their paths.
+fn child_subtrees<N: RadixHashNode>(path: &HexPath, node: &N) -> Vec<Subtree<N>> {
+    node.get_children()
+        .0
+        .iter()
+        .enumerate()
+        .map(|(digit, entry)| {
+            entry.as_ref().map(|(suffix, child)| {
+                let child_path = [&path[..], &[u4(digit as u8)], &suffix[..]].concat();
+                (HexPath(child_path), *child)
+            })
+        })
+        .collect()
+}
+
+/// Adds every value in a subtree to `out`, as removed if `removed`, else as
+/// added.
+async fn diff_whole_subtree<HL: HashLookup, N: RadixHashNode>(
+    hl: &HL,
+    path: HexPath,
+    tree: Hash<N>,
+    removed: bool,
+    out: &mut Vec<NodeDiff<N>>,
+) -> Result<(), anyhow::Error> {
+    let mut iter = RadixTreeIter::subtree(hl, path, tree, PathRange::with_prefix(HexPath(vec![])));
+    while let Some((path, _, node)) = iter.next_node().await? {
+        out.push(if removed {
+            NodeDiff {
+                path,
+                old: Some(node),
+                new: None,
+            }
+        } else {
+            NodeDiff {
+                path,
+                old: None,
+                new: Some(node),
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Adds the changes between two subtrees to `out`.  If both are present,
+/// the path of one's top node is a prefix of the other's, or they share a
+/// parent and the first hex digit after it.
+fn diff_subtrees<'a, HL: HashLookup, N: 'a + RadixHashNode>(
+    hl: &'a HL,
+    same_value: &'a (dyn Fn(&N, &N) -> bool + Sync),
+    old: Subtree<N>,
+    new: Subtree<N>,
+    out: &'a mut Vec<NodeDiff<N>>,
+) -> Pin<Box<dyn Future<Output = Result<(), anyhow::Error>> + Send + 'a>> {
+    async move {
+        let ((old_path, old_hash), (new_path, new_hash)) = match (old, new) {
+            (None, None) => return Ok(()),
+            (Some((path, tree)), None) => {
+                return diff_whole_subtree(hl, path, tree, true, out).await
+            }
+            (None, Some((path, tree))) => {
+                return diff_whole_subtree(hl, path, tree, false, out).await
+            }
+            (Some(old), Some(new)) => (old, new),
+        };
+        if old_path == new_path {
+            if old_hash == new_hash {
+                return Ok(());
+            }
+            let old_node: N = hl.lookup(old_hash).await?;
+            let new_node: N = hl.lookup(new_hash).await?;
+            let old_children = child_subtrees(&old_path, &old_node);
+            let new_children = child_subtrees(&new_path, &new_node);
+            let changed = match (old_node.has_value(), new_node.has_value()) {
+                (false, false) => false,
+                (true, true) => !same_value(&old_node, &new_node),
+                _ => true,
+            };
+            if changed {
+                out.push(NodeDiff {
+                    path: old_path,
+                    old: Some(old_node).filter(N::has_value),
+                    new: Some(new_node).filter(N::has_value),
+                });
+            }
+            for (old_child, new_child) in old_children.into_iter().zip(new_children) {
+                diff_subtrees(hl, same_value, old_child, new_child, out).await?;
+            }
+        } else if is_prefix(&old_path[..], &new_path[..]) {
+            // the new tree has no node at the old node's path
+            let old_node: N = hl.lookup(old_hash).await?;
+            let digit = new_path[old_path.len()].0 as usize;
+            let mut new = Some((new_path, new_hash));
+            let old_children = child_subtrees(&old_path, &old_node);
+            if old_node.has_value() {
+                out.push(NodeDiff {
+                    path: old_path,
+                    old: Some(old_node),
+                    new: None,
+                });
+            }
+            for (i, old_child) in old_children.into_iter().enumerate() {
+                let new_child = if i == digit { new.take() } else { None };
+                diff_subtrees(hl, same_value, old_child, new_child, out).await?;
+            }
+        } else if is_prefix(&new_path[..], &old_path[..]) {
+            // the old tree has no node at the new node's path
+            let new_node: N = hl.lookup(new_hash).await?;
+            let digit = old_path[new_path.len()].0 as usize;
+            let mut old = Some((old_path, old_hash));
+            let new_children = child_subtrees(&new_path, &new_node);
+            if new_node.has_value() {
+                out.push(NodeDiff {
+                    path: new_path,
+                    old: None,
+                    new: Some(new_node),
+                });
+            }
+            for (i, new_child) in new_children.into_iter().enumerate() {
+                let old_child = if i == digit { old.take() } else { None };
+                diff_subtrees(hl, same_value, old_child, new_child, out).await?;
+            }
+        } else if old_path < new_path {
+            // the subtrees are disjoint, and every path in the old one comes first
+            diff_whole_subtree(hl, old_path, old_hash, true, out).await?;
+            diff_whole_subtree(hl, new_path, new_hash, false, out).await?;
+        } else {
+            diff_whole_subtree(hl, new_path, new_hash, false, out).await?;
+            diff_whole_subtree(hl, old_path, old_hash, true, out).await?;
+        }
+        Ok(())
+    }
+    .boxed()
+}
+
+/// Computes the changes between two radix hash trees, in order of path.
+/// `same_value` determines whether two nodes with values have the same
+/// value, disregarding their children.
+pub async fn diff_rh_trees<HL: HashLookup, N: RadixHashNode>(
+    hl: &HL,
+    old: Hash<N>,
+    new: Hash<N>,
+    same_value: &(dyn Fn(&N, &N) -> bool + Sync),
+) -> Result<Vec<NodeDiff<N>>, anyhow::Error> {
+    let mut out = Vec::new();
+    let top = HexPath(Vec::new());
+    diff_subtrees(
+        hl,
+        same_value,
+        Some((top.clone(), old)),
+        Some((top, new)),
+        &mut out,
+    )
+    .await?;
+    Ok(out)
+}
+
+/// Computes the field changes between two data trees, ordered by path.
+pub async fn diff_data_trees<HL: HashLookup>(
+    hl: &HL,
+    old: Hash<DataNode>,
+    new: Hash<DataNode>,
+) -> Result<Vec<FieldDiff>, anyhow::Error> {
+    let diffs = diff_rh_trees(hl, old, new, &|a: &DataNode, b: &DataNode| {
+        a.field == b.field
+    })
+    .await?;
+    Ok(diffs
+        .into_iter()
+        .map(|diff| FieldDiff {
+            path: diff.path,
+            old: diff.old.and_then(|dn| dn.field),
+            new: diff.new.and_then(|dn| dn.field),
+        })
+        .collect())
+}
+
+/// A change to an account's `QuorumNode` between two main blocks.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct AccountDiff {
+    /// The account.
+    pub account: HashCode,
+    /// The old `QuorumNode`, `None` if the account was absent.
+    pub old: Option<Hash<QuorumNode>>,
+    /// The new `QuorumNode`, `None` if the account is absent.
+    pub new: Option<Hash<QuorumNode>>,
+}
+
+/// Computes the account changes between two quorum trees, in order of
+/// account.
+pub async fn diff_quorum_trees<HL: HashLookup>(
+    hl: &HL,
+    old: Hash<QuorumNode>,
+    new: Hash<QuorumNode>,
+) -> Result<Vec<AccountDiff>, anyhow::Error> {
+    let diffs = diff_rh_trees(hl, old, new, &|a: &QuorumNode, b: &QuorumNode| a == b).await?;
+    Ok(diffs
+        .into_iter()
+        .map(|diff| AccountDiff {
+            account: path_to_hash_code(diff.path),
+            old: diff.old.as_ref().map(hash),
+            new: diff.new.as_ref().map(hash),
+        })
+        .collect())
+}
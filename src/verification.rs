@@ -1,20 +1,26 @@
 //! Functionality for verifying parts of the blockchain.
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::future::Future;
 use std::pin::Pin;
 
 use anyhow::{anyhow, bail};
+use ed25519_dalek::PublicKey;
 use futures_lite::FutureExt;
 use serde::Serialize;
 
 use crate::account_construction::add_action_to_account;
+use crate::account_transform::accounts_by_key;
+use crate::beacon::beacon_seed;
 use crate::blockdata::{
-    MainBlock, MainBlockBody, PreSignedMainBlock, QuorumNode, QuorumNodeBody, RadixHashNode,
+    ConflictingSignatures, MainBlock, MainBlockBody, MainOptions, PreSignedMainBlock, QuorumNode,
+    QuorumNodeBody, RadixHashNode, SlashEvidence, SlashSlot,
 };
-use crate::crypto::{hash, path_to_hash_code, verify_sig, HashCode, Signature};
+use crate::crypto::{hash, path_to_hash_code, verify_sig, Hash, HashCode, Signature};
+use crate::governance::next_options;
 use crate::hashlookup::{HashLookup, HashPutOfHashLookup};
 use crate::hex_path::{is_prefix, HexPath};
 use crate::queries::{lookup_quorum_node, miner_and_signers_by_prev_block, quorums_by_prev_block};
+use crate::rewards::{block_rewards, credit_rewards};
 
 /// A score for a `QuorumNodeBody` represented its fee minus its total cost (prize and gas).
 pub async fn quorum_node_body_score<HL: HashLookup>(
@@ -72,18 +78,27 @@ async fn verify_well_formed_quorum_node_body<HL: HashLookup>(
     Ok(())
 }
 
+/// Verifies signatures and gets the accounts that made them, given the
+/// accounts whose signatures count by the hash codes of their keys (see
+/// `accounts_by_key`).  Signatures by other keys count for no account.
 fn signatures_to_signers<T: Serialize>(
     sigs: &Vec<Signature<T>>,
     signed: &T,
+    accounts: &BTreeMap<HashCode, HashCode>,
 ) -> Result<BTreeSet<HashCode>, anyhow::Error> {
+    let mut keys = BTreeSet::<HashCode>::new();
     let mut signers = BTreeSet::<HashCode>::new();
     for sig in sigs {
         if !verify_sig(signed, &sig) {
             bail!("signature invalid");
         }
-        signers.insert(hash(&sig.key).code);
+        let key = hash(&sig.key).code;
+        keys.insert(key);
+        if let Some(acct) = accounts.get(&key) {
+            signers.insert(*acct);
+        }
     }
-    if signers.len() != sigs.len() {
+    if keys.len() != sigs.len() {
         bail!("duplicate signature keys");
     }
     Ok(signers)
@@ -106,9 +121,14 @@ pub async fn verify_endorsed_quorum_node<HL: HashLookup>(
         }
         Some(sigs_hash) => {
             let sigs = hl.lookup(sigs_hash).await?;
-            let signers = signatures_to_signers(&sigs, &node.body)?;
             let quorums =
                 quorums_by_prev_block(hl, &last_main.block.body, node.body.path.clone()).await?;
+            let members: Vec<HashCode> = quorums
+                .iter()
+                .flat_map(|(members, _)| members.iter().copied())
+                .collect();
+            let accounts = accounts_by_key(hl, &last_main.block.body, &members).await?;
+            let signers = signatures_to_signers(&sigs, &node.body, &accounts)?;
             let mut satisfied = false;
             'outer: for (quorum, threshold) in quorums {
                 if sigs.len() as u32 >= threshold {
@@ -164,7 +184,7 @@ fn verify_valid_quorum_node_body<'a, HL: HashLookup>(
                             for (new_child_suffix, _) in qnb.children.iter_entries() {
                                 if is_prefix(
                                     &new_child_suffix[..],
-                                    &suffix[prev_child_suffix.len()..],
+                                    &prev_child_suffix[suffix.len()..],
                                 ) {
                                     continue 'outer;
                                 }
@@ -175,8 +195,12 @@ fn verify_valid_quorum_node_body<'a, HL: HashLookup>(
                 }
             }
             // check that new children are endorsed
-            for (_child_suffix, child_hash) in qnb.children.iter_entries() {
-                let child = hl.lookup(*child_hash).await?;
+            let child_hashes: Vec<Hash<QuorumNode>> = qnb
+                .children
+                .iter_entries()
+                .map(|(_, child)| *child)
+                .collect();
+            for child in hl.lookup_many(&child_hashes).await? {
                 if Some((child.clone(), HexPath(vec![])))
                     != lookup_quorum_node(hl, &last_main.block.body, &child.body.path).await?
                 {
@@ -230,26 +254,62 @@ async fn verify_well_formed_main_block_body<HL: HashLookup>(
     Ok(())
 }
 
+/// Verifies that `MainOptions` can be used to run the chain.
+pub fn verify_options(opts: &MainOptions) -> Result<(), anyhow::Error> {
+    if opts.timestamp_period_ms == 0 || opts.random_seed_period == 0 || opts.quorum_period == 0 {
+        bail!("option periods must be positive");
+    }
+    if opts.main_block_signatures_required > opts.main_block_signers {
+        bail!("main block signatures required exceed main block signers");
+    }
+    if opts.slash_percent > 100 || opts.slash_reporter_percent > 100 {
+        bail!("slash percentages must be at most 100");
+    }
+    if opts.options_vote_percent == 0 || opts.options_vote_percent > 100 {
+        bail!("options vote percentage must be between 1 and 100");
+    }
+    if opts.miner_reward_percent > 100 {
+        bail!("miner reward percentage must be at most 100");
+    }
+    Ok(())
+}
+
 /// Verifies that a `MainBlockBody` is valid.
 pub async fn verify_valid_main_block_body<HL: HashLookup>(
     hl: &HL,
     main: &MainBlockBody,
 ) -> Result<(), anyhow::Error> {
     verify_well_formed_main_block_body(hl, main).await?;
-    let top = hl.lookup(main.tree).await?;
+    let top = hl.lookup(main.endorsed_tree).await?;
     if !top.body.path.is_empty() {
         bail!("top quorum node must have empty path");
     }
     match main.prev {
-        None => Ok(()),
+        None => {
+            if !main.rewards.is_empty() {
+                bail!("genesis block must have no rewards");
+            }
+            if main.tree != main.endorsed_tree {
+                bail!("genesis block must have its endorsed tree as its tree");
+            }
+            // later options are checked when they are proposed
+            verify_options(&hl.lookup(main.options).await?)
+        }
         Some(prev_hash) => {
             let prev = hl.lookup(prev_hash).await?;
-            if main.options != prev.block.body.options {
-                bail!("options must not change");
+            if main.options != next_options(hl, &prev.block.body).await? {
+                bail!("options must only change when a proposal activates");
             }
-            if main.tree != prev.block.body.tree {
+            if main.endorsed_tree != prev.block.body.tree {
                 verify_endorsed_quorum_node(hl, &prev, &top).await?;
             }
+            if main.rewards != block_rewards(hl, &prev, main).await? {
+                bail!("rewards do not match the block");
+            }
+            let mut hp = HashPutOfHashLookup::new(hl);
+            if main.tree != credit_rewards(&mut hp, main.endorsed_tree, &main.rewards).await? {
+                bail!("tree does not credit the rewards to the endorsed tree");
+            }
             Ok(())
         }
     }
@@ -266,11 +326,14 @@ pub async fn verify_endorsed_pre_signed_main_block<HL: HashLookup>(
         Some(prev_hash) => {
             let prev = hl.lookup(prev_hash).await?;
             verify_well_formed_main_block_body(hl, &main.body).await?;
-            let signers = signatures_to_signers(&main.signatures, &main.body)?;
-            let (_miner, needed_signers) = miner_and_signers_by_prev_block(hl, &prev).await?;
+            let (miner, needed_signers) = miner_and_signers_by_prev_block(hl, &prev).await?;
+            let participants: Vec<HashCode> =
+                needed_signers.iter().copied().chain(Some(miner)).collect();
+            let accounts = accounts_by_key(hl, &prev.block.body, &participants).await?;
+            let signers = signatures_to_signers(&main.signatures, &main.body, &accounts)?;
             let mut count = 0;
-            for signer in needed_signers {
-                if signers.contains(&signer) {
+            for signer in &needed_signers {
+                if signers.contains(signer) {
                     count += 1;
                 }
             }
@@ -278,6 +341,16 @@ pub async fn verify_endorsed_pre_signed_main_block<HL: HashLookup>(
             if count < opts.main_block_signatures_required {
                 bail!("not enough main signatures");
             }
+            let seed = beacon_seed(
+                &prev.block.body,
+                miner,
+                &needed_signers,
+                &accounts,
+                &main.body.beacon,
+            )?;
+            if seed != main.body.random_seed {
+                bail!("random seed does not follow from beacon shares");
+            }
             Ok(())
         }
     }
@@ -294,8 +367,9 @@ pub async fn verify_endorsed_main_block<HL: HashLookup>(
         Some(prev_hash) => {
             let prev = hl.lookup(prev_hash).await?;
             verify_endorsed_pre_signed_main_block(hl, &main.block).await?;
-            let signers = signatures_to_signers(&vec![main.signature], &main.block)?;
             let (miner, _signers) = miner_and_signers_by_prev_block(hl, &prev).await?;
+            let accounts = accounts_by_key(hl, &prev.block.body, &[miner]).await?;
+            let signers = signatures_to_signers(&vec![main.signature], &main.block, &accounts)?;
             if !signers.contains(&miner) {
                 bail!("main must be signed by miner");
             }
@@ -304,6 +378,119 @@ pub async fn verify_endorsed_main_block<HL: HashLookup>(
     }
 }
 
+/// Verifies that two signatures are by the same key on different values,
+/// returning the key.
+fn verify_conflicting_signatures<T: Serialize>(
+    conflict: &ConflictingSignatures<T>,
+) -> Result<PublicKey, anyhow::Error> {
+    if !verify_sig(&conflict.first, &conflict.first_signature)
+        || !verify_sig(&conflict.second, &conflict.second_signature)
+    {
+        bail!("evidence signature invalid");
+    }
+    if conflict.first_signature.key != conflict.second_signature.key {
+        bail!("evidence signatures are by different keys");
+    }
+    if hash(&conflict.first) == hash(&conflict.second) {
+        bail!("evidence signatures are of the same value");
+    }
+    Ok(conflict.first_signature.key)
+}
+
+/// Verifies evidence of an offense, returning the key that signed the
+/// conflicting values and the slot of the offense.
+pub fn verify_slash_evidence(
+    evidence: &SlashEvidence,
+) -> Result<(PublicKey, SlashSlot), anyhow::Error> {
+    match evidence {
+        SlashEvidence::Quorum(conflict) => {
+            if conflict.first.last_main != conflict.second.last_main
+                || conflict.first.path != conflict.second.path
+            {
+                bail!("quorum node bodies are for different slots");
+            }
+            let key = verify_conflicting_signatures(conflict)?;
+            let slot = SlashSlot::Quorum {
+                last_main: conflict.first.last_main,
+                path: conflict.first.path.clone(),
+            };
+            Ok((key, slot))
+        }
+        SlashEvidence::Miner(conflict) => {
+            if conflict.first.body.version != conflict.second.body.version {
+                bail!("main blocks are for different slots");
+            }
+            // the signer sets may differ, e.g. after collecting one more
+            // signature, without the miner having mined two blocks
+            if conflict.first.body == conflict.second.body {
+                bail!("main blocks have the same body");
+            }
+            let key = verify_conflicting_signatures(conflict)?;
+            let slot = SlashSlot::Miner {
+                version: conflict.first.body.version,
+            };
+            Ok((key, slot))
+        }
+    }
+}
+
+/// Finds the offender of slash evidence signed by a given key: the account
+/// selected for the slot of the offense (as the miner of both main blocks or
+/// as a member of one of the quorums for the quorum nodes) that the key
+/// signs for in that slot.  Keys are attributed to accounts as in
+/// endorsement (see `accounts_by_key`), so a key the account has rotated
+/// away from is no offender: its signatures never endorsed anything.
+pub async fn find_offender<HL: HashLookup>(
+    hl: &HL,
+    evidence: &SlashEvidence,
+    key: &PublicKey,
+) -> Result<HashCode, anyhow::Error> {
+    let key = hash(key).code;
+    match evidence {
+        SlashEvidence::Quorum(conflict) => {
+            let last_main = match conflict.first.last_main {
+                None => bail!("quorum nodes without a last main block have no quorum"),
+                Some(last_main) => hl.lookup(last_main).await?,
+            };
+            let quorums =
+                quorums_by_prev_block(hl, &last_main.block.body, conflict.first.path.clone())
+                    .await?;
+            let members: Vec<HashCode> = quorums
+                .into_iter()
+                .flat_map(|(members, _)| members)
+                .collect();
+            match accounts_by_key(hl, &last_main.block.body, &members)
+                .await?
+                .get(&key)
+            {
+                Some(member) => Ok(*member),
+                None => bail!("offender was not selected for a quorum in this slot"),
+            }
+        }
+        SlashEvidence::Miner(conflict) => {
+            let mut miners = BTreeSet::new();
+            for block in &[&conflict.first, &conflict.second] {
+                let prev = match block.body.prev {
+                    None => bail!("genesis block has no selected miner"),
+                    Some(prev) => hl.lookup(prev).await?,
+                };
+                let (miner, _signers) = miner_and_signers_by_prev_block(hl, &prev).await?;
+                if !accounts_by_key(hl, &prev.block.body, &[miner])
+                    .await?
+                    .contains_key(&key)
+                {
+                    bail!("offender was not the selected miner in this slot");
+                }
+                miners.insert(miner);
+            }
+            match miners.into_iter().collect::<Vec<_>>()[..] {
+                [miner] => Ok(miner),
+                _ => bail!("main blocks were mined by different accounts"),
+            }
+        }
+    }
+}
+
 /// Verifies that a `MainBlock` is valid and endorsed.
 pub async fn verify_valid_endorsed_main_block<HL: HashLookup>(
     hl: &HL,
@@ -0,0 +1,392 @@
+//! A small deterministic stack machine for running account code.
+//!
+//! An account may store code (a serialized `Vec<Instr>`) in `field_code`.
+//! Actions on the account whose command is not built in run this code
+//! instead.  The code can read and write the account's storage, read fields
+//! of any account as of `last_main`, check signatures in the action's
+//! arguments, and send money from the account.  Since the action itself is
+//! not signed, the code decides who may spend the account's funds: it must
+//! pay the action's fee with `PayFee`, or the action fails.  Every
+//! instruction consumes gas from the action's `GasMeter`, and the stack,
+//! value sizes and number of steps are bounded, so execution always
+//! terminates.
+
+use serde::{Deserialize, Serialize};
+
+use crate::account_transform::{do_send, pay_fee, use_nonce, AccountTransform, GAS_SIGNATURE};
+use crate::blockdata::{Action, SendInfo};
+use crate::crypto::{verify_sig, HashCode, Signature};
+use crate::hashlookup::HashLookup;
+use crate::hex_path::{bytes_to_path, HexPath};
+
+use anyhow::{anyhow, bail};
+use std::convert::{TryFrom, TryInto};
+
+/// Gas charged for each instruction executed.
+pub const GAS_INSTRUCTION: u128 = 1;
+
+/// Gas charged per byte of a value created by an instruction.
+pub const GAS_PER_VALUE_BYTE: u128 = 1;
+
+/// The maximum number of instructions executed in one action.
+pub const MAX_STEPS: u64 = 1_000_000;
+
+/// The maximum number of values on the stack.
+pub const MAX_STACK: usize = 1024;
+
+/// The maximum size of a byte string value.
+pub const MAX_VALUE_BYTES: usize = 64 * 1024;
+
+/// A value on the stack.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub enum Value {
+    /// An unsigned integer.
+    Int(u128),
+    /// A byte string.
+    Bytes(Vec<u8>),
+}
+
+/// An instruction.  Instructions that pop several values list them from the
+/// top of the stack down.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub enum Instr {
+    /// Pushes an integer.
+    PushInt(u128),
+    /// Pushes a byte string.
+    PushBytes(Vec<u8>),
+    /// Pops a value.
+    Drop,
+    /// Pushes a copy of the value at a given depth, where 0 is the top.
+    Pick(u32),
+    /// Swaps the top two values.
+    Swap,
+    /// Pops integers `b` and `a` and pushes `a + b`, failing on overflow.
+    Add,
+    /// Pops integers `b` and `a` and pushes `a - b`, failing on underflow.
+    Sub,
+    /// Pops integers `b` and `a` and pushes `a * b`, failing on overflow.
+    Mul,
+    /// Pops integers `b` and `a` and pushes `a / b`, failing if `b` is 0.
+    Div,
+    /// Pops integers `b` and `a` and pushes `a % b`, failing if `b` is 0.
+    Mod,
+    /// Pops two values and pushes 1 if they are equal, otherwise 0.
+    Eq,
+    /// Pops integers `b` and `a` and pushes 1 if `a < b`, otherwise 0.
+    Lt,
+    /// Pops an integer and pushes 1 if it is 0, otherwise 0.
+    Not,
+    /// Pops byte strings `b` and `a` and pushes `a` followed by `b`.
+    Concat,
+    /// Pops a byte string and pushes its length.
+    Len,
+    /// Continues at a given instruction.
+    Jump(u32),
+    /// Pops an integer and continues at a given instruction if it is not 0.
+    JumpIf(u32),
+    /// Ends execution successfully.  Running past the last instruction does
+    /// the same.
+    Halt,
+    /// Aborts the action.
+    Fail,
+    /// Pushes the action's command.
+    Command,
+    /// Pops an index and pushes the action argument at that index.
+    Arg,
+    /// Pushes the hash code of the account running the code.
+    ThisAccount,
+    /// Pushes the version of the main block the action is part of.
+    Version,
+    /// Pushes the action's fee.
+    Fee,
+    /// Pays the action's fee from this account.  Code must do this exactly
+    /// once for the action to succeed.
+    PayFee,
+    /// Pops an integer and pushes its msgpack encoding, which is how integer
+    /// fields such as balances are stored.
+    EncodeInt,
+    /// Pops a msgpack-encoded integer and pushes the integer.
+    DecodeInt,
+    /// Pops a key and pushes the account's storage value for that key, or
+    /// an empty byte string if there is none.
+    GetStorage,
+    /// Pops a value and a key and sets the account's storage value for that
+    /// key.
+    SetStorage,
+    /// Pops a field name and an account and pushes the value of the field
+    /// `bytes_to_path(name)` in that account, or an empty byte string if
+    /// there is none.  Other accounts are read as of `last_main`.
+    GetField,
+    /// Pops a message, an amount and a recipient and sends the amount from
+    /// this account.
+    Send,
+    /// Pops a public key and an argument index and pushes 1 if the argument
+    /// is a vector of signatures of the action (with the argument replaced by
+    /// an empty vector) including a valid signature by the key, otherwise 0.
+    /// The first valid signature also uses the action's nonce, which must be
+    /// the account's next nonce, so signed actions are ordered and can't be
+    /// replayed.  Actions the code accepts without a signature leave the
+    /// nonce alone.
+    CheckSig,
+}
+
+/// The prefix of the data fields that account code can write.
+fn storage_prefix() -> Vec<u8> {
+    b"storage".to_vec()
+}
+
+/// The path of the field storing the value of a storage key of account code.
+/// Unlike other fields, the value is stored as raw bytes rather than msgpack.
+pub fn storage_path(key: &[u8]) -> HexPath {
+    bytes_to_path(&[storage_prefix(), key.to_vec()].concat())
+}
+
+/// The execution state of account code.
+struct Machine<'a> {
+    action: &'a Action,
+    stack: Vec<Value>,
+    fee_paid: bool,
+    nonce_used: bool,
+}
+
+impl<'a> Machine<'a> {
+    fn push(&mut self, value: Value) -> Result<(), anyhow::Error> {
+        if self.stack.len() >= MAX_STACK {
+            bail!("stack overflow");
+        }
+        if let Value::Bytes(bs) = &value {
+            if bs.len() > MAX_VALUE_BYTES {
+                bail!("value too large");
+            }
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<Value, anyhow::Error> {
+        self.stack.pop().ok_or_else(|| anyhow!("stack underflow"))
+    }
+
+    fn pop_int(&mut self) -> Result<u128, anyhow::Error> {
+        match self.pop()? {
+            Value::Int(n) => Ok(n),
+            Value::Bytes(_) => bail!("expected an integer"),
+        }
+    }
+
+    fn pop_bytes(&mut self) -> Result<Vec<u8>, anyhow::Error> {
+        match self.pop()? {
+            Value::Bytes(bs) => Ok(bs),
+            Value::Int(_) => bail!("expected a byte string"),
+        }
+    }
+
+    fn pop_hash_code(&mut self) -> Result<HashCode, anyhow::Error> {
+        self.pop_bytes()?
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow!("expected a 32-byte hash code"))
+    }
+
+    /// Pops two integers and pushes the result of an operation on them.
+    fn binary_op(
+        &mut self,
+        op: impl FnOnce(u128, u128) -> Option<u128>,
+    ) -> Result<(), anyhow::Error> {
+        let b = self.pop_int()?;
+        let a = self.pop_int()?;
+        let c = op(a, b).ok_or_else(|| anyhow!("arithmetic error"))?;
+        self.push(Value::Int(c))
+    }
+
+    /// Checks whether an action argument contains a valid signature by a key.
+    fn check_sig<HL: HashLookup>(
+        &self,
+        at: &mut AccountTransform<'_, HL>,
+        i: usize,
+        key: &[u8],
+    ) -> Result<bool, anyhow::Error> {
+        let arg = self
+            .action
+            .args
+            .get(i)
+            .ok_or_else(|| anyhow!("too few arguments"))?;
+        let sigs: Vec<Signature<Action>> = rmp_serde::from_read(arg.as_slice())?;
+        let mut act2 = self.action.clone();
+        act2.args[i] = Vec::new();
+        for sig in &sigs {
+            if sig.key.as_bytes()[..] == key[..] {
+                at.gas.charge(GAS_SIGNATURE)?;
+                if verify_sig(&act2, sig) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+}
+
+fn bool_value(b: bool) -> Value {
+    Value::Int(if b { 1 } else { 0 })
+}
+
+/// Runs account code for an action.  The action fails if the code fails or
+/// does not pay the fee.
+pub async fn run_code<'a, HL: HashLookup>(
+    at: &mut AccountTransform<'a, HL>,
+    action: &Action,
+    code: &[Instr],
+) -> Result<(), anyhow::Error> {
+    let mut m = Machine {
+        action,
+        stack: Vec::new(),
+        fee_paid: false,
+        nonce_used: false,
+    };
+    let mut pc = 0;
+    let mut steps = 0;
+    while let Some(instr) = code.get(pc) {
+        steps += 1;
+        if steps > MAX_STEPS {
+            bail!("account code took too many steps");
+        }
+        at.gas.charge(GAS_INSTRUCTION)?;
+        pc += 1;
+        match instr {
+            Instr::PushInt(n) => m.push(Value::Int(*n))?,
+            Instr::PushBytes(bs) => {
+                at.gas.charge(GAS_PER_VALUE_BYTE * bs.len() as u128)?;
+                m.push(Value::Bytes(bs.clone()))?;
+            }
+            Instr::Drop => {
+                m.pop()?;
+            }
+            Instr::Pick(depth) => {
+                let ix = m
+                    .stack
+                    .len()
+                    .checked_sub(*depth as usize + 1)
+                    .ok_or_else(|| anyhow!("stack underflow"))?;
+                let value = m.stack[ix].clone();
+                if let Value::Bytes(bs) = &value {
+                    at.gas.charge(GAS_PER_VALUE_BYTE * bs.len() as u128)?;
+                }
+                m.push(value)?;
+            }
+            Instr::Swap => {
+                let b = m.pop()?;
+                let a = m.pop()?;
+                m.push(b)?;
+                m.push(a)?;
+            }
+            Instr::Add => m.binary_op(|a, b| a.checked_add(b))?,
+            Instr::Sub => m.binary_op(|a, b| a.checked_sub(b))?,
+            Instr::Mul => m.binary_op(|a, b| a.checked_mul(b))?,
+            Instr::Div => m.binary_op(|a, b| a.checked_div(b))?,
+            Instr::Mod => m.binary_op(|a, b| a.checked_rem(b))?,
+            Instr::Eq => {
+                let b = m.pop()?;
+                let a = m.pop()?;
+                m.push(bool_value(a == b))?;
+            }
+            Instr::Lt => m.binary_op(|a, b| Some(if a < b { 1 } else { 0 }))?,
+            Instr::Not => {
+                let a = m.pop_int()?;
+                m.push(bool_value(a == 0))?;
+            }
+            Instr::Concat => {
+                let b = m.pop_bytes()?;
+                let a = m.pop_bytes()?;
+                at.gas
+                    .charge(GAS_PER_VALUE_BYTE * (a.len() + b.len()) as u128)?;
+                m.push(Value::Bytes([a, b].concat()))?;
+            }
+            Instr::Len => {
+                let a = m.pop_bytes()?;
+                m.push(Value::Int(a.len() as u128))?;
+            }
+            Instr::Jump(target) => pc = *target as usize,
+            Instr::JumpIf(target) => {
+                if m.pop_int()? != 0 {
+                    pc = *target as usize;
+                }
+            }
+            Instr::Halt => break,
+            Instr::Fail => bail!("account code failed"),
+            Instr::Command => m.push(Value::Bytes(action.command.clone()))?,
+            Instr::Arg => {
+                let i = m.pop_int()?;
+                let arg = usize::try_from(i)
+                    .ok()
+                    .and_then(|i| action.args.get(i))
+                    .ok_or_else(|| anyhow!("too few arguments"))?;
+                m.push(Value::Bytes(arg.clone()))?;
+            }
+            Instr::ThisAccount => m.push(Value::Bytes(at.this_account.to_vec()))?,
+            Instr::Version => m.push(Value::Int(u128::from(at.next_version().await?)))?,
+            Instr::Fee => m.push(Value::Int(action.fee))?,
+            Instr::PayFee => {
+                if m.fee_paid {
+                    bail!("account code paid the fee twice");
+                }
+                pay_fee(at, action.fee).await?;
+                m.fee_paid = true;
+            }
+            Instr::EncodeInt => {
+                let a = m.pop_int()?;
+                m.push(Value::Bytes(rmp_serde::to_vec_named(&a)?))?;
+            }
+            Instr::DecodeInt => {
+                let a = m.pop_bytes()?;
+                m.push(Value::Int(rmp_serde::from_read(a.as_slice())?))?;
+            }
+            Instr::GetStorage => {
+                let key = m.pop_bytes()?;
+                let value = at
+                    .get_data_field_bytes(at.this_account, &storage_path(&key))
+                    .await?;
+                m.push(Value::Bytes(value.unwrap_or_default()))?;
+            }
+            Instr::SetStorage => {
+                let value = m.pop_bytes()?;
+                let key = m.pop_bytes()?;
+                at.set_data_field_bytes(&storage_path(&key), value)?;
+            }
+            Instr::GetField => {
+                let name = m.pop_bytes()?;
+                let acct = m.pop_hash_code()?;
+                let value = at.get_data_field_bytes(acct, &bytes_to_path(&name)).await?;
+                m.push(Value::Bytes(value.unwrap_or_default()))?;
+            }
+            Instr::Send => {
+                let message = m.pop_bytes()?;
+                let send_amount = m.pop_int()?;
+                let recipient = m.pop_hash_code()?;
+                let send = SendInfo {
+                    last_main: at.last_main,
+                    sender: at.this_account,
+                    recipient,
+                    send_amount,
+                    initialize_spec: None,
+                    message,
+                };
+                do_send(at, &send).await?;
+            }
+            Instr::CheckSig => {
+                let key = m.pop_bytes()?;
+                let i = m.pop_int()?;
+                let i = usize::try_from(i).map_err(|_| anyhow!("too few arguments"))?;
+                let signed = m.check_sig(at, i, &key)?;
+                if signed && !m.nonce_used {
+                    use_nonce(at, action).await?;
+                    m.nonce_used = true;
+                }
+                m.push(bool_value(signed))?;
+            }
+        }
+    }
+    if !m.fee_paid {
+        bail!("account code did not pay the fee");
+    }
+    Ok(())
+}
@@ -0,0 +1,150 @@
+//! A verifiable random function (VRF) over ed25519 keys.
+//!
+//! This follows the construction of ECVRF-EDWARDS25519 (RFC 9381), with
+//! blake3 in place of SHA-512 and try-and-increment hashing to the curve.
+//! The output for a given key and input is unique, so the key holder cannot
+//! choose between outputs, and anyone with the public key can check it.
+
+use anyhow::bail;
+use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::scalar::Scalar;
+use ed25519_dalek::{ExpandedSecretKey, Keypair, PublicKey};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{hash_of_bytes, HashCode};
+
+/// A proof that a VRF output was computed correctly.
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
+pub struct VrfProof {
+    /// The compressed point the output is derived from.
+    pub gamma: [u8; 32],
+    /// The challenge scalar.
+    pub challenge: [u8; 32],
+    /// The response scalar.
+    pub response: [u8; 32],
+}
+
+/// Hashes length-prefixed byte strings to 64 bytes.
+fn wide_hash(parts: &[&[u8]]) -> [u8; 64] {
+    let mut hasher = blake3::Hasher::new();
+    for part in parts {
+        hasher.update(&(part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    let mut out = [0; 64];
+    hasher.finalize_xof().fill(&mut out);
+    out
+}
+
+/// Hashes a public key and an input to a point in the prime-order subgroup.
+fn hash_to_curve(key: &PublicKey, input: &[u8]) -> EdwardsPoint {
+    for ctr in 0u64.. {
+        let bs = [
+            &b"vrf hash to curve"[..],
+            key.as_bytes(),
+            input,
+            &ctr.to_le_bytes(),
+        ]
+        .concat();
+        if let Some(point) = CompressedEdwardsY(hash_of_bytes(&bs)).decompress() {
+            if !point.is_small_order() {
+                return point.mul_by_cofactor();
+            }
+        }
+    }
+    unreachable!()
+}
+
+/// Computes the challenge scalar of a proof.
+fn challenge(h: &EdwardsPoint, gamma: &EdwardsPoint, u: &EdwardsPoint, v: &EdwardsPoint) -> Scalar {
+    Scalar::from_bytes_mod_order_wide(&wide_hash(&[
+        b"vrf challenge",
+        h.compress().as_bytes(),
+        gamma.compress().as_bytes(),
+        u.compress().as_bytes(),
+        v.compress().as_bytes(),
+    ]))
+}
+
+/// Computes the output given the point in a proof.
+fn output(gamma: &EdwardsPoint) -> HashCode {
+    let bs = [
+        &b"vrf output"[..],
+        gamma.mul_by_cofactor().compress().as_bytes(),
+    ]
+    .concat();
+    hash_of_bytes(&bs)
+}
+
+/// Computes the VRF output of an input, along with a proof of it.
+pub fn vrf_prove(key: &Keypair, input: &[u8]) -> (HashCode, VrfProof) {
+    let expanded = ExpandedSecretKey::from(&key.secret).to_bytes();
+    let mut scalar_bytes = [0; 32];
+    scalar_bytes.copy_from_slice(&expanded[..32]);
+    let x = Scalar::from_bytes_mod_order(scalar_bytes);
+    let h = hash_to_curve(&key.public, input);
+    let gamma = x * h;
+    // the nonce is derived like an ed25519 signature's, so proofs are deterministic
+    let k = Scalar::from_bytes_mod_order_wide(&wide_hash(&[
+        b"vrf nonce",
+        &expanded[32..],
+        h.compress().as_bytes(),
+    ]));
+    let c = challenge(&h, &gamma, &(k * ED25519_BASEPOINT_POINT), &(k * h));
+    let s = k + c * x;
+    let proof = VrfProof {
+        gamma: gamma.compress().to_bytes(),
+        challenge: c.to_bytes(),
+        response: s.to_bytes(),
+    };
+    (output(&gamma), proof)
+}
+
+/// Verifies a VRF proof for an input, returning the output.
+pub fn vrf_verify(
+    key: &PublicKey,
+    input: &[u8],
+    proof: &VrfProof,
+) -> Result<HashCode, anyhow::Error> {
+    let y = match CompressedEdwardsY(key.to_bytes()).decompress() {
+        Some(y) if !y.is_small_order() => y,
+        _ => bail!("invalid VRF public key"),
+    };
+    let gamma = match CompressedEdwardsY(proof.gamma).decompress() {
+        Some(gamma) => gamma,
+        None => bail!("invalid VRF proof point"),
+    };
+    let (c, s) = match (
+        Scalar::from_canonical_bytes(proof.challenge),
+        Scalar::from_canonical_bytes(proof.response),
+    ) {
+        (Some(c), Some(s)) => (c, s),
+        _ => bail!("invalid VRF proof scalar"),
+    };
+    let h = hash_to_curve(key, input);
+    let u = EdwardsPoint::vartime_double_scalar_mul_basepoint(&-c, &y, &s);
+    let v = s * h - c * gamma;
+    if challenge(&h, &gamma, &u, &v) != c {
+        bail!("VRF proof invalid");
+    }
+    Ok(output(&gamma))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::gen_private_key;
+
+    #[test]
+    fn prove_and_verify() {
+        let key = gen_private_key();
+        let (out, proof) = vrf_prove(&key, b"input");
+        assert_eq!((out, proof.clone()), vrf_prove(&key, b"input"));
+        assert_eq!(out, vrf_verify(&key.public, b"input", &proof).unwrap());
+        assert!(vrf_verify(&key.public, b"other input", &proof).is_err());
+        assert!(vrf_verify(&gen_private_key().public, b"input", &proof).is_err());
+        let (other_out, _) = vrf_prove(&key, b"other input");
+        assert_ne!(out, other_out);
+    }
+}
@@ -1,5 +1,5 @@
 use ed25519_dalek::Keypair;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::iter::FromIterator;
 
 use mercatoria_rust::account_construction::*;
@@ -10,9 +10,28 @@ use mercatoria_rust::crypto::*;
 use mercatoria_rust::hashlookup::*;
 use mercatoria_rust::hex_path::*;
 
-use mercatoria_rust::state_machine::{genesis_state, get_account_state, get_main_state};
+use mercatoria_rust::archive::{export_account, export_chain, import_archive};
+use mercatoria_rust::beacon::{beacon_seed, mk_beacon_share, next_beacon_seed};
+use mercatoria_rust::garbage_collection::collect_garbage;
+use mercatoria_rust::governance::{activated_proposal, put_next_options, tally_votes};
+use mercatoria_rust::history::{account_history, diff_account_states};
+use mercatoria_rust::iteration::{
+    list_account_fields, list_accounts, list_data_fields, AccountFilter, PathRange,
+};
+use mercatoria_rust::light_client::{prove_endorsement, EndorsementProof, LightClient};
+use mercatoria_rust::proofs::verify_field_proof;
+use mercatoria_rust::rewards::{block_reward_at, block_rewards, credit_rewards};
+use mercatoria_rust::state_machine::{
+    genesis_state, get_account_state, get_main_state, get_next_account_state, get_next_main_state,
+    MainState,
+};
+use mercatoria_rust::tree_diff::{diff_data_trees, diff_quorum_trees, AccountDiff};
 
-use mercatoria_rust::verification::verify_valid_main_block_body;
+use mercatoria_rust::verification::{
+    quorum_node_body_score, verify_endorsed_main_block, verify_endorsed_quorum_node,
+    verify_valid_main_block_body,
+};
+use mercatoria_rust::vm::{storage_path, Instr};
 use proptest::prelude::*;
 
 use mercatoria_rust::queries;
@@ -51,6 +70,86 @@ async fn test_insert_into_data_tree(
     (hl, hash_node)
 }
 
+async fn test_delete_from_data_tree(entries: &Vec<(HexPath, Vec<u8>)>, deleted: &[bool]) {
+    let (mut hl, mut hash_node) = test_insert_into_data_tree(entries).await;
+    let mut remaining: BTreeMap<HexPath, Vec<u8>> = entries.iter().cloned().collect();
+    let mut node_count: usize = 0;
+    for ((key, _), delete) in entries.iter().zip(deleted.iter()) {
+        if *delete {
+            hash_node = delete_from_data_tree(&mut hl, &mut node_count, &key[..], hash_node)
+                .await
+                .unwrap();
+            remaining.remove(key);
+        }
+    }
+    let actual_state = get_account_state(&hl, hash_node).await.unwrap();
+    assert_eq!(
+        remaining, actual_state.fields,
+        "delete_from_data_tree should produce expected result"
+    );
+    let (_, expected_hash) =
+        test_insert_into_data_tree(&remaining.into_iter().collect::<Vec<_>>()).await;
+    assert_eq!(
+        expected_hash, hash_node,
+        "delete_from_data_tree should produce the same tree as only inserting the remaining fields"
+    );
+}
+
+async fn test_list_data_fields(entries: &Vec<(HexPath, Vec<u8>)>, range: PathRange, limit: usize) {
+    let (hl, hash_node) = test_insert_into_data_tree(entries).await;
+    let all: BTreeMap<HexPath, Vec<u8>> = entries.iter().cloned().collect();
+    let expected: Vec<(HexPath, Vec<u8>)> = all
+        .into_iter()
+        .filter(|(path, _)| range.contains(path))
+        .collect();
+    assert!(list_data_fields(&hl, hash_node, range.clone(), 0)
+        .await
+        .is_err());
+    let mut listed = Vec::new();
+    let mut next = Some(range);
+    while let Some(range) = next {
+        let page = list_data_fields(&hl, hash_node, range, limit)
+            .await
+            .unwrap();
+        assert!(page.fields.len() <= limit);
+        listed.extend(page.fields);
+        next = page.next;
+    }
+    assert_eq!(
+        expected, listed,
+        "list_data_fields should list the fields in the range in order"
+    );
+}
+
+async fn test_diff_data_trees(
+    entries: &Vec<(HexPath, Vec<u8>)>,
+    changes: &[(HexPath, Vec<u8>)],
+    deleted: &[bool],
+) {
+    let (mut hl, old_tree) = test_insert_into_data_tree(entries).await;
+    let mut new_tree = old_tree;
+    let mut node_count: usize = 0;
+    for (key, val) in changes {
+        new_tree = insert_into_data_tree(&mut hl, &mut node_count, &key[..], val.clone(), new_tree)
+            .await
+            .unwrap();
+    }
+    for ((key, _), delete) in entries.iter().zip(deleted.iter()) {
+        if *delete {
+            new_tree = delete_from_data_tree(&mut hl, &mut node_count, &key[..], new_tree)
+                .await
+                .unwrap();
+        }
+    }
+    let old_state = get_account_state(&hl, old_tree).await.unwrap();
+    let new_state = get_account_state(&hl, new_tree).await.unwrap();
+    assert_eq!(
+        diff_account_states(&old_state, &new_state),
+        diff_data_trees(&hl, old_tree, new_tree).await.unwrap(),
+        "diff_data_trees should match the diff of the loaded states"
+    );
+}
+
 async fn test_genesis_block(
     inits: &Vec<AccountInit>,
     _keys: &BTreeMap<HashCode, Keypair>,
@@ -152,6 +251,7 @@ async fn test_send_and_receive(
     let (send_act, _send_info) = mk_send(
         hash(start_main),
         fee,
+        0,
         receiver,
         amount,
         None,
@@ -169,6 +269,7 @@ async fn test_send_and_receive(
         start_main.block.body.timestamp_ms + (test_options().timestamp_period_ms as i64),
         start_main_hash,
         send_block_top_hash,
+        beacon_shares(hl, keys, start_main).await,
     )
     .await?;
 
@@ -214,6 +315,49 @@ fn test_options() -> MainOptions {
         quorum_period: 90,
         max_quorum_depth: 16,
         quorum_sizes_thresholds: vec![(3, 4)],
+        unbonding_period: 2,
+        slash_percent: 50,
+        slash_reporter_percent: 20,
+        options_vote_percent: 50,
+        block_reward: 0,
+        block_reward_halving_period: 0,
+        miner_reward_percent: 50,
+    }
+}
+
+// a chain whose genesis block has an account for each (balance, stake) pair
+struct TestChain {
+    hl: MapHashLookup,
+    keys: BTreeMap<HashCode, Keypair>,
+    // the accounts in the order of their (balance, stake) pairs
+    accts: Vec<HashCode>,
+    genesis: MainBlock,
+}
+
+// creates a genesis block signed by every account, with the first account as the miner
+fn test_chain(funds: &[(u128, u128)], opts: MainOptions) -> TestChain {
+    let mut keys = BTreeMap::new();
+    let mut accts = Vec::new();
+    let mut inits = Vec::new();
+    for (balance, stake) in funds {
+        let key = gen_private_key();
+        inits.push(AccountInit {
+            public_key: key.public,
+            balance: *balance,
+            stake: *stake,
+        });
+        accts.push(hash(&key.public).code);
+        keys.insert(hash(&key.public).code, key);
+    }
+    let (mut hl, genesis_block_body) = smol::block_on(test_genesis_block(&inits, &keys, 0, opts));
+    let genesis = PreSignedMainBlock::sign(genesis_block_body, &keys.values().collect());
+    let genesis = MainBlock::sign(genesis, &keys[&accts[0]]);
+    smol::block_on(hl.put(&genesis)).unwrap();
+    TestChain {
+        hl,
+        keys,
+        accts,
+        genesis,
     }
 }
 
@@ -261,6 +405,1321 @@ fn simple_transfer() {
     assert!(res.is_ok(), "failed to send: {}", res.unwrap_err())
 }
 
+#[test]
+fn garbage_collection_keeps_reachable_state() {
+    let TestChain {
+        mut hl, genesis, ..
+    } = test_chain(&[(100, 10)], test_options());
+    let block_hash = hash(&genesis);
+    let state = smol::block_on(get_main_state(&hl, &genesis.block.body)).unwrap();
+    let garbage = smol::block_on(hl.put(&b"unreachable".to_vec())).unwrap();
+    let stats = smol::block_on(collect_garbage(&mut hl, &[block_hash])).unwrap();
+    assert!(stats.objects_reclaimed >= 1);
+    assert!(stats.bytes_reclaimed >= b"unreachable".len() as u64);
+    assert!(smol::block_on(hl.lookup(garbage)).is_err());
+    assert_eq!(
+        state,
+        smol::block_on(get_main_state(&hl, &genesis.block.body)).unwrap()
+    );
+    let stats = smol::block_on(collect_garbage(&mut hl, &[block_hash])).unwrap();
+    assert_eq!(0, stats.objects_reclaimed);
+}
+
+#[test]
+fn garbage_collection_keeps_state_for_the_next_block() {
+    let opts = MainOptions {
+        random_seed_period: 2,
+        quorum_period: 2,
+        ..test_options()
+    };
+    let TestChain {
+        mut hl,
+        keys,
+        accts,
+        genesis,
+    } = test_chain(&[(2000, 10), (5, 3), (5, 3), (5, 3)], opts.clone());
+    let mut blocks = vec![genesis.clone()];
+    for nonce in 0..10 {
+        let prev = blocks.last().unwrap().clone();
+        let (send, _) = mk_send(
+            hash(&prev),
+            1,
+            nonce,
+            accts[1],
+            10,
+            None,
+            vec![],
+            &keys[&accts[0]],
+        );
+        let block = smol::block_on(next_block_with_actions(
+            &mut hl,
+            &keys,
+            &prev,
+            &[(accts[0], send)],
+        ));
+        blocks.push(block);
+    }
+    let head = blocks.last().unwrap().clone();
+    let stats = smol::block_on(collect_garbage(&mut hl, &[hash(&head)])).unwrap();
+    assert!(stats.objects_reclaimed >= 1);
+    // old blocks are kept, but not the state that only they reference
+    assert_eq!(
+        genesis.block.body,
+        smol::block_on(queries::block_with_version(&hl, &head.block.body, 0)).unwrap()
+    );
+    assert!(smol::block_on(hl.lookup(genesis.block.body.tree)).is_err());
+
+    // the next block can still be built and verified
+    let (send, _) = mk_send(
+        hash(&head),
+        1000,
+        10,
+        accts[1],
+        10,
+        None,
+        vec![],
+        &keys[&accts[0]],
+    );
+    let body = smol::block_on(add_action_to_account(&mut hl, &head, accts[0], &send, 0)).unwrap();
+    let sigs: Vec<Signature<QuorumNodeBody>> =
+        keys.values().map(|key| sign(key, body.clone())).collect();
+    let node = QuorumNode {
+        body,
+        signatures: Some(smol::block_on(hl.put(&sigs)).unwrap()),
+    };
+    let tree = smol::block_on(insert_new_node(&mut hl, &head, node));
+    let beacon = smol::block_on(beacon_shares(&hl, &keys, &head));
+    let body = smol::block_on(next_main_block_body(
+        &mut hl,
+        head.block.body.timestamp_ms + opts.timestamp_period_ms as i64,
+        hash(&head),
+        tree,
+        beacon,
+    ))
+    .unwrap();
+    assert!(smol::block_on(verify_valid_main_block_body(&hl, &body)).is_ok());
+}
+
+#[test]
+fn archive_round_trip() {
+    let TestChain {
+        hl, accts, genesis, ..
+    } = test_chain(&[(100, 10), (5, 0)], test_options());
+    let acct = accts[0];
+    let genesis_block_body = genesis.block.body.clone();
+    let block_hash = hash(&genesis);
+
+    let mut archive = Vec::new();
+    let exported = smol::block_on(export_chain(&hl, block_hash, 10, &mut archive)).unwrap();
+    let mut imported_hl = MapHashLookup::new();
+    let (root, imported) =
+        smol::block_on(import_archive(&mut imported_hl, archive.as_slice())).unwrap();
+    assert_eq!(block_hash, root);
+    assert_eq!(exported, imported);
+    assert_eq!(
+        smol::block_on(get_main_state(&hl, &genesis_block_body)).unwrap(),
+        smol::block_on(get_main_state(&imported_hl, &genesis_block_body)).unwrap()
+    );
+
+    let mut account_archive = Vec::new();
+    let partial =
+        smol::block_on(export_account(&hl, block_hash, acct, &mut account_archive)).unwrap();
+    assert!(partial.objects < exported.objects);
+    let mut partial_hl = MapHashLookup::new();
+    smol::block_on(import_archive(&mut partial_hl, account_archive.as_slice())).unwrap();
+    assert_eq!(
+        Some(rmp_serde::to_vec_named(&100u128).unwrap()),
+        smol::block_on(async {
+            let qn = queries::lookup_account(&partial_hl, &genesis_block_body, acct)
+                .await?
+                .unwrap();
+            queries::lookup_data_in_account(&partial_hl, &qn, &field_balance().path).await
+        })
+        .unwrap()
+    );
+
+    let last = archive.len() - 1;
+    archive[last] ^= 1;
+    let mut tampered_hl = MapHashLookup::new();
+    assert!(smol::block_on(import_archive(&mut tampered_hl, archive.as_slice())).is_err());
+}
+
+#[test]
+fn archive_exports_chain_to_depth() {
+    let TestChain {
+        mut hl,
+        keys,
+        accts,
+        genesis,
+    } = test_chain(&[(100, 10), (5, 0)], test_options());
+    let mut blocks = vec![genesis];
+    for nonce in 0..4 {
+        let prev = blocks.last().unwrap().clone();
+        let (send, _) = mk_send(
+            hash(&prev),
+            1,
+            nonce,
+            accts[1],
+            10,
+            None,
+            vec![],
+            &keys[&accts[0]],
+        );
+        blocks.push(smol::block_on(next_block_with_actions(
+            &mut hl,
+            &keys,
+            &prev,
+            &[(accts[0], send)],
+        )));
+    }
+    let head = hash(blocks.last().unwrap());
+
+    // the head and two of its predecessors, with their state
+    let mut archive = Vec::new();
+    let exported = smol::block_on(export_chain(&hl, head, 2, &mut archive)).unwrap();
+    let mut imported_hl = MapHashLookup::new();
+    let (root, imported) =
+        smol::block_on(import_archive(&mut imported_hl, archive.as_slice())).unwrap();
+    assert_eq!((head, exported), (root, imported));
+    for block in &blocks[2..] {
+        assert_eq!(
+            *block,
+            smol::block_on(imported_hl.lookup(hash(block))).unwrap()
+        );
+        assert_eq!(
+            smol::block_on(get_main_state(&hl, &block.block.body)).unwrap(),
+            smol::block_on(get_main_state(&imported_hl, &block.block.body)).unwrap()
+        );
+    }
+    for block in &blocks[..2] {
+        assert!(smol::block_on(imported_hl.lookup(hash(block))).is_err());
+    }
+    // exporting past the imported blocks needs the missing ones
+    let mut further = Vec::new();
+    assert!(smol::block_on(export_chain(&imported_hl, head, 3, &mut further)).is_err());
+
+    // the root block is the last record, so an archive without it is rejected
+    let root_len = smol::block_on(hl.lookup_bytes(head.code)).unwrap().len();
+    let without_root = &archive[..archive.len() - (32 + 4 + root_len)];
+    let err = smol::block_on(import_archive(&mut MapHashLookup::new(), without_root)).unwrap_err();
+    assert!(err.to_string().contains("root block"), "{}", err);
+    let truncated = &archive[..archive.len() - 1];
+    assert!(smol::block_on(import_archive(&mut MapHashLookup::new(), truncated)).is_err());
+    // a record claiming a huge length fails without allocating it up front
+    let mut huge = archive[..8 + 32].to_vec();
+    huge.extend_from_slice(&head.code);
+    huge.extend_from_slice(&u32::MAX.to_le_bytes());
+    huge.extend_from_slice(b"short");
+    let err =
+        smol::block_on(import_archive(&mut MapHashLookup::new(), huge.as_slice())).unwrap_err();
+    assert!(err.to_string().contains("middle of a record"), "{}", err);
+}
+
+#[test]
+fn field_proofs() {
+    let TestChain {
+        hl, accts, genesis, ..
+    } = test_chain(&[(100, 10), (5, 0)], test_options());
+    let acct = accts[0];
+    let block_hash = hash(&genesis);
+
+    let balance = field_balance().path;
+    let proof = smol::block_on(queries::prove_field(&hl, block_hash, acct, &balance)).unwrap();
+    assert_eq!(
+        Some(rmp_serde::to_vec_named(&100u128).unwrap()),
+        verify_field_proof(block_hash, acct, &balance, &proof).unwrap()
+    );
+
+    let missing_field = bytes_to_path(b"no such field");
+    let proof =
+        smol::block_on(queries::prove_field(&hl, block_hash, acct, &missing_field)).unwrap();
+    assert_eq!(
+        None,
+        verify_field_proof(block_hash, acct, &missing_field, &proof).unwrap()
+    );
+    assert!(verify_field_proof(block_hash, acct, &balance, &proof).is_err());
+
+    let missing_acct = hash(&gen_private_key().public).code;
+    let proof =
+        smol::block_on(queries::prove_field(&hl, block_hash, missing_acct, &balance)).unwrap();
+    assert!(proof.data_nodes.is_empty());
+    assert_eq!(
+        None,
+        verify_field_proof(block_hash, missing_acct, &balance, &proof).unwrap()
+    );
+
+    let mut proof = smol::block_on(queries::prove_field(&hl, block_hash, acct, &balance)).unwrap();
+    proof.data_nodes.last_mut().unwrap().field = Some(rmp_serde::to_vec_named(&1u128).unwrap());
+    assert!(verify_field_proof(block_hash, acct, &balance, &proof).is_err());
+}
+
+// creates beacon shares for the block following `prev` from every selected miner and signer
+async fn beacon_shares(
+    hl: &MapHashLookup,
+    keys: &BTreeMap<HashCode, Keypair>,
+    prev: &MainBlock,
+) -> Vec<BeaconShare> {
+    let (miner, signers) = queries::miner_and_signers_by_prev_block(hl, prev)
+        .await
+        .unwrap();
+    let participants: BTreeSet<HashCode> = signers.into_iter().chain(Some(miner)).collect();
+    // the set is ordered by account, as the shares must be
+    participants
+        .iter()
+        .map(|acct| mk_beacon_share(&prev.block.body, &keys[acct]))
+        .collect()
+}
+
+// signs a main block body with every key, using the selected miner's key as the miner
+async fn sign_next_main_block(
+    hl: &MapHashLookup,
+    keys: &BTreeMap<HashCode, Keypair>,
+    body: MainBlockBody,
+) -> MainBlock {
+    let prev = hl.lookup(body.prev.unwrap()).await.unwrap();
+    let (miner, _signers) = queries::miner_and_signers_by_prev_block(hl, &prev)
+        .await
+        .unwrap();
+    let block = PreSignedMainBlock::sign(body, &keys.values().collect());
+    MainBlock::sign(block, keys.get(&miner).unwrap())
+}
+
+// applies actions directly to the tree of the previous block, without quorum signatures
+async fn next_block_with_actions(
+    hl: &mut MapHashLookup,
+    keys: &BTreeMap<HashCode, Keypair>,
+    prev: &MainBlock,
+    actions: &[(HashCode, Action)],
+) -> MainBlock {
+    let mut tree = prev.block.body.tree;
+    for (acct, action) in actions {
+        let node = add_action_to_account(hl, prev, *acct, action, 0)
+            .await
+            .unwrap()
+            .into_unsigned();
+        let mut node_count = 0;
+        let path = bytes_to_path(acct);
+        tree = insert_into_rh_tree(hl, &mut node_count, &path[..], |_| Ok(node), tree)
+            .await
+            .unwrap();
+    }
+    let prev_body = &prev.block.body;
+    let beacon = beacon_shares(hl, keys, prev).await;
+    let mut body = MainBlockBody {
+        prev: Some(hash(prev)),
+        version: prev_body.version + 1,
+        timestamp_ms: prev_body.timestamp_ms + (test_options().timestamp_period_ms as i64),
+        tree,
+        endorsed_tree: tree,
+        options: put_next_options(hl, prev_body).await.unwrap(),
+        random_seed: next_beacon_seed(hl, prev, &beacon).await.unwrap(),
+        beacon,
+        rewards: vec![],
+    };
+    body.rewards = block_rewards(hl, prev, &body).await.unwrap();
+    body.tree = credit_rewards(hl, tree, &body.rewards).await.unwrap();
+    let block = sign_next_main_block(hl, keys, body).await;
+    hl.put(&block).await.unwrap();
+    block
+}
+
+// inserts a node created for the block after `prev` into its tree, recreating the
+// node's ancestors for that block as well
+async fn insert_new_node(
+    hl: &mut MapHashLookup,
+    prev: &MainBlock,
+    node: QuorumNode,
+) -> Hash<QuorumNode> {
+    let path = node.body.path.clone();
+    let mut ancestors = Vec::new();
+    let mut ancestor = hl.lookup(prev.block.body.tree).await.unwrap();
+    loop {
+        let digit = path[ancestor.body.path.len()].0 as usize;
+        let child = match &ancestor.body.children.0[digit] {
+            Some((_, child)) => hl.lookup(*child).await.unwrap(),
+            None => panic!("node must replace an existing account"),
+        };
+        ancestors.push(ancestor);
+        if child.body.path == path {
+            break;
+        }
+        ancestor = child;
+    }
+    let mut child_hash = hl.put(&node).await.unwrap();
+    for mut ancestor in ancestors.into_iter().rev() {
+        let digit = path[ancestor.body.path.len()].0 as usize;
+        let mut children = ancestor.body.children.clone();
+        children.0[digit].as_mut().unwrap().1 = child_hash;
+        ancestor.body.last_main = Some(hash(prev));
+        let ancestor = ancestor.replace_children(hl, children).await.unwrap();
+        child_hash = hl.put(&ancestor).await.unwrap();
+    }
+    child_hash
+}
+
+// applies an action in a new block, checking the result against the state machine
+async fn next_block_checked(
+    hl: &mut MapHashLookup,
+    keys: &BTreeMap<HashCode, Keypair>,
+    prev: &MainBlock,
+    acct: HashCode,
+    action: Action,
+) -> (MainBlock, MainState) {
+    let prev_state = get_main_state(hl, &prev.block.body).await.unwrap();
+    let mut actions = BTreeMap::new();
+    actions.insert(acct, action.clone());
+    let mut expected = get_next_main_state(hl, hash(prev), actions, &prev_state).await;
+    let block = next_block_with_actions(hl, keys, prev, &[(acct, action)]).await;
+    expected.credit_rewards(&block.block.body.rewards);
+    let state = get_main_state(hl, &block.block.body).await.unwrap();
+    assert_eq!(expected, state);
+    let top = hl.lookup(block.block.body.tree).await.unwrap();
+    assert_eq!(state.total_stake(), top.body.stats.stake);
+    (block, state)
+}
+
+#[test]
+fn light_client_follows_endorsed_blocks() {
+    let TestChain {
+        mut hl,
+        keys,
+        accts,
+        genesis,
+    } = test_chain(&[(100, 10), (5, 7)], test_options());
+    let acct = accts[0];
+    let genesis_hash = hash(&genesis);
+    let mut client = smol::block_on(LightClient::new(genesis.clone(), test_options())).unwrap();
+    assert_eq!(genesis_hash, client.best());
+
+    let next_block = |hl: &mut MapHashLookup, prev: &MainBlock, timestamp_ms: i64| {
+        smol::block_on(async {
+            let beacon = beacon_shares(hl, &keys, prev).await;
+            let body =
+                next_main_block_body(hl, timestamp_ms, hash(prev), prev.block.body.tree, beacon)
+                    .await
+                    .unwrap();
+            let block = sign_next_main_block(hl, &keys, body).await;
+            hl.put(&block).await.unwrap();
+            block
+        })
+    };
+    let block1 = next_block(&mut hl, &genesis, 10);
+    let block2 = next_block(&mut hl, &block1, 20);
+    let fork1 = next_block(&mut hl, &genesis, 30);
+
+    let empty = EndorsementProof { objects: vec![] };
+    assert!(smol::block_on(client.add_block(block1.clone(), &empty)).is_err());
+    let proof2 = smol::block_on(prove_endorsement(&hl, &block2)).unwrap();
+    assert!(smol::block_on(client.add_block(block2.clone(), &proof2)).is_err());
+    let proof1 = smol::block_on(prove_endorsement(&hl, &block1)).unwrap();
+    assert!(smol::block_on(client.add_block(block1.clone(), &proof1)).unwrap());
+    assert!(smol::block_on(client.add_block(block2.clone(), &proof2)).unwrap());
+    let proof_fork = smol::block_on(prove_endorsement(&hl, &fork1)).unwrap();
+    assert!(!smol::block_on(client.add_block(fork1.clone(), &proof_fork)).unwrap());
+    assert_eq!(hash(&block2), client.best());
+    assert!(client.contains(hash(&fork1)));
+
+    let mut forged = fork1.block.clone();
+    forged.body.timestamp_ms += 10;
+    let forged = MainBlock::sign(forged, keys.get(&acct).unwrap());
+    assert!(smol::block_on(client.add_block(forged, &proof_fork)).is_err());
+
+    let balance = field_balance().path;
+    let field_proof =
+        smol::block_on(queries::prove_field(&hl, hash(&block2), acct, &balance)).unwrap();
+    assert_eq!(
+        Some(rmp_serde::to_vec_named(&100u128).unwrap()),
+        client
+            .verify_field(hash(&block2), acct, &balance, &field_proof)
+            .unwrap()
+    );
+}
+
+#[test]
+fn account_history_timeline() {
+    let TestChain {
+        mut hl,
+        keys,
+        accts,
+        genesis,
+    } = test_chain(&[(100, 10), (5, 0)], test_options());
+    let (acct, other) = (accts[0], accts[1]);
+
+    let mut blocks = vec![genesis];
+    for version in 1..5 {
+        let prev = blocks.last().unwrap().clone();
+        let actions = if version % 2 == 0 {
+            let nonce = version / 2 - 1;
+            let (send, _) = mk_send(hash(&prev), 1, nonce, other, 10, None, vec![], &keys[&acct]);
+            vec![(acct, send)]
+        } else {
+            vec![]
+        };
+        blocks.push(smol::block_on(next_block_with_actions(
+            &mut hl, &keys, &prev, &actions,
+        )));
+    }
+    let head = hash(blocks.last().unwrap());
+
+    let history = smol::block_on(account_history(&hl, head, acct, 0)).unwrap();
+    assert_eq!(
+        vec![0, 2, 4],
+        history.iter().map(|e| e.version).collect::<Vec<_>>()
+    );
+    assert_eq!(None, history[0].new_action);
+    assert_eq!(3, history[0].diffs.len());
+    assert!(history[0].diffs.iter().all(|d| d.old.is_none()));
+    assert_eq!(
+        b"send".to_vec(),
+        history[1].new_action.as_ref().unwrap().command
+    );
+    assert_eq!(blocks[2].block.body.timestamp_ms, history[1].timestamp_ms);
+    let balances: Vec<(Option<u128>, Option<u128>)> = history[1..]
+        .iter()
+        .flat_map(|e| e.diffs.iter())
+        .filter_map(|d| d.decode(&field_balance()).unwrap())
+        .collect();
+    assert_eq!(vec![(Some(100), Some(89)), (Some(89), Some(78))], balances);
+    // balance, send and nonce
+    assert_eq!(3, history[2].diffs.len());
+
+    let recent = smol::block_on(account_history(&hl, head, acct, 3)).unwrap();
+    assert_eq!(vec![history[2].clone()], recent);
+    let other_history = smol::block_on(account_history(&hl, head, other, 0)).unwrap();
+    assert_eq!(1, other_history.len());
+    assert_eq!(0, other_history[0].version);
+}
+
+#[test]
+fn stake_unstake_and_withdraw() {
+    let TestChain {
+        mut hl,
+        keys,
+        accts,
+        genesis,
+    } = test_chain(&[(100, 10), (5, 3)], test_options());
+    let acct = accts[0];
+
+    let apply = |hl: &mut MapHashLookup, prev: &MainBlock, action: Action| {
+        smol::block_on(next_block_checked(hl, &keys, prev, acct, action))
+    };
+    let fails = |hl: &mut MapHashLookup, prev: &MainBlock, action: Action| {
+        smol::block_on(add_action_to_account(hl, prev, acct, &action, 0)).is_err()
+    };
+
+    assert!(fails(
+        &mut hl,
+        &genesis,
+        mk_stake(hash(&genesis), 1, 0, 1000, &keys[&acct])
+    ));
+    let (block1, state) = apply(
+        &mut hl,
+        &genesis,
+        mk_stake(hash(&genesis), 1, 0, 20, &keys[&acct]),
+    );
+    assert_eq!(
+        (79, 30),
+        (
+            state.accounts[&acct].balance(),
+            state.accounts[&acct].stake()
+        )
+    );
+
+    let (unstake, unbonding) = mk_unstake(&block1, &test_options(), 1, 1, 25, &keys[&acct]);
+    let (block2, state) = apply(&mut hl, &block1, unstake);
+    assert_eq!(
+        (78, 5),
+        (
+            state.accounts[&acct].balance(),
+            state.accounts[&acct].stake()
+        )
+    );
+    assert_eq!(vec![unbonding.clone()], state.accounts[&acct].unbondings());
+    assert_eq!(4, unbonding.unlock_version);
+
+    let withdraw = |prev: &MainBlock, nonce: u64| {
+        mk_withdraw(hash(prev), 1, nonce, hash(&unbonding), &keys[&acct])
+    };
+    assert!(fails(&mut hl, &block2, withdraw(&block2, 2)));
+    // the model keeps the funds locked until the unlock version too
+    let actions = vec![(acct, withdraw(&block2, 2))].into_iter().collect();
+    assert_eq!(
+        state,
+        smol::block_on(get_next_main_state(&hl, hash(&block2), actions, &state))
+    );
+    let block3 = smol::block_on(next_block_with_actions(&mut hl, &keys, &block2, &[]));
+    let (block4, state) = apply(&mut hl, &block3, withdraw(&block3, 2));
+    assert_eq!(
+        (102, 5),
+        (
+            state.accounts[&acct].balance(),
+            state.accounts[&acct].stake()
+        )
+    );
+    assert!(state.accounts[&acct].unbondings().is_empty());
+    assert!(fails(&mut hl, &block4, withdraw(&block4, 3)));
+}
+
+#[test]
+fn unstaking_twice_in_one_block_keeps_both_records() {
+    let TestChain {
+        hl,
+        keys,
+        accts,
+        genesis,
+    } = test_chain(&[(100, 10), (5, 3)], test_options());
+    let acct = accts[0];
+    let last_main = hash(&genesis);
+    let unstake = |nonce: u64| mk_unstake(&genesis, &test_options(), 1, nonce, 4, &keys[&acct]);
+    let (first, first_unbonding) = unstake(0);
+    let (second, second_unbonding) = unstake(1);
+
+    // both actions run against the same last main block, as within one block
+    let mut at = AccountTransform::new(&hl, false, acct, last_main, u128::MAX);
+    smol::block_on(run_action(&mut at, &first)).unwrap();
+    smol::block_on(run_action(&mut at, &second)).unwrap();
+    let mut state = smol::block_on(get_main_state(&hl, &genesis.block.body)).unwrap();
+    let mut actual = state.accounts[&acct].clone();
+    for (field, val) in at.fields_set {
+        match val {
+            Some(val) => actual.fields.insert(field, val),
+            None => actual.fields.remove(&field),
+        };
+    }
+    assert_eq!(2, actual.stake());
+    assert_eq!(2, actual.unbondings().len());
+    for unbonding in &[first_unbonding, second_unbonding] {
+        let path = field_unbonding(hash(unbonding)).path;
+        assert!(actual.fields.contains_key(&path));
+    }
+
+    // the model keeps both records too
+    for action in &[first, second] {
+        let next =
+            smol::block_on(get_next_account_state(&hl, last_main, acct, action, &state)).unwrap();
+        state.accounts.insert(acct, next);
+    }
+    assert_eq!(actual, state.accounts[&acct]);
+}
+
+#[test]
+fn rotated_account_is_slashed_for_its_new_key() {
+    let new_key = gen_private_key();
+    let TestChain {
+        mut hl,
+        mut keys,
+        accts,
+        genesis,
+    } = test_chain(&[(10, 40), (5, 0)], test_options());
+    let (offender, reporter) = (accts[0], accts[1]);
+    let rotate = mk_rotate_key(hash(&genesis), 1, 0, &new_key.public, &keys[&offender]);
+    let (block1, _) = smol::block_on(next_block_checked(
+        &mut hl, &keys, &genesis, offender, rotate,
+    ));
+    let old_key = keys.insert(offender, new_key).unwrap();
+    let block2 = smol::block_on(next_block_with_actions(&mut hl, &keys, &block1, &[]));
+    let mut forked = block2.block.clone();
+    forked.body.timestamp_ms += 1;
+    let evidence = |key: &Keypair| {
+        SlashEvidence::Miner(Box::new(ConflictingSignatures {
+            first_signature: sign(key, block2.block.clone()),
+            first: block2.block.clone(),
+            second_signature: sign(key, forked.clone()),
+            second: forked.clone(),
+        }))
+    };
+    // the old key no longer mines for the offender, so blocks it mines are
+    // not endorsed and signing conflicting ones is no offense
+    let old_mined = MainBlock::sign(block2.block.clone(), &old_key);
+    let err = smol::block_on(verify_endorsed_main_block(&hl, &old_mined)).unwrap_err();
+    assert!(err.to_string().contains("signed by miner"), "{}", err);
+    let old_key_slash = mk_slash(hash(&block2), 1, &evidence(&old_key), reporter);
+    let err = smol::block_on(add_action_to_account(
+        &mut hl,
+        &block2,
+        offender,
+        &old_key_slash,
+        0,
+    ))
+    .unwrap_err();
+    assert!(
+        err.to_string().contains("not the selected miner"),
+        "{}",
+        err
+    );
+    let slash = mk_slash(hash(&block2), 1, &evidence(&keys[&offender]), reporter);
+    let (_block3, state) =
+        smol::block_on(next_block_checked(&mut hl, &keys, &block2, offender, slash));
+    assert_eq!(20, state.accounts[&offender].stake());
+}
+
+#[test]
+fn rotated_key_replaces_old_key() {
+    let new_key = gen_private_key();
+    let TestChain {
+        mut hl,
+        mut keys,
+        accts,
+        genesis,
+    } = test_chain(&[(100, 10), (5, 3)], test_options());
+    let (acct, other) = (accts[0], accts[1]);
+    let fails = |hl: &mut MapHashLookup, prev: &MainBlock, action: Action| {
+        smol::block_on(add_action_to_account(hl, prev, acct, &action, 0)).is_err()
+    };
+    let send =
+        |prev: &MainBlock, key: &Keypair| mk_send(hash(prev), 1, 1, other, 10, None, vec![], key).0;
+
+    let stolen = mk_rotate_key(hash(&genesis), 1, 0, &keys[&other].public, &keys[&other]);
+    assert!(fails(&mut hl, &genesis, stolen));
+    let rotate = mk_rotate_key(hash(&genesis), 1, 0, &new_key.public, &keys[&acct]);
+    let (block1, state) =
+        smol::block_on(next_block_checked(&mut hl, &keys, &genesis, acct, rotate));
+    assert_eq!(
+        Some(&rmp_serde::to_vec_named(&new_key.public).unwrap()),
+        state.accounts[&acct].fields.get(&field_public_key().path)
+    );
+    assert_eq!(99, state.accounts[&acct].balance());
+
+    let old_key = keys.insert(acct, new_key).unwrap();
+    assert!(fails(&mut hl, &block1, send(&block1, &old_key)));
+    let rotate_back = mk_rotate_key(hash(&block1), 1, 1, &old_key.public, &old_key);
+    assert!(fails(&mut hl, &block1, rotate_back));
+    let (_block2, state) = smol::block_on(next_block_checked(
+        &mut hl,
+        &keys,
+        &block1,
+        acct,
+        send(&block1, &keys[&acct]),
+    ));
+    assert_eq!(88, state.accounts[&acct].balance());
+}
+
+#[test]
+fn rotated_key_signs_for_consensus() {
+    // a quorum of one account endorses a node with its signature alone
+    let opts = MainOptions {
+        quorum_sizes_thresholds: vec![(1, 1)],
+        ..test_options()
+    };
+    // the other account has no stake, so the rotating account is selected for every slot
+    let TestChain {
+        mut hl,
+        mut keys,
+        accts,
+        genesis,
+    } = test_chain(&[(2000, 10), (5, 0)], opts);
+    let (acct, other) = (accts[0], accts[1]);
+    let new_key = gen_private_key();
+    let rotate = mk_rotate_key(hash(&genesis), 1, 0, &new_key.public, &keys[&acct]);
+    let (block1, _) = smol::block_on(next_block_checked(&mut hl, &keys, &genesis, acct, rotate));
+    let old_key = keys.insert(acct, new_key).unwrap();
+
+    // main blocks are signed and mined with the new key only
+    let block2 = smol::block_on(next_block_with_actions(&mut hl, &keys, &block1, &[]));
+    let main_error = |block: &MainBlock| {
+        smol::block_on(verify_endorsed_main_block(&hl, block))
+            .unwrap_err()
+            .to_string()
+    };
+    assert!(smol::block_on(verify_endorsed_main_block(&hl, &block2)).is_ok());
+    let old_signed = PreSignedMainBlock::sign(block2.block.body.clone(), &vec![&old_key]);
+    let old_signed = MainBlock::sign(old_signed, &keys[&acct]);
+    assert!(main_error(&old_signed).contains("not enough main signatures"));
+    let old_mined = MainBlock::sign(block2.block.clone(), &old_key);
+    assert!(main_error(&old_mined).contains("signed by miner"));
+
+    // quorum nodes are endorsed with the new key only
+    let (send, _) = mk_send(
+        hash(&block1),
+        1000,
+        1,
+        other,
+        10,
+        None,
+        vec![],
+        &keys[&acct],
+    );
+    let body = smol::block_on(add_action_to_account(&mut hl, &block1, acct, &send, 0)).unwrap();
+    let mut endorsed = |key: &Keypair| {
+        let sigs = vec![sign(key, body.clone())];
+        let node = QuorumNode {
+            body: body.clone(),
+            signatures: Some(smol::block_on(hl.put(&sigs)).unwrap()),
+        };
+        smol::block_on(verify_endorsed_quorum_node(&hl, &block1, &node)).is_ok()
+    };
+    assert!(!endorsed(&old_key));
+    assert!(endorsed(&keys[&acct]));
+}
+
+#[test]
+fn multisig_account_requires_threshold() {
+    let approvers = [gen_private_key(), gen_private_key(), gen_private_key()];
+    let TestChain {
+        mut hl,
+        keys,
+        accts,
+        genesis,
+    } = test_chain(&[(100, 10), (5, 3)], test_options());
+    let (acct, other) = (accts[0], accts[1]);
+    let fails = |hl: &mut MapHashLookup, prev: &MainBlock, action: Action| {
+        smol::block_on(add_action_to_account(hl, prev, acct, &action, 0)).is_err()
+    };
+    let send = |prev: &MainBlock, signers: &[&Keypair]| {
+        mk_send_multisig(hash(prev), 1, 1, acct, other, 10, None, vec![], signers).0
+    };
+
+    let signer_set = |threshold| SignerSet {
+        keys: approvers.iter().map(|k| k.public).collect(),
+        threshold,
+    };
+    for threshold in &[0, 4] {
+        let bad = mk_set_signers(
+            hash(&genesis),
+            1,
+            0,
+            &signer_set(*threshold),
+            &[&keys[&acct]],
+        );
+        assert!(fails(&mut hl, &genesis, bad));
+    }
+    let unauthorized = mk_set_signers(hash(&genesis), 1, 0, &signer_set(2), &[&approvers[0]]);
+    assert!(fails(&mut hl, &genesis, unauthorized));
+    let set_signers = mk_set_signers(hash(&genesis), 1, 0, &signer_set(2), &[&keys[&acct]]);
+    let (block1, state) = smol::block_on(next_block_checked(
+        &mut hl,
+        &keys,
+        &genesis,
+        acct,
+        set_signers,
+    ));
+    assert_eq!(
+        Some(&rmp_serde::to_vec_named(&signer_set(2)).unwrap()),
+        state.accounts[&acct].fields.get(&field_signers().path)
+    );
+
+    assert!(fails(&mut hl, &block1, send(&block1, &[&keys[&acct]])));
+    assert!(fails(&mut hl, &block1, send(&block1, &[&approvers[0]])));
+    assert!(fails(
+        &mut hl,
+        &block1,
+        send(&block1, &[&approvers[0], &approvers[0]])
+    ));
+    assert!(fails(
+        &mut hl,
+        &block1,
+        send(&block1, &[&approvers[0], &keys[&other]])
+    ));
+    let rotate = mk_rotate_key(hash(&block1), 1, 1, &keys[&other].public, &keys[&acct]);
+    assert!(fails(
+        &mut hl,
+        &block1,
+        sign_action(rotate, &[&approvers[0], &approvers[1]])
+    ));
+    let (_block2, state) = smol::block_on(next_block_checked(
+        &mut hl,
+        &keys,
+        &block1,
+        acct,
+        send(&block1, &[&approvers[2], &approvers[0]]),
+    ));
+    assert_eq!(88, state.accounts[&acct].balance());
+}
+
+#[test]
+fn gas_is_metered_and_limited() {
+    // runs a send from a genesis block with a given gas limit
+    let run_send = |gas_limit: u128, fee: u128| {
+        let mut opts = test_options();
+        opts.gas_limit = gas_limit;
+        let TestChain {
+            mut hl,
+            keys,
+            accts,
+            genesis,
+        } = test_chain(&[(10000, 10), (5, 3)], opts);
+        let (acct, other) = (accts[0], accts[1]);
+        let (send, _) = mk_send(
+            hash(&genesis),
+            fee,
+            0,
+            other,
+            10,
+            None,
+            vec![],
+            &keys[&acct],
+        );
+        smol::block_on(async {
+            let node = add_action_to_account(&mut hl, &genesis, acct, &send, 0).await?;
+            let score = quorum_node_body_score(&hl, &genesis, &node).await?;
+            Ok::<_, anyhow::Error>((node.stats.gas, score))
+        })
+    };
+
+    let (gas, score) = run_send(u128::MAX, 1).unwrap();
+    assert!(gas > GAS_SIGNATURE);
+    assert_eq!(None, score);
+    let fee = 2 * gas;
+    let (paid_gas, paid_score) = run_send(u128::MAX, fee).unwrap();
+    assert_eq!(Some(fee - paid_gas), paid_score);
+    // signatures (and so the gas for their argument bytes) vary slightly between chains
+    assert!(run_send(2 * gas, 1).is_ok());
+    let err = run_send(gas / 2, 1).unwrap_err();
+    assert!(err.to_string().contains("out of gas"), "{}", err);
+}
+
+#[test]
+fn account_code_runs_custom_commands() {
+    let TestChain {
+        mut hl,
+        keys,
+        accts,
+        genesis,
+    } = test_chain(&[(1000, 10), (5, 3)], test_options());
+    let (acct, other) = (accts[0], accts[1]);
+    let owner_key = gen_private_key();
+    let fails = |hl: &mut MapHashLookup, prev: &MainBlock, action: Action| {
+        smol::block_on(add_action_to_account(hl, prev, acct, &action, 0)).is_err()
+    };
+    let custom = |prev: &MainBlock, nonce: u64, command: &[u8], args: Vec<Vec<u8>>| Action {
+        last_main: hash(prev),
+        fee: 1,
+        nonce,
+        command: command.to_vec(),
+        args,
+    };
+    let note = |prev: &MainBlock, nonce: u64, fee: u128, signer: &Keypair| {
+        let act = Action {
+            fee,
+            ..custom(prev, nonce, b"note", vec![b"hi".to_vec(), vec![]])
+        };
+        sign_action(act, &[signer])
+    };
+    let pay = |prev: &MainBlock, signer: &Keypair| {
+        let act = custom(
+            prev,
+            2,
+            b"pay",
+            vec![
+                other.to_vec(),
+                rmp_serde::to_vec_named(&10u128).unwrap(),
+                vec![],
+            ],
+        );
+        sign_action(act, &[signer])
+    };
+
+    // "note" stores its argument and "pay" sends, both only if signed by
+    // the owner key, and "note" only pays fees below 5
+    let code = vec![
+        Instr::Command,
+        Instr::PushBytes(b"note".to_vec()),
+        Instr::Eq,
+        Instr::JumpIf(9),
+        Instr::Command,
+        Instr::PushBytes(b"pay".to_vec()),
+        Instr::Eq,
+        Instr::JumpIf(25),
+        Instr::Fail,
+        Instr::PushInt(1),
+        Instr::PushBytes(owner_key.public.as_bytes().to_vec()),
+        Instr::CheckSig,
+        Instr::Not,
+        Instr::JumpIf(8),
+        Instr::Fee,
+        Instr::PushInt(5),
+        Instr::Lt,
+        Instr::Not,
+        Instr::JumpIf(8),
+        Instr::PayFee,
+        Instr::PushBytes(b"note".to_vec()),
+        Instr::PushInt(0),
+        Instr::Arg,
+        Instr::SetStorage,
+        Instr::Halt,
+        Instr::PushInt(2),
+        Instr::PushBytes(owner_key.public.as_bytes().to_vec()),
+        Instr::CheckSig,
+        Instr::Not,
+        Instr::JumpIf(8),
+        Instr::PayFee,
+        Instr::PushInt(0),
+        Instr::Arg,
+        Instr::PushInt(1),
+        Instr::Arg,
+        Instr::DecodeInt,
+        Instr::PushBytes(vec![]),
+        Instr::Send,
+    ];
+    assert!(fails(&mut hl, &genesis, note(&genesis, 0, 1, &owner_key)));
+    let unauthorized = mk_set_code(hash(&genesis), 1, 0, &code, &[&owner_key]);
+    assert!(fails(&mut hl, &genesis, unauthorized));
+    let set_code = mk_set_code(hash(&genesis), 1, 0, &code, &[&keys[&acct]]);
+    let (block1, state) =
+        smol::block_on(next_block_checked(&mut hl, &keys, &genesis, acct, set_code));
+    assert_eq!(
+        Some(&rmp_serde::to_vec_named(&code).unwrap()),
+        state.accounts[&acct].fields.get(&field_code().path)
+    );
+
+    // callers without the owner key can't spend the fee or use up the nonce,
+    // and the owner can't pay more than the code allows
+    assert!(fails(&mut hl, &block1, note(&block1, 1, 1, &keys[&other])));
+    assert!(fails(&mut hl, &block1, note(&block1, 1, 100, &owner_key)));
+    let (block2, state) = smol::block_on(next_block_checked(
+        &mut hl,
+        &keys,
+        &block1,
+        acct,
+        note(&block1, 1, 1, &owner_key),
+    ));
+    assert_eq!(
+        Some(&b"hi".to_vec()),
+        state.accounts[&acct].fields.get(&storage_path(b"note"))
+    );
+    assert_eq!(998, state.accounts[&acct].balance());
+
+    assert!(fails(
+        &mut hl,
+        &block2,
+        custom(&block2, 2, b"other", vec![])
+    ));
+    assert!(fails(&mut hl, &block2, pay(&block2, &keys[&acct])));
+    let (block3, state) = smol::block_on(next_block_checked(
+        &mut hl,
+        &keys,
+        &block2,
+        acct,
+        pay(&block2, &owner_key),
+    ));
+    assert_eq!(987, state.accounts[&acct].balance());
+    // the signature used the nonce, so the action can't run again
+    assert_eq!(3, state.accounts[&acct].nonce());
+    assert!(fails(&mut hl, &block3, pay(&block3, &owner_key)));
+
+    let looping = mk_set_code(hash(&block3), 1, 3, &[Instr::Jump(0)], &[&keys[&acct]]);
+    let (block4, _) = smol::block_on(next_block_checked(&mut hl, &keys, &block3, acct, looping));
+    let err = smol::block_on(add_action_to_account(
+        &mut hl,
+        &block4,
+        acct,
+        &custom(&block4, 4, b"spin", vec![]),
+        0,
+    ))
+    .unwrap_err();
+    assert!(err.to_string().contains("too many steps"), "{}", err);
+
+    let free = mk_set_code(hash(&block4), 1, 4, &[Instr::Halt], &[&keys[&acct]]);
+    let (block5, _) = smol::block_on(next_block_checked(&mut hl, &keys, &block4, acct, free));
+    let err = smol::block_on(add_action_to_account(
+        &mut hl,
+        &block5,
+        acct,
+        &custom(&block5, 5, b"anything", vec![]),
+        0,
+    ))
+    .unwrap_err();
+    assert!(err.to_string().contains("did not pay the fee"), "{}", err);
+
+    // code accepting actions without a signature doesn't let their callers
+    // use up the nonce of the owner's signed actions
+    let open = mk_set_code(hash(&block5), 1, 5, &[Instr::PayFee], &[&keys[&acct]]);
+    let (block6, _) = smol::block_on(next_block_checked(&mut hl, &keys, &block5, acct, open));
+    let (block7, state) = smol::block_on(next_block_checked(
+        &mut hl,
+        &keys,
+        &block6,
+        acct,
+        custom(&block6, 42, b"anything", vec![]),
+    ));
+    assert_eq!(6, state.accounts[&acct].nonce());
+    let closed = mk_set_code(hash(&block7), 1, 6, &[Instr::Halt], &[&keys[&acct]]);
+    smol::block_on(next_block_checked(&mut hl, &keys, &block7, acct, closed));
+}
+
+#[test]
+fn receive_initializes_account_from_spec() {
+    let new_key = gen_private_key();
+    let approver = gen_private_key();
+    let TestChain {
+        mut hl,
+        keys,
+        accts,
+        genesis,
+    } = test_chain(&[(100, 10)], test_options());
+    let acct = accts[0];
+    let new_acct = hash(&new_key.public).code;
+
+    let spec = InitializeSpec {
+        signers: Some(SignerSet {
+            keys: vec![approver.public],
+            threshold: 1,
+        }),
+        code: Some(vec![Instr::Halt]),
+        storage: vec![(b"owner".to_vec(), acct.to_vec())],
+    };
+    let (send, send_info) = mk_send(
+        hash(&genesis),
+        1,
+        0,
+        new_acct,
+        20,
+        Some(spec.commitment()),
+        vec![],
+        &keys[&acct],
+    );
+    let (block1, _) = smol::block_on(next_block_checked(&mut hl, &keys, &genesis, acct, send));
+    let receive = |spec: Option<&InitializeSpec>| {
+        mk_receive(hash(&block1), 1, 0, acct, hash(&send_info), spec, &new_key)
+    };
+    let fails = |hl: &mut MapHashLookup, prev: &MainBlock, action: Action| {
+        smol::block_on(add_action_to_account(hl, prev, new_acct, &action, 0)).is_err()
+    };
+
+    assert!(fails(&mut hl, &block1, receive(None)));
+    assert!(fails(
+        &mut hl,
+        &block1,
+        receive(Some(&InitializeSpec::default()))
+    ));
+    let (block2, state) = smol::block_on(next_block_checked(
+        &mut hl,
+        &keys,
+        &block1,
+        new_acct,
+        receive(Some(&spec)),
+    ));
+    let fields = &state.accounts[&new_acct].fields;
+    assert_eq!(19, state.accounts[&new_acct].balance());
+    assert_eq!(
+        Some(&rmp_serde::to_vec_named(&spec.signers.unwrap()).unwrap()),
+        fields.get(&field_signers().path)
+    );
+    assert_eq!(
+        Some(&rmp_serde::to_vec_named(&spec.code.unwrap()).unwrap()),
+        fields.get(&field_code().path)
+    );
+    assert_eq!(Some(&acct.to_vec()), fields.get(&storage_path(b"owner")));
+
+    let send_back = |signer: &Keypair| {
+        let signers: &[&Keypair] = &[signer];
+        mk_send_multisig(
+            hash(&block2),
+            1,
+            1,
+            new_acct,
+            acct,
+            5,
+            None,
+            vec![],
+            signers,
+        )
+        .0
+    };
+    assert!(fails(&mut hl, &block2, send_back(&new_key)));
+    let (_block3, state) = smol::block_on(next_block_checked(
+        &mut hl,
+        &keys,
+        &block2,
+        new_acct,
+        send_back(&approver),
+    ));
+    assert_eq!(13, state.accounts[&new_acct].balance());
+}
+
+#[test]
+fn received_sends_can_be_pruned() {
+    let TestChain {
+        mut hl,
+        keys,
+        accts,
+        genesis,
+    } = test_chain(&[(100, 10), (5, 3)], test_options());
+    let (acct, other) = (accts[0], accts[1]);
+    let fails = |hl: &mut MapHashLookup, prev: &MainBlock, acct: HashCode, action: Action| {
+        smol::block_on(add_action_to_account(hl, prev, acct, &action, 0)).is_err()
+    };
+
+    let (send, send_info) = mk_send(hash(&genesis), 1, 0, other, 10, None, vec![], &keys[&acct]);
+    let send_hash = hash(&send_info);
+    let (block1, state) = smol::block_on(next_block_checked(&mut hl, &keys, &genesis, acct, send));
+    assert_eq!(vec![send_info.clone()], state.accounts[&acct].sends());
+    let prune = |prev: &MainBlock| mk_prune_send(hash(prev), 1, 1, send_hash, &keys[&acct]);
+    assert!(fails(&mut hl, &block1, acct, prune(&block1)));
+
+    let receive =
+        |prev: &MainBlock| mk_receive(hash(prev), 1, 0, acct, send_hash, None, &keys[&other]);
+    let (block2, _) = smol::block_on(next_block_checked(
+        &mut hl,
+        &keys,
+        &block1,
+        other,
+        receive(&block1),
+    ));
+    let (block3, state) = smol::block_on(next_block_checked(
+        &mut hl,
+        &keys,
+        &block2,
+        acct,
+        prune(&block2),
+    ));
+    assert!(state.accounts[&acct].sends().is_empty());
+    assert_eq!(88, state.accounts[&acct].balance());
+    let sends = |block: &MainBlock| {
+        let range = PathRange::with_prefix(bytes_to_path(b"send"));
+        smol::block_on(list_account_fields(&hl, &block.block.body, acct, range, 10))
+            .unwrap()
+            .fields
+    };
+    assert_eq!(
+        vec![(
+            field_send(send_hash).path,
+            rmp_serde::to_vec_named(&send_info).unwrap()
+        )],
+        sends(&block2)
+    );
+    assert!(sends(&block3).is_empty());
+    assert!(fails(&mut hl, &block3, acct, prune(&block3)));
+    assert!(fails(&mut hl, &block3, other, receive(&block3)));
+}
+
+#[test]
+fn accounts_are_listed_in_pages() {
+    let funds: Vec<(u128, u128)> = (0..7).map(|i| (100, i)).collect();
+    let TestChain { hl, genesis, .. } = test_chain(&funds, test_options());
+    let genesis_block_body = genesis.block.body;
+    let state = smol::block_on(get_main_state(&hl, &genesis_block_body)).unwrap();
+    assert!(smol::block_on(list_accounts(
+        &hl,
+        &genesis_block_body,
+        None,
+        AccountFilter::default(),
+        0
+    ))
+    .is_err());
+
+    let list_all = |filter: AccountFilter, limit: usize| {
+        let mut listed = Vec::new();
+        let mut start = None;
+        loop {
+            let page = smol::block_on(list_accounts(
+                &hl,
+                &genesis_block_body,
+                start,
+                filter.clone(),
+                limit,
+            ))
+            .unwrap();
+            assert!(page.accounts.len() <= limit);
+            listed.extend(page.accounts);
+            match page.next {
+                None => return listed,
+                Some(next) => start = Some(next),
+            }
+        }
+    };
+    for limit in 1..4 {
+        let listed = list_all(AccountFilter::default(), limit);
+        assert_eq!(
+            state.accounts.keys().cloned().collect::<Vec<_>>(),
+            listed.iter().map(|(acct, _, _)| *acct).collect::<Vec<_>>()
+        );
+        for (acct, node_hash, stats) in &listed {
+            let node = smol::block_on(queries::lookup_account(&hl, &genesis_block_body, *acct))
+                .unwrap()
+                .unwrap();
+            assert_eq!(hash(&node), *node_hash);
+            assert_eq!(node.body.stats, *stats);
+        }
+    }
+    let staked = list_all(AccountFilter { min_stake: 4 }, 2);
+    assert_eq!(
+        state
+            .accounts
+            .iter()
+            .filter(|(_, acct_state)| acct_state.stake() >= 4)
+            .map(|(acct, _)| *acct)
+            .collect::<Vec<_>>(),
+        staked.iter().map(|(acct, _, _)| *acct).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn quorum_tree_diff_lists_changed_accounts() {
+    let TestChain {
+        mut hl,
+        keys,
+        accts,
+        genesis,
+    } = test_chain(&[(100, 10), (5, 3), (5, 3)], test_options());
+    let (acct, other) = (accts[0], accts[1]);
+    let new_key = gen_private_key();
+    let new_acct = hash(&new_key.public).code;
+
+    let (send, send_info) = mk_send(
+        hash(&genesis),
+        1,
+        0,
+        new_acct,
+        10,
+        None,
+        vec![],
+        &keys[&acct],
+    );
+    let stake = mk_stake(hash(&genesis), 1, 0, 2, &keys[&other]);
+    let block1 = smol::block_on(next_block_with_actions(
+        &mut hl,
+        &keys,
+        &genesis,
+        &[(acct, send), (other, stake)],
+    ));
+    let receive = mk_receive(hash(&block1), 1, 0, acct, hash(&send_info), None, &new_key);
+    let block2 = smol::block_on(next_block_with_actions(
+        &mut hl,
+        &keys,
+        &block1,
+        &[(new_acct, receive)],
+    ));
+
+    let node = |block: &MainBlock, acct: HashCode| {
+        smol::block_on(queries::lookup_account(&hl, &block.block.body, acct))
+            .unwrap()
+            .map(|qn| hash(&qn))
+    };
+    let diff = |old: &MainBlock, new: &MainBlock| {
+        smol::block_on(diff_quorum_trees(
+            &hl,
+            old.block.body.tree,
+            new.block.body.tree,
+        ))
+        .unwrap()
+    };
+    let expected = |old: &MainBlock, new: &MainBlock, mut accts: Vec<HashCode>| {
+        accts.sort();
+        accts
+            .into_iter()
+            .map(|acct| AccountDiff {
+                account: acct,
+                old: node(old, acct),
+                new: node(new, acct),
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        expected(&genesis, &block1, vec![acct, other]),
+        diff(&genesis, &block1)
+    );
+    assert_eq!(
+        expected(&block1, &block2, vec![new_acct]),
+        diff(&block1, &block2)
+    );
+    assert_eq!(
+        expected(&block2, &genesis, vec![acct, other, new_acct]),
+        diff(&block2, &genesis)
+    );
+    assert!(diff(&block2, &block2).is_empty());
+}
+
 proptest! {
     #![proptest_config(ProptestConfig {
         cases: 10, .. ProptestConfig::default()
@@ -270,6 +1729,34 @@ proptest! {
         smol::block_on(test_insert_into_data_tree(&entries));
     }
     #[test]
+    fn proptest_list_data_fields(
+        entries: Vec<(HexPath, Vec<u8>)>,
+        prefix_len in 0usize..3,
+        start: Option<HexPath>,
+        end: Option<HexPath>,
+        limit in 1usize..5
+    ) {
+        // a prefix of an existing path, so that the range is usually not empty
+        let prefix = match entries.first() {
+            None => HexPath(vec![]),
+            Some((path, _)) => HexPath(path[..prefix_len.min(path.len())].to_vec()),
+        };
+        let range = PathRange { prefix, start, end };
+        smol::block_on(test_list_data_fields(&entries, range, limit));
+    }
+    #[test]
+    fn proptest_diff_data_trees(
+        entries: Vec<(HexPath, Vec<u8>)>,
+        changes: Vec<(HexPath, Vec<u8>)>,
+        deleted: Vec<bool>
+    ) {
+        smol::block_on(test_diff_data_trees(&entries, &changes, &deleted));
+    }
+    #[test]
+    fn proptest_delete_from_data_tree(entries: Vec<(HexPath, Vec<u8>)>, deleted: Vec<bool>) {
+        smol::block_on(test_delete_from_data_tree(&entries, &deleted));
+    }
+    #[test]
     fn proptest_genesis_block(
         inits in account_inits(),
         timestamp_ms in prop::num::i32::ANY
@@ -322,3 +1809,707 @@ proptest! {
         assert!(res.is_ok(), "failed to send: {}", res.unwrap_err())
     }
 }
+
+#[test]
+fn equivocating_accounts_are_slashed() {
+    // the reporter has no stake, so the offender is selected for every slot
+    let TestChain {
+        mut hl,
+        keys,
+        accts,
+        genesis,
+    } = test_chain(&[(10, 40), (5, 0)], test_options());
+    let (offender, reporter) = (accts[0], accts[1]);
+    let fails = |hl: &mut MapHashLookup, prev: &MainBlock, acct: HashCode, action: Action| {
+        smol::block_on(add_action_to_account(hl, prev, acct, &action, 0)).is_err()
+    };
+    let conflict = |signer: HashCode, first: PreSignedMainBlock, second: PreSignedMainBlock| {
+        SlashEvidence::Miner(Box::new(ConflictingSignatures {
+            first_signature: sign(&keys[&signer], first.clone()),
+            first,
+            second_signature: sign(&keys[&signer], second.clone()),
+            second,
+        }))
+    };
+
+    let block1 = smol::block_on(next_block_with_actions(&mut hl, &keys, &genesis, &[]));
+    let mut forked = block1.block.clone();
+    forked.body.timestamp_ms += 1;
+    let miner_evidence = conflict(offender, block1.block.clone(), forked.clone());
+    let slash =
+        |prev: &MainBlock, evidence: &SlashEvidence| mk_slash(hash(prev), 1, evidence, reporter);
+    let same_block = conflict(offender, block1.block.clone(), block1.block.clone());
+    assert!(fails(
+        &mut hl,
+        &block1,
+        offender,
+        slash(&block1, &same_block)
+    ));
+    // signing the same body again with fewer signatures is no offense
+    let mut resigned = block1.block.clone();
+    resigned.signatures.pop();
+    let resigned_evidence = conflict(offender, block1.block.clone(), resigned);
+    let err = smol::block_on(add_action_to_account(
+        &mut hl,
+        &block1,
+        offender,
+        &slash(&block1, &resigned_evidence),
+        0,
+    ))
+    .unwrap_err();
+    assert!(err.to_string().contains("same body"), "{}", err);
+    assert!(fails(
+        &mut hl,
+        &block1,
+        reporter,
+        slash(&block1, &miner_evidence)
+    ));
+    // the reporter was never selected, so its conflicting signatures are no
+    // offense, even when slashing it would cost nothing
+    let unselected = conflict(reporter, block1.block.clone(), forked);
+    let err = smol::block_on(add_action_to_account(
+        &mut hl,
+        &block1,
+        reporter,
+        &mk_slash(hash(&block1), 0, &unselected, offender),
+        0,
+    ))
+    .unwrap_err();
+    assert!(
+        err.to_string().contains("not the selected miner"),
+        "{}",
+        err
+    );
+    let mut genesis_fork = genesis.block.clone();
+    genesis_fork.body.timestamp_ms += 1;
+    let genesis_evidence = conflict(offender, genesis.block.clone(), genesis_fork);
+    assert!(fails(
+        &mut hl,
+        &block1,
+        offender,
+        slash(&block1, &genesis_evidence)
+    ));
+    let (block2, state) = smol::block_on(next_block_checked(
+        &mut hl,
+        &keys,
+        &block1,
+        offender,
+        slash(&block1, &miner_evidence),
+    ));
+    assert_eq!(20, state.accounts[&offender].stake());
+    assert_eq!(10, state.accounts[&offender].balance());
+    assert!(fails(
+        &mut hl,
+        &block2,
+        offender,
+        slash(&block2, &miner_evidence)
+    ));
+
+    // the offender's node was changed by the slash, so it has a quorum
+    let node = smol::block_on(queries::lookup_account(&hl, &block2.block.body, offender))
+        .unwrap()
+        .unwrap();
+    let mut conflicting_body = node.body.clone();
+    conflicting_body.prize += 1;
+    let quorum_evidence = |signer: HashCode| {
+        SlashEvidence::Quorum(Box::new(ConflictingSignatures {
+            first_signature: sign(&keys[&signer], node.body.clone()),
+            first: node.body.clone(),
+            second_signature: sign(&keys[&signer], conflicting_body.clone()),
+            second: conflicting_body.clone(),
+        }))
+    };
+    let err = smol::block_on(add_action_to_account(
+        &mut hl,
+        &block2,
+        reporter,
+        &mk_slash(hash(&block2), 0, &quorum_evidence(reporter), offender),
+        0,
+    ))
+    .unwrap_err();
+    assert!(
+        err.to_string().contains("not selected for a quorum"),
+        "{}",
+        err
+    );
+    let (block3, state) = smol::block_on(next_block_checked(
+        &mut hl,
+        &keys,
+        &block2,
+        offender,
+        slash(&block2, &quorum_evidence(offender)),
+    ));
+    assert_eq!(10, state.accounts[&offender].stake());
+    let rewards = state.accounts[&offender].sends();
+    let mut amounts: Vec<u128> = rewards.iter().map(|send| send.send_amount).collect();
+    amounts.sort();
+    assert_eq!(vec![2, 4], amounts);
+
+    let mut block = block3;
+    for (nonce, reward) in rewards.iter().enumerate() {
+        assert_eq!(reporter, reward.recipient);
+        let receive = mk_receive(
+            hash(&block),
+            1,
+            nonce as u64,
+            offender,
+            hash(reward),
+            None,
+            &keys[&reporter],
+        );
+        block = smol::block_on(next_block_checked(
+            &mut hl, &keys, &block, reporter, receive,
+        ))
+        .0;
+    }
+    let state = smol::block_on(get_main_state(&hl, &block.block.body)).unwrap();
+    assert_eq!(9, state.accounts[&reporter].balance());
+}
+
+#[test]
+fn random_seed_follows_beacon() {
+    let TestChain {
+        mut hl,
+        keys,
+        accts,
+        genesis,
+    } = test_chain(&[(100, 10), (5, 10), (5, 10)], test_options());
+    let (acct, other) = (accts[0], accts[1]);
+    let with_beacon = |hl: &MapHashLookup, beacon: Vec<BeaconShare>, random_seed: HashCode| {
+        let body = MainBlockBody {
+            prev: Some(hash(&genesis)),
+            version: 1,
+            timestamp_ms: test_options().timestamp_period_ms as i64,
+            tree: genesis.block.body.tree,
+            endorsed_tree: genesis.block.body.tree,
+            options: genesis.block.body.options,
+            beacon,
+            random_seed,
+            rewards: vec![],
+        };
+        smol::block_on(sign_next_main_block(hl, &keys, body))
+    };
+    let verifies = |hl: &MapHashLookup, block: &MainBlock| {
+        smol::block_on(verify_endorsed_main_block(hl, block)).is_ok()
+    };
+    let seed = |hl: &MapHashLookup, shares: &[BeaconShare]| {
+        smol::block_on(next_beacon_seed(hl, &genesis, shares))
+    };
+
+    // the seed does not depend on the block's contents
+    let block1 = smol::block_on(next_block_with_actions(&mut hl, &keys, &genesis, &[]));
+    let (send, _) = mk_send(hash(&genesis), 1, 0, other, 10, None, vec![], &keys[&acct]);
+    let fork1 = smol::block_on(next_block_with_actions(
+        &mut hl,
+        &keys,
+        &genesis,
+        &[(acct, send)],
+    ));
+    assert_ne!(hash(&block1), hash(&fork1));
+    assert_eq!(block1.block.body.random_seed, fork1.block.body.random_seed);
+    assert!(verifies(&hl, &block1));
+
+    // every participant's share combines into the seed
+    let shares = block1.block.body.beacon.clone();
+    let (miner, _signers) =
+        smol::block_on(queries::miner_and_signers_by_prev_block(&hl, &genesis)).unwrap();
+    assert!(shares.iter().any(|share| hash(&share.key).code == miner));
+    let combined = block1.block.body.random_seed;
+    assert!(!verifies(
+        &hl,
+        &with_beacon(&hl, shares.clone(), genesis.block.body.random_seed)
+    ));
+
+    // the miner's share alone gives the fallback seed, and nothing else does
+    let miner_share = mk_beacon_share(&genesis.block.body, &keys[&miner]);
+    let fallback = seed(&hl, std::slice::from_ref(&miner_share)).unwrap();
+    // the fallback is the same whoever mines, so dropping the signers'
+    // shares can't steer the seed toward the miner
+    let other_miner = accts.iter().copied().find(|a| *a != miner).unwrap();
+    let other_share = mk_beacon_share(&genesis.block.body, &keys[&other_miner]);
+    let other_fallback = beacon_seed(
+        &genesis.block.body,
+        other_miner,
+        &[miner],
+        &BTreeMap::from_iter(vec![(other_miner, other_miner)]),
+        &[other_share],
+    );
+    assert_eq!(fallback, other_fallback.unwrap());
+    if shares.len() > 1 {
+        assert_ne!(combined, fallback);
+        assert!(verifies(
+            &hl,
+            &with_beacon(&hl, vec![miner_share], fallback)
+        ));
+        assert!(!verifies(&hl, &with_beacon(&hl, shares.clone(), fallback)));
+        let mut reversed = shares.clone();
+        reversed.reverse();
+        assert!(seed(&hl, &reversed).is_err());
+    }
+    assert!(seed(&hl, &[]).is_err());
+    assert!(!verifies(&hl, &with_beacon(&hl, vec![], combined)));
+    let without_miner: Vec<BeaconShare> = shares
+        .iter()
+        .filter(|share| hash(&share.key).code != miner)
+        .cloned()
+        .collect();
+    assert!(seed(&hl, &without_miner).is_err());
+    if shares.len() > 2 {
+        let partial: Vec<BeaconShare> = shares
+            .iter()
+            .filter(|share| share.key != without_miner[0].key)
+            .cloned()
+            .collect();
+        assert!(seed(&hl, &partial).is_err());
+        assert!(!verifies(&hl, &with_beacon(&hl, partial, combined)));
+    }
+
+    // the shares must be over the right input
+    let outsider = mk_beacon_share(&genesis.block.body, &gen_private_key());
+    assert!(seed(&hl, &[outsider]).is_err());
+    let stale: Vec<BeaconShare> = shares
+        .iter()
+        .map(|share| mk_beacon_share(&block1.block.body, &keys[&hash(&share.key).code]))
+        .collect();
+    assert!(seed(&hl, &stale).is_err());
+    assert!(seed(&hl, &[mk_beacon_share(&block1.block.body, &keys[&miner])]).is_err());
+}
+
+#[test]
+fn options_change_after_passing_vote() {
+    let opts = MainOptions {
+        quorum_period: 3,
+        ..test_options()
+    };
+    let TestChain {
+        mut hl,
+        keys,
+        accts,
+        genesis,
+    } = test_chain(&[(100, 10), (100, 3)], opts.clone());
+    let (acct, other) = (accts[0], accts[1]);
+    let apply = |hl: &mut MapHashLookup, prev: &MainBlock, acct: HashCode, action: Action| {
+        smol::block_on(next_block_checked(hl, &keys, prev, acct, action)).0
+    };
+    let empty = |hl: &mut MapHashLookup, prev: &MainBlock| {
+        smol::block_on(next_block_with_actions(hl, &keys, prev, &[]))
+    };
+    let fails = |hl: &mut MapHashLookup, prev: &MainBlock, acct: HashCode, action: Action| {
+        smol::block_on(add_action_to_account(hl, prev, acct, &action, 0)).is_err()
+    };
+
+    let new_opts = MainOptions {
+        gas_cost: 2,
+        ..opts.clone()
+    };
+    let propose = |prev: &MainBlock, key: &Keypair, options: &MainOptions, version: u64| {
+        mk_propose_options(hash(prev), 1, 0, options.clone(), version, key)
+    };
+    // the activation version must be a multiple of the period, a period away
+    assert!(fails(
+        &mut hl,
+        &genesis,
+        acct,
+        propose(&genesis, &keys[&acct], &new_opts, 3).0
+    ));
+    assert!(fails(
+        &mut hl,
+        &genesis,
+        acct,
+        propose(&genesis, &keys[&acct], &new_opts, 5).0
+    ));
+    let bad_opts = MainOptions {
+        quorum_period: 0,
+        ..opts.clone()
+    };
+    assert!(fails(
+        &mut hl,
+        &genesis,
+        acct,
+        propose(&genesis, &keys[&acct], &bad_opts, 6).0
+    ));
+    assert!(smol::block_on(genesis_block_body(
+        &mut MapHashLookup::new(),
+        &vec![],
+        0,
+        bad_opts
+    ))
+    .is_err());
+    let (action, proposal) = propose(&genesis, &keys[&acct], &new_opts, 6);
+    let block1 = apply(&mut hl, &genesis, acct, action);
+    let (action, rejected) = propose(&block1, &keys[&other], &opts, 6);
+    let block2 = apply(&mut hl, &block1, other, action);
+
+    let vote = |prev: &MainBlock, key: &Keypair, nonce: u64, proposal: &ProposalInfo| {
+        mk_vote(hash(prev), 1, nonce, proposal.proposer, hash(proposal), key)
+    };
+    assert!(fails(
+        &mut hl,
+        &block1,
+        other,
+        vote(&block1, &keys[&other], 0, &rejected)
+    ));
+    let block3 = apply(
+        &mut hl,
+        &block2,
+        other,
+        vote(&block2, &keys[&other], 1, &rejected),
+    );
+    // votes are tallied in version 3, so voting in version 4 is too late
+    assert!(fails(
+        &mut hl,
+        &block3,
+        acct,
+        vote(&block3, &keys[&acct], 1, &proposal)
+    ));
+    let tally = |block: &MainBlock| smol::block_on(tally_votes(&hl, &block.block.body, 6)).unwrap();
+    assert_eq!(
+        vec![3],
+        tally(&block3).values().map(|t| t.votes).collect::<Vec<_>>()
+    );
+
+    // the winning proposal needs half the stake of the tally block
+    let block3_voted = apply(
+        &mut hl,
+        &block2,
+        acct,
+        vote(&block2, &keys[&acct], 1, &proposal),
+    );
+    let fork4 = empty(&mut hl, &block3);
+    let block4 = empty(&mut hl, &block3_voted);
+    let fork5 = empty(&mut hl, &fork4);
+    let block5 = empty(&mut hl, &block4);
+    assert_eq!(
+        None,
+        smol::block_on(activated_proposal(&hl, &fork5.block.body)).unwrap()
+    );
+    assert_eq!(
+        Some(proposal.clone()),
+        smol::block_on(activated_proposal(&hl, &block5.block.body)).unwrap()
+    );
+    let fork6 = empty(&mut hl, &fork5);
+    let block6 = empty(&mut hl, &block5);
+    assert_eq!(hash(&opts), fork6.block.body.options);
+    assert_eq!(hash(&new_opts), block6.block.body.options);
+    assert_eq!(hash(&opts), block5.block.body.options);
+    assert_eq!(
+        new_opts,
+        smol::block_on(hl.lookup(block6.block.body.options)).unwrap()
+    );
+    assert!(smol::block_on(verify_valid_main_block_body(&hl, &block6.block.body)).is_ok());
+    let stale_options = MainBlockBody {
+        options: block5.block.body.options,
+        ..block6.block.body.clone()
+    };
+    assert!(smol::block_on(verify_valid_main_block_body(&hl, &stale_options)).is_err());
+    let block7 = empty(&mut hl, &block6);
+    assert_eq!(hash(&new_opts), block7.block.body.options);
+
+    // the stake voting for each proposal is kept in the tree's stats
+    let votes = |hl: &MapHashLookup, block: &MainBlock| {
+        smol::block_on(hl.lookup(block.block.body.tree))
+            .unwrap()
+            .body
+            .stats
+            .votes
+    };
+    let vote_key = (acct, hash(&proposal).code);
+    assert_eq!(
+        vec![(vote_key, 10)],
+        votes(&hl, &block7).into_iter().collect::<Vec<_>>()
+    );
+    let block8 = apply(
+        &mut hl,
+        &block7,
+        acct,
+        mk_stake(hash(&block7), 1, 2, 5, &keys[&acct]),
+    );
+    assert_eq!(Some(&15), votes(&hl, &block8).get(&vote_key));
+
+    // the proposal and its votes can be pruned once it has activated
+    let prune_vote = |prev: &MainBlock, nonce: u64| {
+        mk_prune_vote(hash(prev), 1, nonce, hash(&proposal), &keys[&acct])
+    };
+    let prune_proposal = |prev: &MainBlock, nonce: u64| {
+        mk_prune_proposal(hash(prev), 1, nonce, hash(&proposal), &keys[&acct])
+    };
+    assert!(fails(&mut hl, &block5, acct, prune_vote(&block5, 2)));
+    assert!(fails(&mut hl, &block5, acct, prune_proposal(&block5, 2)));
+    let block9 = apply(&mut hl, &block8, acct, prune_vote(&block8, 3));
+    assert!(votes(&hl, &block9).is_empty());
+    assert!(fails(&mut hl, &block9, acct, prune_vote(&block9, 4)));
+    let block10 = apply(&mut hl, &block9, acct, prune_proposal(&block9, 4));
+    let acct_node = smol::block_on(queries::lookup_account(&hl, &block10.block.body, acct))
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        None,
+        smol::block_on(queries::lookup_data_in_account(
+            &hl,
+            &acct_node,
+            &field_proposal(hash(&proposal)).path
+        ))
+        .unwrap()
+    );
+}
+
+#[test]
+fn endorsed_account_node_keeps_its_siblings() {
+    let TestChain {
+        mut hl,
+        keys,
+        accts,
+        genesis,
+    } = test_chain(&[(2000, 10), (5, 3), (5, 3), (5, 3)], test_options());
+    // the top node keeps the children of the accounts that don't change
+    let (send, _) = mk_send(
+        hash(&genesis),
+        1000,
+        0,
+        accts[1],
+        10,
+        None,
+        vec![],
+        &keys[&accts[0]],
+    );
+    let body =
+        smol::block_on(add_action_to_account(&mut hl, &genesis, accts[0], &send, 0)).unwrap();
+    let sigs: Vec<Signature<QuorumNodeBody>> =
+        keys.values().map(|key| sign(key, body.clone())).collect();
+    let node = QuorumNode {
+        body,
+        signatures: Some(smol::block_on(hl.put(&sigs)).unwrap()),
+    };
+    let tree = smol::block_on(insert_new_node(&mut hl, &genesis, node));
+    let beacon = smol::block_on(beacon_shares(&hl, &keys, &genesis));
+    let body = smol::block_on(next_main_block_body(
+        &mut hl,
+        test_options().timestamp_period_ms as i64,
+        hash(&genesis),
+        tree,
+        beacon,
+    ))
+    .unwrap();
+    assert!(smol::block_on(verify_valid_main_block_body(&hl, &body)).is_ok());
+}
+
+#[test]
+fn rewards_pay_out_fees_and_prizes() {
+    // no signatures are required, so selected signers may withhold theirs
+    let opts = MainOptions {
+        block_reward: 100,
+        block_reward_halving_period: 2,
+        miner_reward_percent: 40,
+        main_block_signatures_required: 0,
+        ..test_options()
+    };
+    assert_eq!(
+        vec![100, 100, 50, 50, 25],
+        (0..5)
+            .map(|v| block_reward_at(&opts, v))
+            .collect::<Vec<_>>()
+    );
+    let TestChain {
+        mut hl,
+        keys,
+        accts,
+        genesis,
+    } = test_chain(
+        &[(2000, 10), (2000, 20), (2000, 30), (2000, 40)],
+        opts.clone(),
+    );
+
+    // a send with a prize for the quorum members signing it
+    let (send, _) = mk_send(
+        hash(&genesis),
+        1000,
+        0,
+        accts[1],
+        10,
+        None,
+        vec![],
+        &keys[&accts[0]],
+    );
+    let body = smol::block_on(add_action_to_account(
+        &mut hl, &genesis, accts[0], &send, 200,
+    ))
+    .unwrap();
+    // an account outside the quorum also signs
+    let sybil = gen_private_key();
+    let sigs: Vec<Signature<QuorumNodeBody>> = keys
+        .values()
+        .chain(Some(&sybil))
+        .map(|key| sign(key, body.clone()))
+        .collect();
+    let members: BTreeSet<HashCode> = smol::block_on(queries::quorums_by_prev_block(
+        &hl,
+        &genesis.block.body,
+        body.path.clone(),
+    ))
+    .unwrap()
+    .into_iter()
+    .flat_map(|(members, _threshold)| members)
+    .collect();
+    let node = QuorumNode {
+        body,
+        signatures: Some(smol::block_on(hl.put(&sigs)).unwrap()),
+    };
+    let tree = smol::block_on(insert_new_node(&mut hl, &genesis, node));
+    let beacon = smol::block_on(beacon_shares(&hl, &keys, &genesis));
+    let body = smol::block_on(next_main_block_body(
+        &mut hl,
+        opts.timestamp_period_ms as i64,
+        hash(&genesis),
+        tree,
+        beacon,
+    ))
+    .unwrap();
+    assert!(smol::block_on(verify_valid_main_block_body(&hl, &body)).is_ok());
+
+    // the quorum members split the prize, and since the genesis block has no
+    // signers to pay, the miner gets the fee and block reward, less the prize
+    let (miner, signers) =
+        smol::block_on(queries::miner_and_signers_by_prev_block(&hl, &genesis)).unwrap();
+    let prize_share = 200 / members.len() as u128;
+    let mut expected: BTreeMap<HashCode, u128> = members
+        .iter()
+        .map(|member| (*member, prize_share))
+        .collect();
+    *expected.entry(miner).or_insert(0) += 900 + 200 % members.len() as u128;
+    let rewards: BTreeMap<HashCode, u128> = body
+        .rewards
+        .iter()
+        .map(|reward| (reward.account, reward.amount))
+        .collect();
+    assert_eq!(expected, rewards);
+    assert!(!rewards.contains_key(&hash(&sybil.public).code));
+    assert_eq!(1100, rewards.values().sum::<u128>());
+    let mut tampered = body.clone();
+    tampered.rewards[0].amount += 1;
+    assert!(smol::block_on(verify_valid_main_block_body(&hl, &tampered)).is_err());
+
+    // the rewards are credited to balances in the block's tree
+    let mut endorsed = body.clone();
+    endorsed.tree = body.endorsed_tree;
+    let mut expected_state = smol::block_on(get_main_state(&hl, &endorsed)).unwrap();
+    expected_state.credit_rewards(&body.rewards);
+    let state1 = smol::block_on(get_main_state(&hl, &body)).unwrap();
+    assert_eq!(expected_state, state1);
+    assert!(smol::block_on(verify_valid_main_block_body(&hl, &endorsed)).is_err());
+
+    // one selected signer withholds its signature
+    let withholder = signers[0];
+    let signing_keys = keys
+        .iter()
+        .filter(|(acct, _)| **acct != withholder)
+        .map(|(_, key)| key)
+        .collect();
+    let block1 = PreSignedMainBlock::sign(body, &signing_keys);
+    let block1 = MainBlock::sign(block1, &keys[&miner]);
+    smol::block_on(hl.put(&block1)).unwrap();
+
+    // the next block pays the slots of the signers who signed, and the rest
+    // of its block reward goes to its miner
+    let block2 = smol::block_on(next_block_with_actions(&mut hl, &keys, &block1, &[]));
+    let (miner2, _signers) =
+        smol::block_on(queries::miner_and_signers_by_prev_block(&hl, &block1)).unwrap();
+    let signed: Vec<HashCode> = signers
+        .iter()
+        .cloned()
+        .filter(|signer| *signer != withholder)
+        .collect();
+    let share = match signed.len() as u128 {
+        0 => 0,
+        num_signed => 30 / num_signed,
+    };
+    let mut expected: BTreeMap<HashCode, u128> = BTreeMap::new();
+    for signer in &signed {
+        *expected.entry(*signer).or_insert(0) += share;
+    }
+    *expected.entry(miner2).or_insert(0) += 50 - share * signed.len() as u128;
+    let rewards: BTreeMap<HashCode, u128> = block2
+        .block
+        .body
+        .rewards
+        .iter()
+        .map(|reward| (reward.account, reward.amount))
+        .collect();
+    assert_eq!(expected, rewards);
+    if withholder != miner2 {
+        assert!(!rewards.contains_key(&withholder));
+    }
+
+    // with no new quorum nodes, the balances change only by the rewards
+    let mut expected_state = state1;
+    expected_state.credit_rewards(&block2.block.body.rewards);
+    assert_eq!(
+        expected_state,
+        smol::block_on(get_main_state(&hl, &block2.block.body)).unwrap()
+    );
+}
+
+#[test]
+fn signed_actions_use_nonces_in_order() {
+    let TestChain {
+        mut hl,
+        keys,
+        accts,
+        genesis,
+    } = test_chain(&[(100, 10), (5, 3)], test_options());
+    let (acct, other) = (accts[0], accts[1]);
+    let fails = |hl: &mut MapHashLookup, prev: &MainBlock, action: Action| {
+        smol::block_on(add_action_to_account(hl, prev, acct, &action, 0)).is_err()
+    };
+    let send = |prev: &MainBlock, nonce: u64| {
+        mk_send(hash(prev), 1, nonce, other, 10, None, vec![], &keys[&acct]).0
+    };
+    let nonce = |hl: &MapHashLookup, block: &MainBlock| {
+        smol::block_on(next_nonce(hl, &block.block.body, acct)).unwrap()
+    };
+
+    assert_eq!(0, nonce(&hl, &genesis));
+    assert!(fails(&mut hl, &genesis, send(&genesis, 1)));
+    let (block1, state) = smol::block_on(next_block_checked(
+        &mut hl,
+        &keys,
+        &genesis,
+        acct,
+        send(&genesis, 0),
+    ));
+    assert_eq!(1, state.accounts[&acct].nonce());
+    assert_eq!(1, nonce(&hl, &block1));
+    assert_eq!(0, state.accounts[&other].nonce());
+
+    // the same action can't run twice, even when re-signed for a later block
+    assert!(fails(&mut hl, &block1, send(&block1, 0)));
+    assert!(fails(&mut hl, &block1, send(&block1, 2)));
+    let mut actions = BTreeMap::new();
+    actions.insert(acct, send(&block1, 0));
+    assert_eq!(
+        state,
+        smol::block_on(get_next_main_state(&hl, hash(&block1), actions, &state))
+    );
+    // the model checks the nonces of other signed commands itself as well
+    let new_key = gen_private_key();
+    let stale_rotate = mk_rotate_key(hash(&block1), 1, 0, &new_key.public, &keys[&acct]);
+    assert!(fails(&mut hl, &block1, stale_rotate.clone()));
+    assert_eq!(
+        None,
+        smol::block_on(get_next_account_state(
+            &hl,
+            hash(&block1),
+            acct,
+            &stale_rotate,
+            &state
+        ))
+    );
+    let (block2, state) = smol::block_on(next_block_checked(
+        &mut hl,
+        &keys,
+        &block1,
+        acct,
+        send(&block1, 1),
+    ));
+    assert_eq!(78, state.accounts[&acct].balance());
+    assert_eq!(2, nonce(&hl, &block2));
+}